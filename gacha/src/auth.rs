@@ -0,0 +1,675 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use axum::Json;
+use axum::extract::{FromRequestParts, Query, State};
+use axum::http::header::AUTHORIZATION;
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Duration, Utc};
+use rand::{Rng, rng};
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::{AppState, PersistenceError, UserId};
+
+pub(crate) const PASSWORD_MIN: usize = 8;
+const TOKEN_TTL_DAYS: i64 = 30;
+const LOGIN_ATTEMPTS: u32 = 5;
+const LOGIN_LOCKOUT_MINUTES: i64 = 15;
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS credentials (
+    user_id TEXT PRIMARY KEY REFERENCES users (id),
+    password_hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tokens (
+    hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    created_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+CREATE TABLE IF NOT EXISTS api_keys (
+    id TEXT PRIMARY KEY,
+    hash TEXT NOT NULL UNIQUE,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    scope TEXT NOT NULL,
+    label TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    revoked INTEGER NOT NULL DEFAULT 0
+);
+";
+
+// What an API key is allowed to call. Keys are for automation that can't do
+// a password login, so each one is good for a single endpoint.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum Scope {
+    SevenAmUnlock,
+}
+impl Scope {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Scope::SevenAmUnlock => "SevenAmUnlock",
+        }
+    }
+    fn from_str(scope: &str) -> Option<Self> {
+        match scope {
+            "SevenAmUnlock" => Some(Scope::SevenAmUnlock),
+            _ => None,
+        }
+    }
+}
+
+// Only the sha256 of a token or key is stored; the plain value is handed out
+// once and never kept.
+fn new_secret() -> (String, String) {
+    let bytes: [u8; 32] = rng().random();
+    let secret: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    let hash = digest(&secret);
+    (secret, hash)
+}
+fn digest(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Argon2 is slow on purpose, hash and verify without the database locked.
+pub(crate) fn hash_password(password: &str) -> Result<String, PersistenceError> {
+    let salt_bytes: [u8; 16] = rng().random();
+    let salt = SaltString::encode_b64(&salt_bytes).map_err(|_| PersistenceError::HashError)?;
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|_| PersistenceError::HashError)?
+        .to_string())
+}
+fn verify_password(stored: &str, password: &str) -> bool {
+    PasswordHash::new(stored).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+// Verified against when the account doesn't exist, so turning down an
+// unknown userid takes as long as turning down a wrong password.
+static DUMMY_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("no such account").expect("Error hashing dummy password!"));
+
+// For `--set-password`, replaces whatever password the account had.
+pub(crate) fn set_password(
+    conn: &Connection,
+    id: &UserId,
+    password: &str,
+) -> Result<(), PersistenceError> {
+    conn.execute(
+        "INSERT INTO credentials (user_id, password_hash) VALUES (?1, ?2)
+        ON CONFLICT (user_id) DO UPDATE SET password_hash = excluded.password_hash",
+        params![id.0, hash_password(password)?],
+    )?;
+    Ok(())
+}
+// For new accounts, fails if the account already has a password.
+pub(crate) fn add_password(
+    conn: &Connection,
+    id: &UserId,
+    hash: &str,
+) -> Result<(), PersistenceError> {
+    conn.execute(
+        "INSERT INTO credentials (user_id, password_hash) VALUES (?1, ?2)",
+        params![id.0, hash],
+    )?;
+    Ok(())
+}
+fn password_hash(conn: &Connection, id: &UserId) -> Result<Option<String>, PersistenceError> {
+    Ok(conn
+        .query_row(
+            "SELECT password_hash FROM credentials WHERE user_id = ?1",
+            params![id.0],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+// Failed logins per account. LOGIN_ATTEMPTS of them in a row lock the
+// account out of /login for LOGIN_LOCKOUT_MINUTES, right password or not.
+#[derive(Default)]
+pub(crate) struct LoginThrottle {
+    failures: Mutex<HashMap<String, (u32, DateTime<Utc>)>>,
+}
+impl LoginThrottle {
+    fn check(&self, id: &UserId) -> Result<(), ApiError> {
+        let failures = self.failures.lock().unwrap();
+        match failures.get(&id.0) {
+            Some((count, last)) if *count >= LOGIN_ATTEMPTS => {
+                let until = *last + Duration::minutes(LOGIN_LOCKOUT_MINUTES);
+                if until > Utc::now() {
+                    return Err(ApiError::Throttled(until));
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+    fn failed(&self, id: &UserId) {
+        let now = Utc::now();
+        let mut failures = self.failures.lock().unwrap();
+        // Old failures are forgotten, so ids that were only guessed at don't
+        // pile up.
+        failures.retain(|_, (_, last)| now - *last < Duration::minutes(LOGIN_LOCKOUT_MINUTES));
+        let (count, last) = failures.entry(id.0.clone()).or_insert((0, now));
+        *count += 1;
+        *last = now;
+    }
+    fn succeeded(&self, id: &UserId) {
+        self.failures.lock().unwrap().remove(&id.0);
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct TokenResponse {
+    token: String,
+    expires_at: i64,
+}
+pub(crate) fn issue_token(
+    conn: &Connection,
+    id: &UserId,
+) -> Result<TokenResponse, PersistenceError> {
+    let (token, hash) = new_secret();
+    let now = Utc::now();
+    let expires_at = (now + Duration::days(TOKEN_TTL_DAYS)).timestamp();
+    conn.execute(
+        "INSERT INTO tokens (hash, user_id, created_at, expires_at) VALUES (?1, ?2, ?3, ?4)",
+        params![hash, id.0, now.timestamp(), expires_at],
+    )?;
+    Ok(TokenResponse { token, expires_at })
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// EventSource and WebSocket can't set headers, so on their routes the token
+// is also read from the `access_token` query parameter. Anywhere else a token
+// in the URL would only end up in logs and browser history.
+const QUERY_TOKEN_ROUTES: [&str; 2] = ["/events", "/ws"];
+async fn request_token(parts: &mut Parts, state: &AppState) -> Result<String, ApiError> {
+    if let Some(token) = bearer(&parts.headers) {
+        return Ok(digest(token));
+    }
+    if !QUERY_TOKEN_ROUTES.contains(&parts.uri.path()) {
+        return Err(ApiError::Unauthorized);
+    }
+    let query = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+        .await
+        .map(|Query(params)| params)
+        .unwrap_or_default();
+    query
+        .get("access_token")
+        .map(String::as_str)
+        .map(digest)
+        .ok_or(ApiError::Unauthorized)
+}
+// The account a token belongs to, if it's neither revoked nor expired.
+fn token_user(conn: &Connection, hash: &str) -> Result<Option<UserId>, PersistenceError> {
+    let userid: Option<String> = conn
+        .query_row(
+            "SELECT user_id FROM tokens WHERE hash = ?1 AND revoked = 0 AND expires_at > ?2",
+            params![hash, Utc::now().timestamp()],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(userid.map(UserId))
+}
+
+// The account behind the bearer token. Handlers take the user from here and
+// never from the request body.
+pub(crate) struct AuthUser(pub(crate) UserId);
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let hash = request_token(parts, state).await?;
+        let conn = state.repo.db.lock().unwrap();
+        token_user(&conn, &hash)?
+            .map(AuthUser)
+            .ok_or(ApiError::Unauthorized)
+    }
+}
+
+// The token a request came with, for connections that outlive the request
+// and have to notice when it's revoked or runs out.
+pub(crate) struct Token(String);
+impl Token {
+    pub(crate) fn valid(&self, state: &AppState) -> bool {
+        let conn = state.repo.db.lock().unwrap();
+        token_user(&conn, &self.0).is_ok_and(|user| user.is_some())
+    }
+}
+impl FromRequestParts<AppState> for Token {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Token(request_token(parts, state).await?))
+    }
+}
+
+// Read from `X-Api-Key`, or the `key` query parameter for clients that can
+// only fire a plain GET.
+pub(crate) struct ApiKey {
+    pub(crate) owner: UserId,
+    pub(crate) scope: Scope,
+}
+impl FromRequestParts<AppState> for ApiKey {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let query = Query::<HashMap<String, String>>::from_request_parts(parts, state)
+            .await
+            .map(|Query(params)| params)
+            .unwrap_or_default();
+        let key = parts
+            .headers
+            .get("x-api-key")
+            .and_then(|h| h.to_str().ok())
+            .or(query.get("key").map(String::as_str))
+            .ok_or(ApiError::Unauthorized)?;
+
+        let conn = state.repo.db.lock().unwrap();
+        let found: Option<(String, String)> = conn
+            .query_row(
+                "SELECT user_id, scope FROM api_keys WHERE hash = ?1 AND revoked = 0",
+                params![digest(key)],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+
+        let (owner, scope) = found.ok_or(ApiError::Unauthorized)?;
+        Ok(ApiKey {
+            owner: UserId(owner),
+            scope: Scope::from_str(&scope).ok_or(ApiError::Unauthorized)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoginRequest {
+    userid: String,
+    password: String,
+}
+pub(crate) async fn login(
+    State(state): State<AppState>,
+    Json(req): Json<LoginRequest>,
+) -> Result<Json<TokenResponse>, ApiError> {
+    let id = UserId(req.userid);
+    state.logins.check(&id)?;
+    let stored = password_hash(&state.repo.db.lock().unwrap(), &id)?;
+    let verified = tokio::task::spawn_blocking(move || {
+        let known = stored.is_some();
+        let hash = stored.unwrap_or_else(|| DUMMY_HASH.clone());
+        verify_password(&hash, &req.password) && known
+    })
+    .await
+    .unwrap_or(false);
+    if !verified {
+        state.logins.failed(&id);
+        return Err(ApiError::Unauthorized);
+    }
+    state.logins.succeeded(&id);
+
+    let conn = state.repo.db.lock().unwrap();
+    let token = issue_token(&conn, &id)?;
+    Ok(Json(token))
+}
+
+pub(crate) async fn logout(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let hash = digest(bearer(&headers).ok_or(ApiError::Unauthorized)?);
+    let conn = state.repo.db.lock().unwrap();
+    conn.execute(
+        "UPDATE tokens SET revoked = 1 WHERE hash = ?1 AND user_id = ?2",
+        params![hash, id.0],
+    )?;
+    Ok(StatusCode::OK)
+}
+
+// Signs the account out everywhere, including the token used for this call.
+pub(crate) async fn revoke_tokens(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+) -> Result<Json<usize>, ApiError> {
+    let conn = state.repo.db.lock().unwrap();
+    let revoked = conn.execute(
+        "UPDATE tokens SET revoked = 1 WHERE user_id = ?1 AND revoked = 0",
+        params![id.0],
+    )?;
+    Ok(Json(revoked))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ApiKeyRequest {
+    scope: Scope,
+    label: String,
+}
+#[derive(Serialize)]
+pub(crate) struct ApiKeyResponse {
+    id: Uuid,
+    key: String,
+    scope: Scope,
+}
+pub(crate) async fn create_api_key(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    Json(req): Json<ApiKeyRequest>,
+) -> Result<Json<ApiKeyResponse>, ApiError> {
+    let (key, hash) = new_secret();
+    let key_id = Uuid::now_v7();
+    let conn = state.repo.db.lock().unwrap();
+    conn.execute(
+        "INSERT INTO api_keys (id, hash, user_id, scope, label, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            key_id.to_string(),
+            hash,
+            id.0,
+            req.scope.as_str(),
+            req.label,
+            Utc::now().timestamp()
+        ],
+    )?;
+
+    Ok(Json(ApiKeyResponse {
+        id: key_id,
+        key,
+        scope: req.scope,
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct ApiKeyInfo {
+    id: String,
+    scope: String,
+    label: String,
+    created_at: i64,
+}
+pub(crate) async fn get_api_keys(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+) -> Result<Json<Vec<ApiKeyInfo>>, ApiError> {
+    let conn = state.repo.db.lock().unwrap();
+    let keys = conn
+        .prepare(
+            "SELECT id, scope, label, created_at FROM api_keys
+            WHERE user_id = ?1 AND revoked = 0 ORDER BY created_at",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![id.0], |row| {
+                Ok(ApiKeyInfo {
+                    id: row.get(0)?,
+                    scope: row.get(1)?,
+                    label: row.get(2)?,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()
+        })?;
+    Ok(Json(keys))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeKeyRequest {
+    id: Uuid,
+}
+pub(crate) async fn revoke_api_key(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    Json(req): Json<RevokeKeyRequest>,
+) -> Result<StatusCode, ApiError> {
+    let conn = state.repo.db.lock().unwrap();
+    let revoked = conn.execute(
+        "UPDATE api_keys SET revoked = 1 WHERE id = ?1 AND user_id = ?2",
+        params![req.id.to_string(), id.0],
+    )?;
+    match revoked {
+        0 => Err(ApiError::NotFound("api key")),
+        _ => Ok(StatusCode::OK),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{User, repo};
+    use axum::http::Request;
+
+    const PASSWORD: &str = "correct horse";
+
+    fn registered() -> AppState {
+        let state = AppState::in_memory();
+        let user = User::new(UserId("tester".into()), "tester".into(), None);
+        let conn = state.repo.db.lock().unwrap();
+        repo::write(&conn, &user).unwrap();
+        add_password(&conn, &user.id, &hash_password(PASSWORD).unwrap()).unwrap();
+        drop(conn);
+        state
+    }
+    async fn login_as(state: &AppState, userid: &str, password: &str) -> Result<String, ApiError> {
+        let req = LoginRequest {
+            userid: userid.into(),
+            password: password.into(),
+        };
+        let Json(token) = login(State(state.clone()), Json(req)).await?;
+        Ok(token.token)
+    }
+    fn request(uri: &str, token: Option<&str>) -> Parts {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+    async fn signed_in(state: &AppState, mut parts: Parts) -> Option<UserId> {
+        AuthUser::from_request_parts(&mut parts, state)
+            .await
+            .ok()
+            .map(|AuthUser(id)| id)
+    }
+
+    #[tokio::test]
+    async fn only_the_right_password_gets_a_token() {
+        let state = registered();
+        let token = login_as(&state, "tester", PASSWORD).await.unwrap();
+        assert_eq!(
+            signed_in(&state, request("/pity", Some(&token))).await,
+            Some(UserId("tester".into()))
+        );
+
+        for (userid, password) in [("tester", "wrong horse"), ("nobody", PASSWORD)] {
+            assert!(matches!(
+                login_as(&state, userid, password).await,
+                Err(ApiError::Unauthorized)
+            ));
+        }
+        assert!(
+            signed_in(&state, request("/pity", Some("made up")))
+                .await
+                .is_none()
+        );
+        assert!(signed_in(&state, request("/pity", None)).await.is_none());
+    }
+
+    #[test]
+    fn unknown_accounts_are_checked_against_a_real_hash() {
+        assert!(PasswordHash::new(&DUMMY_HASH).is_ok());
+        assert!(!verify_password(&DUMMY_HASH, PASSWORD));
+    }
+
+    #[tokio::test]
+    async fn query_tokens_only_work_where_headers_cant_be_set() {
+        let state = registered();
+        let token = login_as(&state, "tester", PASSWORD).await.unwrap();
+        let in_query = |route: &str| request(&format!("{}?access_token={}", route, token), None);
+
+        for route in QUERY_TOKEN_ROUTES {
+            assert!(signed_in(&state, in_query(route)).await.is_some());
+        }
+        for route in ["/pity", "/purchase", "/revoke_tokens"] {
+            assert!(signed_in(&state, in_query(route)).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_failures_lock_the_account_out() {
+        let state = registered();
+        for _ in 0..LOGIN_ATTEMPTS {
+            assert!(matches!(
+                login_as(&state, "tester", "wrong horse").await,
+                Err(ApiError::Unauthorized)
+            ));
+        }
+        // Locked out even with the right password, and only this account.
+        assert!(matches!(
+            login_as(&state, "tester", PASSWORD).await,
+            Err(ApiError::Throttled(_))
+        ));
+        let other = UserId("other".into());
+        assert!(state.logins.check(&other).is_ok());
+
+        // Once the lockout has run out the right password works again and
+        // clears the count.
+        let id = UserId("tester".into());
+        state
+            .logins
+            .failures
+            .lock()
+            .unwrap()
+            .get_mut("tester")
+            .unwrap()
+            .1 -= Duration::minutes(LOGIN_LOCKOUT_MINUTES);
+        assert!(login_as(&state, "tester", PASSWORD).await.is_ok());
+        state.logins.failed(&id);
+        assert_eq!(state.logins.failures.lock().unwrap()["tester"].0, 1);
+    }
+
+    #[test]
+    fn old_failures_are_forgotten() {
+        let throttle = LoginThrottle::default();
+        let (old, new) = (UserId("old".into()), UserId("new".into()));
+        throttle.failed(&old);
+        throttle.failures.lock().unwrap().get_mut("old").unwrap().1 -=
+            Duration::minutes(LOGIN_LOCKOUT_MINUTES);
+        throttle.failed(&new);
+        let failures = throttle.failures.lock().unwrap();
+        assert!(!failures.contains_key("old"));
+        assert_eq!(failures["new"].0, 1);
+    }
+
+    #[tokio::test]
+    async fn expired_and_revoked_tokens_stop_working() {
+        let state = registered();
+        let expiring = login_as(&state, "tester", PASSWORD).await.unwrap();
+        let other = login_as(&state, "tester", PASSWORD).await.unwrap();
+        state
+            .repo
+            .db
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE tokens SET expires_at = ?1 WHERE hash = ?2",
+                params![Utc::now().timestamp(), digest(&expiring)],
+            )
+            .unwrap();
+        assert!(
+            signed_in(&state, request("/pity", Some(&expiring)))
+                .await
+                .is_none()
+        );
+        assert!(!Token(digest(&expiring)).valid(&state));
+
+        let live = Token(digest(&other));
+        assert!(live.valid(&state));
+        let id = UserId("tester".into());
+        let Json(revoked) = revoke_tokens(State(state.clone()), AuthUser(id))
+            .await
+            .unwrap();
+        assert_eq!(revoked, 2);
+        assert!(!live.valid(&state));
+        assert!(
+            signed_in(&state, request("/pity", Some(&other)))
+                .await
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn api_keys_carry_their_scope() {
+        let state = registered();
+        let id = UserId("tester".into());
+        let req = ApiKeyRequest {
+            scope: Scope::SevenAmUnlock,
+            label: "alarm".into(),
+        };
+        let Json(created) = create_api_key(State(state.clone()), AuthUser(id.clone()), Json(req))
+            .await
+            .unwrap();
+        let key = async |uri: &str, header: Option<&str>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(key) = header {
+                builder = builder.header("x-api-key", key);
+            }
+            let mut parts = builder.body(()).unwrap().into_parts().0;
+            ApiKey::from_request_parts(&mut parts, &state).await
+        };
+
+        let found = key("/7am_unlock", Some(&created.key)).await.unwrap();
+        assert_eq!(
+            (found.owner, found.scope),
+            (id.clone(), Scope::SevenAmUnlock)
+        );
+        let uri = format!("/7am_unlock?key={}", created.key);
+        assert_eq!(key(&uri, None).await.unwrap().scope, Scope::SevenAmUnlock);
+
+        // A scope this build doesn't know grants nothing.
+        state
+            .repo
+            .db
+            .lock()
+            .unwrap()
+            .execute("UPDATE api_keys SET scope = 'Everything'", [])
+            .unwrap();
+        assert!(matches!(
+            key("/7am_unlock", Some(&created.key)).await,
+            Err(ApiError::Unauthorized)
+        ));
+
+        let req = RevokeKeyRequest { id: created.id };
+        revoke_api_key(State(state.clone()), AuthUser(id), Json(req))
+            .await
+            .unwrap();
+        assert!(matches!(
+            key("/7am_unlock", Some(&created.key)).await,
+            Err(ApiError::Unauthorized)
+        ));
+    }
+}
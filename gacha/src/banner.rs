@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::sync::LazyLock;
+
+use axum::Json;
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use gacha_protocol::Rarities;
+use rand::Rng;
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+use crate::auth::AuthUser;
+use crate::config::{self, Banner, Featured, Loot, Prize, RateUp};
+use crate::error::ApiError;
+use crate::{AppState, Coeff, PersistenceError, User, UserId, Voucher, load_user};
+
+pub(crate) const STANDARD: &str = "standard";
+// Where limited banners keep their pity with `pity.carry_over`.
+pub(crate) const LIMITED: &str = "limited";
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS banner_pity (
+    user_id TEXT NOT NULL REFERENCES users (id),
+    banner_id TEXT NOT NULL,
+    sss_pity INTEGER NOT NULL DEFAULT 0,
+    s_pity INTEGER NOT NULL DEFAULT 0,
+    a_pity INTEGER NOT NULL DEFAULT 0,
+    sss_guaranteed INTEGER NOT NULL DEFAULT 0,
+    s_guaranteed INTEGER NOT NULL DEFAULT 0,
+    a_guaranteed INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, banner_id)
+);
+ALTER TABLE pull_history ADD COLUMN banner TEXT NOT NULL DEFAULT 'standard';
+";
+
+// The permanent banner. Its Mythic is the old week off / day off 50/50 and
+// the rest drops as it always did.
+static STANDARD_MYTHIC: LazyLock<RateUp> = LazyLock::new(|| RateUp {
+    prize: Prize::Template(999),
+    off_rate: Some(Prize::Template(1)),
+    chance: 0.5,
+});
+static STANDARD_BANNER: LazyLock<Banner> = LazyLock::new(|| Banner {
+    id: STANDARD.into(),
+    name: "Standard".into(),
+    starts: None,
+    ends: None,
+    featured: Featured {
+        mythic: Some(STANDARD_MYTHIC.clone()),
+        s: None,
+        a: None,
+    },
+});
+
+// Pity is counted per banner. The standard banner keeps using the counters on
+// `User` (and `has_slip` as its Mythic guarantee), every other banner gets a
+// row in `banner_pity`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub(crate) struct BannerPity {
+    pub(crate) sss_pity: u16,
+    pub(crate) s_pity: u16,
+    pub(crate) a_pity: u16,
+    pub(crate) sss_guaranteed: bool,
+    pub(crate) s_guaranteed: bool,
+    pub(crate) a_guaranteed: bool,
+}
+impl BannerPity {
+    // The 50/50 guarantee of a rarity. B has none.
+    pub(crate) fn guaranteed(&mut self, outcome: &Rarities) -> Option<&mut bool> {
+        match outcome {
+            Rarities::MythicSSS => Some(&mut self.sss_guaranteed),
+            Rarities::S => Some(&mut self.s_guaranteed),
+            Rarities::A => Some(&mut self.a_guaranteed),
+            Rarities::B => None,
+        }
+    }
+}
+
+fn pity_key(banner: &str) -> &str {
+    match banner {
+        STANDARD => STANDARD,
+        _ if config::pity().carry_over => LIMITED,
+        _ => banner,
+    }
+}
+
+impl User {
+    pub(crate) fn pity(&self, banner: &str) -> BannerPity {
+        match pity_key(banner) {
+            STANDARD => BannerPity {
+                sss_pity: self.sss_pity,
+                s_pity: self.s_pity,
+                a_pity: self.a_pity,
+                sss_guaranteed: self.has_slip,
+                ..BannerPity::default()
+            },
+            key => self.banner_pity.get(key).copied().unwrap_or_default(),
+        }
+    }
+    pub(crate) fn set_pity(&mut self, banner: &str, pity: BannerPity) {
+        match pity_key(banner) {
+            STANDARD => {
+                self.sss_pity = pity.sss_pity;
+                self.s_pity = pity.s_pity;
+                self.a_pity = pity.a_pity;
+                self.has_slip = pity.sss_guaranteed;
+            }
+            key => {
+                self.banner_pity.insert(key.to_string(), pity);
+            }
+        }
+    }
+}
+
+fn running(banner: &Banner, now: DateTime<Utc>) -> bool {
+    banner.starts.is_none_or(|starts| starts <= now) && banner.ends.is_none_or(|ends| now < ends)
+}
+
+fn all() -> impl Iterator<Item = &'static Banner> {
+    std::iter::once(&*STANDARD_BANNER).chain(config::banners())
+}
+
+// Running or not, for looking back at pulls.
+pub(crate) fn by_id(id: &str) -> Option<&'static Banner> {
+    all().find(|b| b.id == id)
+}
+
+// The banner a pull goes to, the standard one when none is named.
+pub(crate) fn find(id: Option<&str>) -> Result<&'static Banner, ApiError> {
+    let id = id.unwrap_or(STANDARD);
+    let banner = by_id(id).ok_or(ApiError::NotFound("banner"))?;
+    if !running(banner, Utc::now()) {
+        return Err(ApiError::Forbidden(format!("banner {} is not running", id)));
+    }
+    Ok(banner)
+}
+
+impl Banner {
+    // Featured Mythics fall back to the standard 50/50, S and A only pay out
+    // their loot table.
+    pub(crate) fn mythic(&self) -> &RateUp {
+        self.featured.mythic.as_ref().unwrap_or(&STANDARD_MYTHIC)
+    }
+    pub(crate) fn rate_up(&self, outcome: &Rarities) -> Option<&RateUp> {
+        match outcome {
+            Rarities::MythicSSS => Some(self.mythic()),
+            Rarities::S => self.featured.s.as_ref(),
+            Rarities::A => self.featured.a.as_ref(),
+            Rarities::B => None,
+        }
+    }
+
+    // Template ids are per account, so a banner or a loot table can name one
+    // this account doesn't have. Checked before anything is spent.
+    pub(crate) fn check(&self, user: &User) -> Result<(), ApiError> {
+        let featured = [
+            Some(self.mythic()),
+            self.featured.s.as_ref(),
+            self.featured.a.as_ref(),
+        ];
+        let loot = &config::economy().loot;
+        let prizes = featured
+            .into_iter()
+            .flatten()
+            .flat_map(|rate_up| std::iter::once(&rate_up.prize).chain(&rate_up.off_rate))
+            .chain(
+                [&loot.mythic, &loot.s, &loot.a, &loot.b]
+                    .into_iter()
+                    .flatten()
+                    .flat_map(|draw| &draw.entries)
+                    .filter_map(|entry| match &entry.loot {
+                        Loot::Voucher(prize) => Some(prize),
+                        _ => None,
+                    }),
+            );
+        for prize in prizes {
+            if let (Prize::Template(id), None) = (prize, prize.voucher(user)) {
+                return Err(ApiError::Invalid(format!(
+                    "banner {} hands out template {}, which this account doesn't have",
+                    self.id, id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Prize {
+    pub(crate) fn voucher(&self, user: &User) -> Option<Voucher> {
+        match self {
+            Prize::Template(id) => Voucher::by_id(*id, user).ok(),
+            Prize::Custom {
+                name,
+                hours,
+                description,
+                coeff,
+                cost,
+            } => {
+                let coeff = Coeff::from(coeff.as_str());
+                Some(Voucher::new(
+                    0,
+                    uuid::Uuid::now_v7(),
+                    name.clone(),
+                    cost.unwrap_or((coeff.get_val() as f64 * hours) as u64),
+                    hours * 60.0,
+                    true,
+                    description.clone(),
+                    coeff,
+                ))
+            }
+        }
+    }
+}
+
+impl RateUp {
+    // A lost 50/50 guarantees the prize next time. The bool is true when that
+    // guarantee was spent on this draw.
+    pub(crate) fn draw(
+        &self,
+        guaranteed: &mut bool,
+        user: &User,
+        rng: &mut impl Rng,
+    ) -> Option<(Voucher, bool)> {
+        if *guaranteed {
+            *guaranteed = false;
+            return Some((self.prize.voucher(user)?, true));
+        }
+        match &self.off_rate {
+            Some(off_rate) if rng.random::<f64>() >= self.chance => {
+                *guaranteed = true;
+                Some((off_rate.voucher(user)?, false))
+            }
+            _ => Some((self.prize.voucher(user)?, false)),
+        }
+    }
+}
+
+pub(crate) fn read_pity(
+    conn: &Connection,
+    id: &UserId,
+) -> Result<HashMap<String, BannerPity>, PersistenceError> {
+    let pity = conn
+        .prepare(
+            "SELECT banner_id, sss_pity, s_pity, a_pity, sss_guaranteed, s_guaranteed,
+            a_guaranteed FROM banner_pity WHERE user_id = ?1",
+        )?
+        .query_map(params![id.0], |row| {
+            Ok((
+                row.get(0)?,
+                BannerPity {
+                    sss_pity: row.get(1)?,
+                    s_pity: row.get(2)?,
+                    a_pity: row.get(3)?,
+                    sss_guaranteed: row.get(4)?,
+                    s_guaranteed: row.get(5)?,
+                    a_guaranteed: row.get(6)?,
+                },
+            ))
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(pity)
+}
+pub(crate) fn write_pity(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let stored = read_pity(conn, &user.id)?;
+    let mut upsert = conn.prepare(
+        "INSERT INTO banner_pity (user_id, banner_id, sss_pity, s_pity, a_pity, sss_guaranteed,
+        s_guaranteed, a_guaranteed) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        ON CONFLICT (user_id, banner_id) DO UPDATE SET sss_pity = ?3, s_pity = ?4, a_pity = ?5,
+        sss_guaranteed = ?6, s_guaranteed = ?7, a_guaranteed = ?8",
+    )?;
+    for (banner, pity) in user.banner_pity.iter() {
+        if stored.get(banner) == Some(pity) {
+            continue;
+        }
+        upsert.execute(params![
+            user.id.0,
+            banner,
+            pity.sss_pity,
+            pity.s_pity,
+            pity.a_pity,
+            pity.sss_guaranteed,
+            pity.s_guaranteed,
+            pity.a_guaranteed,
+        ])?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub(crate) struct BannerInfo {
+    #[serde(flatten)]
+    banner: &'static Banner,
+    pity: BannerPity,
+}
+// The banners that can be pulled on right now, with this account's pity on
+// each.
+pub(crate) async fn banners(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+) -> Result<Json<Vec<BannerInfo>>, ApiError> {
+    let (user, _conn) = load_user(userid, &state)?;
+    let now = Utc::now();
+
+    Ok(Json(
+        all()
+            .filter(|banner| running(banner, now))
+            .map(|banner| BannerInfo {
+                banner,
+                pity: user.pity(&banner.id),
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "tester".into(), None)
+    }
+    fn rate_up(chance: f64) -> RateUp {
+        RateUp {
+            prize: Prize::Template(999),
+            off_rate: Some(Prize::Template(1)),
+            chance,
+        }
+    }
+
+    #[test]
+    fn a_lost_fifty_fifty_guarantees_the_prize() {
+        let user = user();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let mut guaranteed = false;
+
+        let (voucher, spent) = rate_up(0.0).draw(&mut guaranteed, &user, &mut rng).unwrap();
+        assert_eq!((voucher.id, spent, guaranteed), (1, false, true));
+        let (voucher, spent) = rate_up(0.0).draw(&mut guaranteed, &user, &mut rng).unwrap();
+        assert_eq!((voucher.id, spent, guaranteed), (999, true, false));
+
+        let (voucher, spent) = rate_up(1.0).draw(&mut guaranteed, &user, &mut rng).unwrap();
+        assert_eq!((voucher.id, spent, guaranteed), (999, false, false));
+    }
+
+    #[test]
+    fn prizes_without_a_template_are_refused() {
+        let user = user();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let missing = RateUp {
+            prize: Prize::Template(4242),
+            off_rate: None,
+            chance: 0.5,
+        };
+        assert!(missing.draw(&mut false, &user, &mut rng).is_none());
+
+        let custom = Prize::Custom {
+            name: "Nap".into(),
+            hours: 2.0,
+            description: "Sleep it off".into(),
+            coeff: "pure_c".into(),
+            cost: None,
+        };
+        let voucher = custom.voucher(&user).unwrap();
+        assert_eq!((voucher.name.as_str(), voucher.hours()), ("Nap", 2.0));
+        assert_eq!(voucher.cost, Coeff::pure_c().get_val() * 2);
+
+        let banner = Banner {
+            featured: Featured {
+                mythic: Some(missing),
+                s: None,
+                a: None,
+            },
+            ..STANDARD_BANNER.clone()
+        };
+        assert!(matches!(banner.check(&user), Err(ApiError::Invalid(_))));
+        assert!(STANDARD_BANNER.check(&user).is_ok());
+    }
+
+    #[test]
+    fn the_standard_banner_keeps_its_pity_on_the_user() {
+        let mut user = user();
+        let pity = BannerPity {
+            sss_pity: 12,
+            sss_guaranteed: true,
+            s_guaranteed: true,
+            ..BannerPity::default()
+        };
+        user.set_pity(STANDARD, pity);
+        assert_eq!((user.sss_pity, user.has_slip), (12, true));
+        // Only the Mythic guarantee has somewhere to go on the standard banner.
+        assert_eq!(
+            user.pity(STANDARD),
+            BannerPity {
+                s_guaranteed: false,
+                ..pity
+            }
+        );
+        assert_eq!(user.pity("someone-else"), BannerPity::default());
+    }
+}
@@ -0,0 +1,595 @@
+use axum::Json;
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, Datelike, Duration, Months, Utc};
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::config;
+use crate::error::ApiError;
+use crate::expiry;
+use crate::ledger::{Currency, LedgerSource};
+use crate::pricing::Purchase;
+use crate::repo::json_col;
+use crate::stock;
+use crate::{
+    AppState, Daily, PersistenceError, User, UserId, Voucher, decrease_flux, load_user, save_user,
+};
+
+// Same cap as /create_advanced.
+const MAX_VOUCHERS: u32 = 26;
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS bundles (
+    user_id TEXT NOT NULL REFERENCES users (id),
+    id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    price INTEGER NOT NULL,
+    currency TEXT NOT NULL,
+    contents TEXT NOT NULL,
+    purchase_limit TEXT,
+    PRIMARY KEY (user_id, id)
+);
+CREATE TABLE IF NOT EXISTS bundle_orders (
+    uuid TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    bundle_id INTEGER NOT NULL,
+    at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS bundle_orders_user ON bundle_orders (user_id, bundle_id, at);
+";
+
+// Periods follow the daily reset: days start at the 04:00 UTC cycle, weeks on
+// Monday's and months on the first's.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Period {
+    Day,
+    Week,
+    Month,
+    Ever,
+}
+impl Period {
+    pub(crate) fn start(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let day = Daily::cycle_at(now, 0);
+        match self {
+            Period::Day => Some(day),
+            Period::Week => Some(day - Duration::days(day.weekday().num_days_from_monday() as i64)),
+            Period::Month => Some(day - Duration::days(day.day0() as i64)),
+            Period::Ever => None,
+        }
+    }
+    pub(crate) fn next(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = self.start(now)?;
+        match self {
+            Period::Day => Some(start + Duration::days(1)),
+            Period::Week => Some(start + Duration::weeks(1)),
+            Period::Month => start.checked_add_months(Months::new(1)),
+            Period::Ever => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limit {
+    count: u32,
+    per: Period,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Item {
+    id: u64,
+    amount: u8,
+}
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Contents {
+    templates: Vec<Item>,
+    astrum: u64,
+    astrai: u64,
+    flux: u64,
+}
+
+// Everything about a bundle but its id, as /create_bundle takes it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct BundleSpec {
+    name: String,
+    #[serde(default)]
+    description: String,
+    price: u64,
+    currency: Currency,
+    contents: Contents,
+    #[serde(default)]
+    limit: Option<Limit>,
+}
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Bundle {
+    id: u64,
+    #[serde(flatten)]
+    spec: BundleSpec,
+}
+
+// A bundle bought, queued on the user like ledger entries and written by the
+// same save, so the limit can't count an order that was never granted.
+#[derive(Debug, Clone)]
+pub(crate) struct Order {
+    uuid: Uuid,
+    bundle: u64,
+    at: DateTime<Utc>,
+}
+
+pub(crate) fn write_orders(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let mut stmt = conn.prepare(
+        "INSERT OR IGNORE INTO bundle_orders (uuid, user_id, bundle_id, at)
+        VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for order in user.orders.iter() {
+        stmt.execute(params![
+            order.uuid.to_string(),
+            user.id.0,
+            order.bundle as i64,
+            order.at.timestamp()
+        ])?;
+    }
+    Ok(())
+}
+
+fn json<T: Serialize>(value: &T) -> Result<String, PersistenceError> {
+    Ok(serde_json::to_string(value)?)
+}
+const COLUMNS: &str = "id, name, description, price, currency, contents, purchase_limit";
+fn read_bundle(row: &Row) -> rusqlite::Result<Bundle> {
+    let limit: Option<String> = row.get(6)?;
+    Ok(Bundle {
+        id: row.get::<_, i64>(0)? as u64,
+        spec: BundleSpec {
+            name: row.get(1)?,
+            description: row.get(2)?,
+            price: row.get::<_, i64>(3)? as u64,
+            currency: json_col(row, 4)?,
+            contents: json_col(row, 5)?,
+            limit: limit.and_then(|l| serde_json::from_str(&l).ok()),
+        },
+    })
+}
+
+fn find(conn: &Connection, user: &UserId, id: u64) -> Result<Option<Bundle>, PersistenceError> {
+    Ok(conn
+        .query_row(
+            &format!(
+                "SELECT {} FROM bundles WHERE user_id = ?1 AND id = ?2",
+                COLUMNS
+            ),
+            params![user.0, id as i64],
+            read_bundle,
+        )
+        .optional()?)
+}
+
+// Orders of a bundle since `since`, or ever.
+fn bought(
+    conn: &Connection,
+    user: &UserId,
+    id: u64,
+    since: Option<DateTime<Utc>>,
+) -> Result<u32, PersistenceError> {
+    Ok(conn.query_row(
+        "SELECT COUNT(*) FROM bundle_orders WHERE user_id = ?1 AND bundle_id = ?2 AND at >= ?3",
+        params![user.0, id as i64, since.map_or(i64::MIN, |s| s.timestamp())],
+        |row| row.get(0),
+    )?)
+}
+
+// What the contents are worth in the bundle's currency: vouchers at their
+// template's cost plus the flux for flux, astrum plus astrai at the pull price
+// for astrum. Contents that can't be valued in it are refused, or a bundle
+// would trade one currency for another at whatever rate it was given.
+fn worth(spec: &BundleSpec, user: &User) -> Result<u64, ApiError> {
+    let contents = &spec.contents;
+    match spec.currency {
+        Currency::Flux if contents.astrum > 0 || contents.astrai > 0 => Err(ApiError::Invalid(
+            "astrum and astrai only come in bundles paid for in astrum".into(),
+        )),
+        Currency::Flux => {
+            let mut worth = contents.flux;
+            for item in contents.templates.iter() {
+                let template =
+                    Voucher::by_id(item.id, user).map_err(|_| ApiError::NotFound("voucher"))?;
+                worth += template.cost * item.amount as u64;
+            }
+            Ok(worth)
+        }
+        _ if !contents.templates.is_empty() || contents.flux > 0 => Err(ApiError::Invalid(
+            "vouchers and flux only come in bundles paid for in flux".into(),
+        )),
+        _ => Ok(contents.astrum + contents.astrai * config::economy().astrum_per_pull),
+    }
+}
+
+fn check(spec: &BundleSpec, user: &User) -> Result<u64, ApiError> {
+    if spec.name.is_empty() {
+        return Err(ApiError::Invalid("a bundle needs a name".into()));
+    }
+    if spec.currency == Currency::Astrai {
+        return Err(ApiError::Invalid(
+            "bundles are paid for in flux or astrum".into(),
+        ));
+    }
+    let contents = &spec.contents;
+    if contents.templates.is_empty()
+        && contents.astrum == 0
+        && contents.astrai == 0
+        && contents.flux == 0
+    {
+        return Err(ApiError::Invalid("a bundle can't be empty".into()));
+    }
+    for item in contents.templates.iter() {
+        Voucher::by_id(item.id, user).map_err(|_| ApiError::NotFound("voucher"))?;
+    }
+    let vouchers: u32 = contents.templates.iter().map(|i| i.amount as u32).sum();
+    if contents.templates.iter().any(|i| i.amount == 0) || vouchers > MAX_VOUCHERS {
+        return Err(ApiError::Invalid(format!(
+            "every template needs an amount, at most {} vouchers per bundle",
+            MAX_VOUCHERS
+        )));
+    }
+    if spec.limit.as_ref().is_some_and(|l| l.count == 0) {
+        return Err(ApiError::Invalid(
+            "a limit needs a count of at least 1".into(),
+        ));
+    }
+    let worth = worth(spec, user)?;
+    let lowest = lowest_price(worth, config::economy().pricing.bundle_discount);
+    if spec.price < lowest {
+        return Err(ApiError::Invalid(format!(
+            "a bundle can't cost less than {} {}, its contents are worth {}",
+            lowest,
+            spec.currency.name(),
+            worth
+        )));
+    }
+    Ok(worth)
+}
+// The discount is rounded down, so a bundle is never allowed below it.
+fn lowest_price(worth: u64, discount: f64) -> u64 {
+    worth - (worth as f64 * discount).floor() as u64
+}
+
+#[derive(Serialize)]
+pub(crate) struct BundleInfo {
+    #[serde(flatten)]
+    bundle: Bundle,
+    // Orders in the current period, or ever without a limit.
+    bought: u32,
+    remaining: Option<u32>,
+    resets: Option<DateTime<Utc>>,
+}
+pub(crate) async fn get_bundles(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+) -> Result<Json<Vec<BundleInfo>>, ApiError> {
+    let conn = state.repo.db.lock().unwrap();
+    let now = Utc::now();
+    let bundles = conn
+        .prepare(&format!(
+            "SELECT {} FROM bundles WHERE user_id = ?1 ORDER BY id",
+            COLUMNS
+        ))?
+        .query_map(params![id.0], read_bundle)?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut listed = Vec::new();
+    for bundle in bundles {
+        let limit = bundle.spec.limit.clone();
+        let since = limit.as_ref().and_then(|l| l.per.start(now));
+        let bought = bought(&conn, &id, bundle.id, since)?;
+        listed.push(BundleInfo {
+            bundle,
+            bought,
+            remaining: limit.as_ref().map(|l| l.count.saturating_sub(bought)),
+            resets: limit.and_then(|l| l.per.next(now)),
+        });
+    }
+    Ok(Json(listed))
+}
+
+pub(crate) async fn create_bundle(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(spec): Json<BundleSpec>,
+) -> Result<Json<Bundle>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    check(&spec, &user)?;
+
+    // Ids aren't reused, a new bundle mustn't inherit a deleted one's orders.
+    let id: i64 = conn.query_row(
+        "SELECT MAX(COALESCE((SELECT MAX(id) FROM bundles WHERE user_id = ?1), 0),
+        COALESCE((SELECT MAX(bundle_id) FROM bundle_orders WHERE user_id = ?1), 0)) + 1",
+        params![user.id.0],
+        |row| row.get(0),
+    )?;
+    conn.execute(
+        "INSERT INTO bundles (user_id, id, name, description, price, currency, contents,
+        purchase_limit) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        params![
+            user.id.0,
+            id,
+            spec.name,
+            spec.description,
+            spec.price as i64,
+            json(&spec.currency)?,
+            json(&spec.contents)?,
+            spec.limit.as_ref().map(json).transpose()?,
+        ],
+    )?;
+    Ok(Json(Bundle {
+        id: id as u64,
+        spec,
+    }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateBundle {
+    id: u64,
+    #[serde(flatten)]
+    spec: BundleSpec,
+}
+// Replaces everything but the id. Orders made so far still count against the
+// limit.
+pub(crate) async fn update_bundle(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<UpdateBundle>,
+) -> Result<Json<Bundle>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    check(&req.spec, &user)?;
+    let spec = req.spec;
+    let updated = conn.execute(
+        "UPDATE bundles SET name = ?3, description = ?4, price = ?5, currency = ?6,
+        contents = ?7, purchase_limit = ?8 WHERE user_id = ?1 AND id = ?2",
+        params![
+            user.id.0,
+            req.id as i64,
+            spec.name,
+            spec.description,
+            spec.price as i64,
+            json(&spec.currency)?,
+            json(&spec.contents)?,
+            spec.limit.as_ref().map(json).transpose()?,
+        ],
+    )?;
+    match updated {
+        0 => Err(ApiError::NotFound("bundle")),
+        _ => Ok(Json(Bundle { id: req.id, spec })),
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct BundleRequest {
+    id: u64,
+}
+pub(crate) async fn delete_bundle(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    Json(req): Json<BundleRequest>,
+) -> Result<StatusCode, ApiError> {
+    let conn = state.repo.db.lock().unwrap();
+    let deleted = conn.execute(
+        "DELETE FROM bundles WHERE user_id = ?1 AND id = ?2",
+        params![id.0, req.id as i64],
+    )?;
+    match deleted {
+        0 => Err(ApiError::NotFound("bundle")),
+        _ => Ok(StatusCode::OK),
+    }
+}
+
+// Vouchers of a template in the bundle, wherever it's listed.
+fn contents_of(contents: &Contents, id: u64) -> u32 {
+    contents
+        .templates
+        .iter()
+        .filter(|i| i.id == id)
+        .map(|i| i.amount as u32)
+        .sum()
+}
+
+// A voucher's part of what was paid for the bundle, in proportion to its cost.
+// Refunds hand this back, never more than the bundle cost.
+fn share(cost: u64, price: u64, worth: u64) -> u64 {
+    match worth {
+        0 => 0,
+        _ => (cost as u128 * price as u128 / worth as u128) as u64,
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct BundleBought {
+    order: Uuid,
+    name: String,
+    vouchers: Vec<Voucher>,
+    astrum: u64,
+    astrai: u64,
+    flux: u64,
+}
+// Everything is checked before anything moves and the whole grant goes out in
+// one save, so a bundle is handed out complete or not at all.
+pub(crate) async fn buy_bundle(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<BundleRequest>,
+) -> Result<Json<BundleBought>, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+    let Bundle { id, spec } = find(&conn, &user.id, req.id)?.ok_or(ApiError::NotFound("bundle"))?;
+    // Checked again, templates may have been repriced since it was made.
+    let worth = check(&spec, &user)?;
+    let now = Utc::now();
+
+    let sold_out = match &spec.limit {
+        Some(limit) => bought(&conn, &user.id, id, limit.per.start(now))? >= limit.count,
+        None => false,
+    };
+    if sold_out {
+        let until = spec.limit.as_ref().and_then(|l| l.per.next(now));
+        return Err(ApiError::Forbidden(match until {
+            Some(next) => format!("{} is sold out until {}", spec.name, next),
+            None => format!("{} is sold out", spec.name),
+        }));
+    }
+    let have = user.balance(spec.currency) as i128;
+    if have < spec.price as i128 {
+        return Err(ApiError::Insufficient {
+            currency: spec.currency.name(),
+            need: spec.price as i128,
+            have,
+        });
+    }
+    let mut vouchers = Vec::new();
+    for item in spec.contents.templates.iter() {
+        let template = Voucher::by_id(item.id, &user).map_err(|_| ApiError::NotFound("voucher"))?;
+        let amount = contents_of(&spec.contents, item.id);
+        stock::check(&conn, &user.id, &template, amount, now)?;
+        for _ in 0..item.amount {
+            let mut voucher = template.clone();
+            voucher.uuid = Uuid::now_v7();
+            voucher.new = true;
+            voucher.paid = Some(share(template.cost, spec.price, worth));
+            voucher.acquire(expiry::Source::Purchase);
+            vouchers.push(voucher);
+        }
+    }
+
+    let order = Uuid::now_v7();
+    match spec.currency {
+        Currency::Flux => decrease_flux(
+            &mut user,
+            spec.price as i128,
+            LedgerSource::Purchase,
+            Some(order),
+        )?,
+        currency => user.adjust(
+            currency,
+            -(spec.price as i64),
+            LedgerSource::Purchase,
+            Some(order),
+        )?,
+    }
+    let contents = &spec.contents;
+    for (currency, amount) in [
+        (Currency::Astrum, contents.astrum),
+        (Currency::Astrai, contents.astrai),
+        (Currency::Flux, contents.flux),
+    ] {
+        user.adjust(currency, amount as i64, LedgerSource::Bundle, Some(order))?;
+    }
+    user.vouchers.extend(vouchers.iter().cloned());
+    for item in contents.templates.iter() {
+        user.purchases.push(Purchase {
+            template: item.id,
+            amount: item.amount as u32,
+            at: now,
+        });
+    }
+    user.orders.push(Order {
+        uuid: order,
+        bundle: id,
+        at: now,
+    });
+    save_user(&user, &conn, &state)?;
+
+    Ok(Json(BundleBought {
+        order,
+        name: spec.name,
+        vouchers,
+        astrum: contents.astrum,
+        astrai: contents.astrai,
+        flux: contents.flux,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn periods_follow_the_daily_reset() {
+        // A Wednesday, before the 04:00 reset.
+        let now = Utc.with_ymd_and_hms(2026, 10, 14, 2, 0, 0).unwrap();
+        let at = |d, h| Utc.with_ymd_and_hms(2026, 10, d, h, 0, 0).unwrap();
+        assert_eq!(Period::Day.start(now), Some(at(13, 4)));
+        assert_eq!(Period::Day.next(now), Some(at(14, 4)));
+        assert_eq!(Period::Week.start(now), Some(at(12, 4)));
+        assert_eq!(Period::Week.next(now), Some(at(19, 4)));
+        assert_eq!(Period::Month.start(now), Some(at(1, 4)));
+        assert_eq!(
+            Period::Month.next(now),
+            Some(Utc.with_ymd_and_hms(2026, 11, 1, 4, 0, 0).unwrap())
+        );
+        assert_eq!(Period::Ever.start(now), None);
+    }
+
+    fn spec(price: u64, currency: Currency, contents: Contents) -> BundleSpec {
+        BundleSpec {
+            name: "Weekend".into(),
+            description: String::new(),
+            price,
+            currency,
+            contents,
+            limit: None,
+        }
+    }
+
+    #[test]
+    fn bundles_cost_at_least_their_contents() {
+        let user = User::new(UserId("tester".into()), "tester".into(), None);
+        let coffee = || Contents {
+            templates: vec![Item { id: 4, amount: 2 }],
+            flux: 100,
+            ..Contents::default()
+        };
+        assert_eq!(
+            check(&spec(400, Currency::Flux, coffee()), &user).unwrap(),
+            400
+        );
+        assert!(matches!(
+            check(&spec(399, Currency::Flux, coffee()), &user),
+            Err(ApiError::Invalid(_))
+        ));
+        assert!(matches!(
+            check(&spec(10_000, Currency::Astrum, coffee()), &user),
+            Err(ApiError::Invalid(_))
+        ));
+
+        let pulls = Contents {
+            astrai: 2,
+            ..Contents::default()
+        };
+        assert_eq!(
+            check(&spec(320, Currency::Astrum, pulls.clone()), &user).unwrap(),
+            320
+        );
+        assert!(check(&spec(319, Currency::Astrum, pulls), &user).is_err());
+    }
+
+    #[test]
+    fn bundles_can_be_discounted_as_far_as_configured() {
+        assert_eq!(lowest_price(400, 0.0), 400);
+        assert_eq!(lowest_price(400, 0.1), 360);
+        assert_eq!(lowest_price(400, 0.25), 300);
+        assert_eq!(lowest_price(399, 0.5), 200);
+        assert_eq!(lowest_price(400, 1.0), 0);
+    }
+
+    #[test]
+    fn vouchers_are_paid_their_share() {
+        assert_eq!(share(150, 800, 400), 300);
+        assert_eq!(share(150, 400, 400), 150);
+        assert_eq!(share(150, 0, 0), 0);
+    }
+}
@@ -0,0 +1,761 @@
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_PATH: &str = "gacha.toml";
+
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ConfigError {
+    #[error("Unable to read config {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Invalid config {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("Invalid config value {0}: {1}")]
+    Invalid(&'static str, String),
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) network: Network,
+    pub(crate) storage: Storage,
+    pub(crate) economy: Economy,
+    pub(crate) fairness: Fairness,
+    pub(crate) pity: Pity,
+    pub(crate) banners: Vec<Banner>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Network {
+    pub(crate) bind: String,
+}
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            bind: "11.0.0.2:3000".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Storage {
+    pub(crate) db_path: PathBuf,
+}
+impl Default for Storage {
+    fn default() -> Self {
+        Self {
+            db_path: "userdata.sql".into(),
+        }
+    }
+}
+
+// With `commit_reveal` the hash of each day's server seed is published through
+// /seeds before any pull uses it, and the seed itself once the day is over.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Fairness {
+    pub(crate) commit_reveal: bool,
+}
+
+// Without `curves` rarities are rolled by gacha_protocol with its built-in
+// rates. `resets_lower` makes a rarity reset the counters below it as well,
+// `carry_over` makes every limited banner share one set of pity counters (and
+// 50/50 guarantees) instead of each starting from zero.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Pity {
+    pub(crate) curves: Option<Curves>,
+    pub(crate) resets_lower: bool,
+    pub(crate) carry_over: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Curves {
+    pub(crate) mythic: Curve,
+    pub(crate) s: Curve,
+    pub(crate) a: Curve,
+}
+
+// The chance of a rarity on the nth pull since the last one: `base`, plus
+// `soft_step` for every pull past `soft_start`, and certain from `hard` on.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Curve {
+    pub(crate) base: f64,
+    #[serde(default)]
+    pub(crate) soft_start: Option<u16>,
+    #[serde(default)]
+    pub(crate) soft_step: f64,
+    #[serde(default)]
+    pub(crate) hard: Option<u16>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Economy {
+    pub(crate) astrum_per_pull: u64,
+    // Flux spent today before the flux daily (id 3) can be claimed.
+    pub(crate) flux_threshold: u64,
+    pub(crate) loot: LootTables,
+    pub(crate) dailies: Dailies,
+    pub(crate) bars: Vec<BarTuning>,
+    pub(crate) expiry: Expiry,
+    pub(crate) pricing: Pricing,
+    pub(crate) stock: Vec<Stock>,
+}
+impl Default for Economy {
+    fn default() -> Self {
+        Self {
+            astrum_per_pull: 160,
+            flux_threshold: 324,
+            loot: LootTables::default(),
+            dailies: Dailies::default(),
+            bars: vec![
+                BarTuning::new(1.5, 240.0, 360.0),
+                BarTuning::new(1.2, 210.0, 360.0),
+                BarTuning::new(1.0, 90.0, 480.0),
+                BarTuning::new(0.7, 150.0, 600.0),
+                BarTuning::new(1.1, 90.0, 480.0),
+                BarTuning::new(0.0, 180.0, 10000.0),
+            ],
+            expiry: Expiry::default(),
+            pricing: Pricing::default(),
+            stock: Vec::new(),
+        }
+    }
+}
+
+// How long vouchers stay usable, by where they came from. A rule for the
+// voucher's template id wins over the one for its source. An expired voucher
+// hands back `refund` (a share of its cost) in flux. Nothing expires by
+// default.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Expiry {
+    pub(crate) pull: ExpiryRule,
+    pub(crate) purchase: ExpiryRule,
+    // What is handed back when a voucher is only partly used.
+    pub(crate) remainder: ExpiryRule,
+    pub(crate) templates: Vec<TemplateExpiry>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ExpiryRule {
+    pub(crate) hours: Option<f64>,
+    pub(crate) refund: f64,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TemplateExpiry {
+    pub(crate) id: u64,
+    #[serde(default)]
+    pub(crate) hours: Option<f64>,
+    #[serde(default)]
+    pub(crate) refund: f64,
+}
+
+// Store prices. A template costs its coefficient's hourly rate for every hour
+// it lasts, then each modifier that applies scales that. None do by default.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Pricing {
+    pub(crate) bulk: Vec<BulkDiscount>,
+    pub(crate) surcharges: Vec<Surcharge>,
+    pub(crate) scarcity: Scarcity,
+    pub(crate) demand: Demand,
+    // How far (a share) a bundle can be priced below what its contents are
+    // worth. 0 keeps bundles at their worth or above.
+    pub(crate) bundle_discount: f64,
+}
+
+// Orders of at least `min_amount` vouchers are `discount` (a share) cheaper.
+// The biggest tier reached counts.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BulkDiscount {
+    pub(crate) min_amount: u32,
+    pub(crate) discount: f64,
+}
+
+// Prices are `multiplier` times as high from `from_hour` up to `to_hour` (UTC).
+// A window wraps past midnight when `to_hour` is the smaller one.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Surcharge {
+    pub(crate) from_hour: u32,
+    pub(crate) to_hour: u32,
+    pub(crate) multiplier: f64,
+}
+
+// Every unused copy of a template already held makes the next one `step` (a
+// share) pricier, up to `max` times the price.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Scarcity {
+    pub(crate) step: f64,
+    pub(crate) max: f64,
+}
+impl Default for Scarcity {
+    fn default() -> Self {
+        Self {
+            step: 0.0,
+            max: 2.0,
+        }
+    }
+}
+
+// Every voucher bought from a template adds `step` (a share) to its price,
+// fading out linearly over `window_hours`, up to `max` times the price.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Demand {
+    pub(crate) step: f64,
+    pub(crate) window_hours: f64,
+    pub(crate) max: f64,
+}
+impl Default for Demand {
+    fn default() -> Self {
+        Self {
+            step: 0.0,
+            window_hours: 168.0,
+            max: 3.0,
+        }
+    }
+}
+
+// How many vouchers of template `id` can be bought per day, week and month,
+// and ever. Unset caps don't apply, templates without an entry are unlimited.
+// Days restock at the 04:00 UTC reset, weeks on Monday's and months on the
+// first's.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Stock {
+    pub(crate) id: u64,
+    #[serde(default)]
+    pub(crate) per_day: Option<u32>,
+    #[serde(default)]
+    pub(crate) per_week: Option<u32>,
+    #[serde(default)]
+    pub(crate) per_month: Option<u32>,
+    #[serde(default)]
+    pub(crate) lifetime: Option<u32>,
+}
+
+// What each rarity pays out, on top of a banner's featured prize. Every draw
+// in a rarity's list is rolled once per pull and picks at most one entry,
+// `nothing` is the weight of it coming up empty.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LootTables {
+    pub(crate) mythic: Vec<LootDraw>,
+    pub(crate) s: Vec<LootDraw>,
+    pub(crate) a: Vec<LootDraw>,
+    pub(crate) b: Vec<LootDraw>,
+}
+impl Default for LootTables {
+    fn default() -> Self {
+        // What `apply_outcome` paid before the tables. Its S pulls counted
+        // vouchers without handing any out.
+        let voucher = |name: &str, hours: f64, cost: u64, description: &str| {
+            Loot::Voucher(Prize::Custom {
+                name: name.into(),
+                hours,
+                description: description.into(),
+                coeff: "pure_c".into(),
+                cost: Some(cost),
+            })
+        };
+        Self {
+            mythic: vec![LootDraw::always(Loot::Flux(2400))],
+            s: vec![LootDraw::always(Loot::Flux(240))],
+            a: vec![
+                LootDraw::always(Loot::Flux(70)),
+                LootDraw::chance(
+                    200,
+                    56,
+                    voucher(
+                        "15 Minute Break",
+                        0.0,
+                        51,
+                        "Take a 15 minute breather break",
+                    ),
+                ),
+            ],
+            b: vec![
+                LootDraw::always(Loot::Flux(4)),
+                LootDraw::chance(1, 255, Loot::Voucher(Prize::Template(1))),
+            ],
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LootDraw {
+    #[serde(default)]
+    pub(crate) nothing: u32,
+    pub(crate) entries: Vec<LootEntry>,
+}
+impl LootDraw {
+    fn always(loot: Loot) -> Self {
+        Self {
+            nothing: 0,
+            entries: vec![LootEntry::new(1, loot)],
+        }
+    }
+    fn chance(weight: u32, nothing: u32, loot: Loot) -> Self {
+        Self {
+            nothing,
+            entries: vec![LootEntry::new(weight, loot)],
+        }
+    }
+}
+
+// `{ weight = 200, flux = 70 }`, `{ weight = 1, voucher = 4 }`.
+#[derive(Deserialize, Clone, Debug)]
+pub(crate) struct LootEntry {
+    pub(crate) weight: u32,
+    #[serde(flatten)]
+    pub(crate) loot: Loot,
+}
+impl LootEntry {
+    fn new(weight: u32, loot: Loot) -> Self {
+        Self { weight, loot }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Loot {
+    Flux(i64),
+    Astrum(i64),
+    Astrai(i64),
+    Voucher(Prize),
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Reward {
+    pub(crate) astrum: u16,
+    pub(crate) astrai: u16,
+    pub(crate) flux: u16,
+    pub(crate) vouchers: u16,
+}
+impl Reward {
+    pub(crate) fn add(&mut self, other: &Reward) {
+        self.astrum = self.astrum.saturating_add(other.astrum);
+        self.astrai = self.astrai.saturating_add(other.astrai);
+        self.flux = self.flux.saturating_add(other.flux);
+        self.vouchers = self.vouchers.saturating_add(other.vouchers);
+    }
+    fn new(astrum: u16, astrai: u16, flux: u16, vouchers: u16) -> Self {
+        Self {
+            astrum,
+            astrai,
+            flux,
+            vouchers,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Dailies {
+    // Indexed by daily id, 0 through 4.
+    pub(crate) rewards: Vec<Reward>,
+    // Paid once three dailies are claimed, and again once all of them are.
+    pub(crate) three_claimed: Reward,
+    pub(crate) all_claimed: Reward,
+}
+impl Default for Dailies {
+    fn default() -> Self {
+        Self {
+            rewards: vec![
+                Reward::new(240, 0, 0, 0),
+                Reward::new(160, 2, 0, 0),
+                Reward::new(80, 0, 100, 0),
+                Reward::new(80, 0, 0, 0),
+                Reward::new(1600, 0, 0, 1),
+            ],
+            three_claimed: Reward::new(100, 2, 100, 0),
+            all_claimed: Reward::new(500, 0, 50, 0),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct BarTuning {
+    pub(crate) c: f64,
+    pub(crate) smax: f64,
+    pub(crate) tmax: f64,
+}
+impl BarTuning {
+    fn new(c: f64, smax: f64, tmax: f64) -> Self {
+        Self { c, smax, tmax }
+    }
+}
+
+// A limited banner next to the standard one. Dates are RFC 3339 strings, a
+// banner without them never closes.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Banner {
+    pub(crate) id: String,
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) starts: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) ends: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub(crate) featured: Featured,
+}
+
+// Featured prizes come on top of the rarity's loot table. A banner without a
+// Mythic of its own uses the standard week off / day off 50/50.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Featured {
+    pub(crate) mythic: Option<RateUp>,
+    pub(crate) s: Option<RateUp>,
+    pub(crate) a: Option<RateUp>,
+}
+
+// `chance` is the 50/50: losing it gives `off_rate` and guarantees `prize` the
+// next time this rarity drops on the same banner.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RateUp {
+    pub(crate) prize: Prize,
+    #[serde(default)]
+    pub(crate) off_rate: Option<Prize>,
+    #[serde(default = "half")]
+    pub(crate) chance: f64,
+}
+fn half() -> f64 {
+    0.5
+}
+
+// A template id from the store, or a voucher that only this banner hands out.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub(crate) enum Prize {
+    Template(u64),
+    Custom {
+        name: String,
+        hours: f64,
+        #[serde(default)]
+        description: String,
+        #[serde(default)]
+        coeff: String,
+        // Worth the coefficient's rate for `hours` when left out.
+        #[serde(default)]
+        cost: Option<u64>,
+    },
+}
+
+impl Config {
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, why: &str| Err(ConfigError::Invalid(field, why.into()));
+
+        if let Err(e) = self.network.bind.parse::<SocketAddr>() {
+            return invalid("network.bind", &format!("{} ({})", e, self.network.bind));
+        }
+        if self.storage.db_path.as_os_str().is_empty() {
+            return invalid("storage.db_path", "must not be empty");
+        }
+        let economy = &self.economy;
+        if economy.astrum_per_pull == 0 {
+            return invalid("economy.astrum_per_pull", "must be above 0");
+        }
+        let loot = &economy.loot;
+        for draw in [&loot.mythic, &loot.s, &loot.a, &loot.b]
+            .into_iter()
+            .flatten()
+        {
+            if draw.nothing + draw.entries.iter().map(|e| e.weight).sum::<u32>() == 0 {
+                return invalid("economy.loot", "every draw needs a weight above 0");
+            }
+            let negative = draw.entries.iter().any(|e| match e.loot {
+                Loot::Flux(n) | Loot::Astrum(n) | Loot::Astrai(n) => n < 0,
+                Loot::Voucher(_) => false,
+            });
+            if negative {
+                return invalid("economy.loot", "rewards can't be negative");
+            }
+        }
+        if economy.dailies.rewards.len() != 5 {
+            return invalid("economy.dailies.rewards", "needs one entry per daily (5)");
+        }
+        if economy.bars.len() != 6 {
+            return invalid("economy.bars", "needs one entry per bar (6)");
+        }
+        for bar in economy.bars.iter() {
+            if bar.c < 0.0 || bar.smax <= 0.0 || bar.tmax <= 0.0 {
+                return invalid(
+                    "economy.bars",
+                    "c can't be negative, smax and tmax must be above 0",
+                );
+            }
+        }
+        let expiry = &economy.expiry;
+        let rules = [&expiry.pull, &expiry.purchase, &expiry.remainder]
+            .into_iter()
+            .map(|rule| (rule.hours, rule.refund))
+            .chain(expiry.templates.iter().map(|t| (t.hours, t.refund)));
+        for (hours, refund) in rules {
+            if hours.is_some_and(|h| h <= 0.0) || !(0.0..=1.0).contains(&refund) {
+                return invalid(
+                    "economy.expiry",
+                    "hours must be above 0, refund between 0 and 1",
+                );
+            }
+        }
+        let pricing = &economy.pricing;
+        if pricing
+            .bulk
+            .iter()
+            .any(|b| b.min_amount < 2 || !(0.0..1.0).contains(&b.discount))
+        {
+            return invalid(
+                "economy.pricing.bulk",
+                "min_amount must be at least 2, discount between 0 and 1",
+            );
+        }
+        if pricing
+            .surcharges
+            .iter()
+            .any(|s| s.from_hour > 23 || s.to_hour > 24 || s.multiplier <= 0.0)
+        {
+            return invalid(
+                "economy.pricing.surcharges",
+                "hours must be within a day, multiplier above 0",
+            );
+        }
+        if pricing.scarcity.step < 0.0 || pricing.scarcity.max < 1.0 {
+            return invalid(
+                "economy.pricing.scarcity",
+                "step can't be negative, max must be at least 1",
+            );
+        }
+        if !(0.0..=1.0).contains(&pricing.bundle_discount) {
+            return invalid("economy.pricing.bundle_discount", "must be between 0 and 1");
+        }
+        let demand = &pricing.demand;
+        if demand.step < 0.0 || demand.window_hours <= 0.0 || demand.max < 1.0 {
+            return invalid(
+                "economy.pricing.demand",
+                "step can't be negative, window_hours must be above 0, max at least 1",
+            );
+        }
+        for (idx, stock) in economy.stock.iter().enumerate() {
+            if economy.stock[..idx].iter().any(|s| s.id == stock.id) {
+                return invalid("economy.stock.id", "each template can only be listed once");
+            }
+        }
+        if let Some(curves) = &self.pity.curves {
+            for curve in [&curves.mythic, &curves.s, &curves.a] {
+                if !(0.0..=1.0).contains(&curve.base) || curve.soft_step < 0.0 {
+                    return invalid(
+                        "pity.curves",
+                        "base must be between 0 and 1, soft_step can't be negative",
+                    );
+                }
+                if curve.hard == Some(0) {
+                    return invalid("pity.curves.hard", "must be above 0");
+                }
+            }
+        }
+        for (idx, banner) in self.banners.iter().enumerate() {
+            if banner.id.is_empty() || banner.id == "standard" || banner.id == "limited" {
+                return invalid(
+                    "banners.id",
+                    "must be set and can't be \"standard\" or \"limited\"",
+                );
+            }
+            if self.banners[..idx].iter().any(|b| b.id == banner.id) {
+                return invalid("banners.id", &format!("{} is used twice", banner.id));
+            }
+            if banner.starts.zip(banner.ends).is_some_and(|(s, e)| s >= e) {
+                return invalid(
+                    "banners.ends",
+                    &format!("{} ends before it starts", banner.id),
+                );
+            }
+            let featured = &banner.featured;
+            for rate_up in [&featured.mythic, &featured.s, &featured.a]
+                .into_iter()
+                .flatten()
+            {
+                if !(0.0..=1.0).contains(&rate_up.chance) {
+                    return invalid("banners.featured.chance", "must be between 0 and 1");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn arg_value(args: &[String], flag: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == flag)
+        .and_then(|pos| args.get(pos + 1).cloned())
+}
+
+// File first, then env, then the command line. The default path may be
+// missing, a path that was asked for explicitly may not.
+pub(crate) fn load(args: &[String]) -> Result<Config, ConfigError> {
+    let explicit = arg_value(args, "--config").or(std::env::var("GACHA_CONFIG").ok());
+    let path = PathBuf::from(explicit.as_deref().unwrap_or(DEFAULT_PATH));
+
+    let mut config = match std::fs::read_to_string(&path) {
+        Ok(text) => toml::from_str(&text).map_err(|e| ConfigError::Parse(path.clone(), e))?,
+        Err(e) if explicit.is_some() || e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(ConfigError::Io(path, e));
+        }
+        Err(_) => Config::default(),
+    };
+
+    if let Some(bind) = arg_value(args, "--bind").or(std::env::var("GACHA_BIND").ok()) {
+        config.network.bind = bind;
+    }
+    if let Some(db) = arg_value(args, "--db").or(std::env::var("GACHA_DB").ok()) {
+        config.storage.db_path = db.into();
+    }
+
+    config.validate()?;
+    Ok(config)
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub(crate) fn init(config: Config) -> &'static Config {
+    CONFIG.get_or_init(|| config)
+}
+pub(crate) fn economy() -> &'static Economy {
+    &CONFIG.get_or_init(Config::default).economy
+}
+pub(crate) fn pity() -> &'static Pity {
+    &CONFIG.get_or_init(Config::default).pity
+}
+pub(crate) fn banners() -> &'static [Banner] {
+    &CONFIG.get_or_init(Config::default).banners
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The field a config is refused for, None when it passes.
+    fn refused(text: &str) -> Option<&'static str> {
+        let config: Config = toml::from_str(text).unwrap();
+        match config.validate() {
+            Err(ConfigError::Invalid(field, _)) => Some(field),
+            Err(e) => panic!("{}", e),
+            Ok(()) => None,
+        }
+    }
+
+    #[test]
+    fn the_defaults_and_the_example_pass() {
+        assert!(Config::default().validate().is_ok());
+        assert_eq!(refused(include_str!("../../gacha.example.toml")), None);
+    }
+
+    // The one entry of a draw, with its weight and what it is out of.
+    fn single(draw: &LootDraw) -> (u32, u32, &Loot) {
+        assert_eq!(draw.entries.len(), 1);
+        let entry = &draw.entries[0];
+        (entry.weight, draw.nothing + entry.weight, &entry.loot)
+    }
+
+    // `apply_outcome` rolled a u8 for its chances, so they are out of 256.
+    #[test]
+    fn default_loot_pays_what_apply_outcome_paid() {
+        let loot = LootTables::default();
+        let [mythic] = &loot.mythic[..] else {
+            panic!("{:?}", loot.mythic);
+        };
+        assert!(matches!(single(mythic), (1, 1, Loot::Flux(2400))));
+        let [s] = &loot.s[..] else {
+            panic!("{:?}", loot.s);
+        };
+        assert!(matches!(single(s), (1, 1, Loot::Flux(240))));
+
+        let [a_flux, a_break] = &loot.a[..] else {
+            panic!("{:?}", loot.a);
+        };
+        assert!(matches!(single(a_flux), (1, 1, Loot::Flux(70))));
+        let (200, 256, Loot::Voucher(prize)) = single(a_break) else {
+            panic!("{:?}", a_break);
+        };
+        let user = crate::User::new(crate::UserId("tester".into()), "tester".into(), None);
+        let voucher = prize.voucher(&user).unwrap();
+        let paid_before = crate::Voucher::new(
+            0,
+            voucher.uuid,
+            "15 Minute Break".into(),
+            (crate::Coeff::pure_c().get_val() as f64 * 0.25) as u64,
+            0.0,
+            true,
+            "Take a 15 minute breather break".into(),
+            crate::Coeff::pure_c(),
+        );
+        assert_eq!(voucher, paid_before);
+
+        let [b_flux, b_day] = &loot.b[..] else {
+            panic!("{:?}", loot.b);
+        };
+        assert!(matches!(single(b_flux), (1, 1, Loot::Flux(4))));
+        assert!(matches!(
+            single(b_day),
+            (1, 256, Loot::Voucher(Prize::Template(1)))
+        ));
+
+        let example: Config = toml::from_str(include_str!("../../gacha.example.toml")).unwrap();
+        assert_eq!(format!("{:?}", example.economy.loot), format!("{:?}", loot));
+    }
+
+    #[test]
+    fn out_of_range_values_name_their_field() {
+        let cases = [
+            ("[network]\nbind = \"nowhere\"", "network.bind"),
+            ("[economy]\nastrum_per_pull = 0", "economy.astrum_per_pull"),
+            (
+                "[[economy.bars]]\nc = 1.0\nsmax = 60.0\ntmax = 60.0",
+                "economy.bars",
+            ),
+            ("[economy.expiry.pull]\nrefund = 1.5", "economy.expiry"),
+            (
+                "[[economy.pricing.bulk]]\nmin_amount = 1\ndiscount = 0.1",
+                "economy.pricing.bulk",
+            ),
+            (
+                "[[economy.stock]]\nid = 4\n[[economy.stock]]\nid = 4",
+                "economy.stock.id",
+            ),
+            (
+                "[pity.curves]\nmythic = { base = 0.01, hard = 0 }\n\
+                s = { base = 0.05 }\na = { base = 0.2 }",
+                "pity.curves.hard",
+            ),
+            ("[[banners]]\nid = \"standard\"\nname = \"x\"", "banners.id"),
+            (
+                "[[banners]]\nid = \"late\"\nname = \"x\"\n\
+                starts = \"2026-02-01T00:00:00Z\"\nends = \"2026-01-01T00:00:00Z\"",
+                "banners.ends",
+            ),
+        ];
+        for (text, field) in cases {
+            assert_eq!(refused(text), Some(field), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::time::Duration;
+
+use crate::Bar;
+
+const TIMER_TICK: Duration = Duration::from_secs(5);
+const IDLE_TICK: Duration = Duration::from_secs(60);
+// Bar minutes one timer tick adds.
+const TIMER_STEP: f64 = 15.0;
+const IDLE_BAR: u8 = 5;
+const LEISURE_BAR: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BarMode {
+    Timer,
+    Idle,
+}
+impl BarMode {
+    pub(crate) fn tick(&self) -> Duration {
+        match self {
+            BarMode::Timer => TIMER_TICK,
+            BarMode::Idle => IDLE_TICK,
+        }
+    }
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BarMode::Timer => "Timer",
+            BarMode::Idle => "Idle",
+        }
+    }
+    pub(crate) fn from_str(mode: &str) -> Option<Self> {
+        match mode {
+            "Timer" => Some(BarMode::Timer),
+            "Idle" => Some(BarMode::Idle),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BarEvent {
+    Overdrive(u8),
+    Locked(u8),
+    Unlocked(u8),
+    // The idle bar locked up and leisure was started in its place.
+    IdleEat,
+}
+
+// The bar rules without any clock, locks or tasks. `running` is the mode and
+// bar being driven, None once nothing runs. Whatever drives the engine (the
+// session task, or the catch-up after a restart) only decides how much time
+// has passed.
+#[derive(Clone)]
+pub(crate) struct BarEngine {
+    pub(crate) bars: Vec<Bar>,
+    pub(crate) running: Option<(BarMode, u8)>,
+}
+impl BarEngine {
+    pub(crate) fn new(bars: Vec<Bar>, running: Option<(BarMode, u8)>) -> Self {
+        Self { bars, running }
+    }
+
+    // Runs every whole tick that fits in `elapsed`, following the hand-offs
+    // between modes as they come up. A remainder shorter than a tick is dropped.
+    pub(crate) fn advance(mut self, elapsed: Duration) -> (Self, Vec<BarEvent>) {
+        let mut events = Vec::new();
+        let mut left = elapsed;
+
+        self.settle(&mut events);
+        while let Some((mode, id)) = self.running {
+            if left < mode.tick() {
+                break;
+            }
+            left -= mode.tick();
+
+            let before = self.bars.clone();
+            let eaten = match mode {
+                BarMode::Timer => {
+                    timer_tick(&mut self.bars, id);
+                    false
+                }
+                BarMode::Idle => idle_tick(&mut self.bars, id),
+            };
+            diff(&before, &self.bars, &mut events);
+            if eaten {
+                events.push(BarEvent::IdleEat);
+                self.running = Some((BarMode::Timer, LEISURE_BAR));
+            }
+            self.settle(&mut events);
+        }
+        (self, events)
+    }
+
+    // The steps that take no time: a timer picking its bar up, and a full bar
+    // handing over to idle. A bar that is already timing has been picked up.
+    fn settle(&mut self, events: &mut Vec<BarEvent>) {
+        let before = self.bars.clone();
+        loop {
+            match self.running {
+                Some((BarMode::Timer, id)) => {
+                    if !self.bars[id as usize].is_timing && !timer_enter(&mut self.bars, id) {
+                        self.running = None;
+                    } else if timer_full(&mut self.bars, id) {
+                        self.running = Some((BarMode::Idle, IDLE_BAR));
+                        continue;
+                    }
+                }
+                Some((BarMode::Idle, id)) => {
+                    self.bars[id as usize].is_timing = true;
+                }
+                None => (),
+            }
+            break;
+        }
+        diff(&before, &self.bars, events);
+    }
+}
+
+fn diff(before: &[Bar], after: &[Bar], events: &mut Vec<BarEvent>) {
+    for (old, new) in before.iter().zip(after.iter()) {
+        if !old.overdrive && new.overdrive {
+            events.push(BarEvent::Overdrive(new.id));
+        }
+        if !old.locked && new.locked {
+            events.push(BarEvent::Locked(new.id));
+        }
+        if old.locked && !new.locked {
+            events.push(BarEvent::Unlocked(new.id));
+        }
+    }
+}
+
+// False if the bar is locked and the timer can't pick it up.
+fn timer_enter(bars: &mut [Bar], id: u8) -> bool {
+    if bars.iter().filter(|f| !f.locked).count() == 1 {
+        bars.iter_mut().filter(|f| !f.locked).for_each(|bar| {
+            bar.reduce_s(bar.overdrive_val - bar.s_reduction, 1.0);
+        });
+    }
+
+    let bar = &mut bars[id as usize];
+
+    if bar.locked {
+        return false;
+    }
+    if bar.tbase > bar.smax && !bar.overdrive {
+        bar.overdrive = true;
+    }
+
+    bar.is_timing = true;
+    true
+}
+
+fn timer_full(bars: &mut [Bar], id: u8) -> bool {
+    let bar = &mut bars[id as usize];
+    if bar.s >= bar.smax {
+        if bar.overdrive && bar.s_reduction >= bar.overdrive_val {
+            bar.locked = true;
+            bar.tbase = bar.tmax;
+        }
+        bar.s = bar.smax;
+        bar.smax = Bar::by_id(id).smax;
+        bar.is_timing = false;
+        return true;
+    }
+    false
+}
+
+fn timer_tick(bars: &mut [Bar], id: u8) {
+    let time = TIMER_STEP;
+    let bar = &mut bars[id as usize];
+    let c = bar.c;
+    let smax = if bar.overdrive {
+        bar.smax + bar.overdrive_val
+    } else {
+        bar.smax
+    };
+
+    bar.s = smax.min(bar.s + time);
+    bar.tbase = (smax + 1.0).min(bar.tbase + time);
+
+    bars.iter_mut().for_each(|bar_f| {
+        if bar_f.id != id {
+            bar_f.reduce_tbase(time, c);
+            bar_f.reduce_s(time, c);
+        }
+
+        if bar_f.id == id && bar_f.tbase > bar_f.smax {
+            bar_f.overdrive = true;
+        }
+    });
+}
+
+// True once the idle bar locks up.
+fn idle_tick(bars: &mut [Bar], id: u8) -> bool {
+    let bar = &mut bars[id as usize];
+    bar.s += 1.0;
+
+    if bar.s >= bar.smax {
+        if !bar.overdrive {
+            bar.overdrive = true;
+            bar.s -= bar.overdrive_val
+        } else {
+            bar.locked = true;
+            bar.is_timing = false;
+            return true;
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh() -> Vec<Bar> {
+        (0..6).map(Bar::by_id).collect()
+    }
+    fn ticks(mode: BarMode, n: u32) -> Duration {
+        mode.tick() * n
+    }
+
+    #[test]
+    fn timer_fills_its_bar_and_drains_the_others() {
+        let mut bars = fresh();
+        bars[1].s = 60.0;
+        bars[1].s_reduction = 0.0;
+
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Timer, 0))).advance(ticks(BarMode::Timer, 2));
+
+        assert_eq!(engine.running, Some((BarMode::Timer, 0)));
+        assert!(engine.bars[0].is_timing);
+        assert_eq!(engine.bars[0].s, 30.0);
+        // Bar 0 has c = 1.5, so each tick takes 22.5 off the other bars.
+        assert_eq!(engine.bars[1].s, 15.0);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn partial_ticks_are_dropped() {
+        let (engine, _) = BarEngine::new(fresh(), Some((BarMode::Timer, 2)))
+            .advance(TIMER_TICK * 3 - Duration::from_millis(1));
+        assert_eq!(engine.bars[2].s, 30.0);
+    }
+
+    #[test]
+    fn full_bar_hands_over_to_idle() {
+        let mut bars = fresh();
+        bars[2].s = 80.0;
+
+        let (engine, _) =
+            BarEngine::new(bars, Some((BarMode::Timer, 2))).advance(ticks(BarMode::Timer, 1));
+
+        assert_eq!(engine.running, Some((BarMode::Idle, IDLE_BAR)));
+        assert_eq!(engine.bars[2].s, engine.bars[2].smax);
+        assert!(!engine.bars[2].is_timing);
+        assert!(engine.bars[IDLE_BAR as usize].is_timing);
+    }
+
+    #[test]
+    fn timer_enters_overdrive_past_smax() {
+        let mut bars = fresh();
+        bars[0].tbase = 230.0;
+
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Timer, 0))).advance(ticks(BarMode::Timer, 1));
+
+        assert!(engine.bars[0].overdrive);
+        assert_eq!(events, vec![BarEvent::Overdrive(0)]);
+    }
+
+    #[test]
+    fn overdriven_bar_locks_when_full() {
+        let mut bars = fresh();
+        let bar = &mut bars[0];
+        bar.overdrive = true;
+        bar.is_timing = true;
+        bar.s = bar.smax;
+        bar.s_reduction = bar.overdrive_val;
+
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Timer, 0))).advance(Duration::ZERO);
+
+        assert!(engine.bars[0].locked);
+        assert_eq!(engine.bars[0].tbase, engine.bars[0].tmax);
+        assert_eq!(events, vec![BarEvent::Locked(0)]);
+        assert_eq!(engine.running, Some((BarMode::Idle, IDLE_BAR)));
+    }
+
+    #[test]
+    fn locked_bar_unlocks_once_drained() {
+        let mut bars = fresh();
+        bars[1].locked = true;
+        bars[1].tbase = 20.0;
+
+        // Bar 0 drains others by 15 * 1.5 per tick, enough in one tick.
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Timer, 0))).advance(ticks(BarMode::Timer, 1));
+
+        assert!(!engine.bars[1].locked);
+        assert_eq!(engine.bars[1].tbase, 0.0);
+        assert_eq!(events, vec![BarEvent::Unlocked(1)]);
+    }
+
+    #[test]
+    fn locked_bar_cannot_be_timed() {
+        let mut bars = fresh();
+        bars[4].locked = true;
+
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Timer, 4))).advance(ticks(BarMode::Timer, 3));
+
+        assert_eq!(engine.running, None);
+        assert!(!engine.bars[4].is_timing);
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn idle_overdrives_then_eats_into_leisure() {
+        let mut bars = fresh();
+        let idle = &mut bars[IDLE_BAR as usize];
+        idle.s = idle.smax - 1.0;
+
+        let (engine, events) =
+            BarEngine::new(bars, Some((BarMode::Idle, IDLE_BAR))).advance(ticks(BarMode::Idle, 1));
+        assert_eq!(events, vec![BarEvent::Overdrive(IDLE_BAR)]);
+        assert_eq!(engine.running, Some((BarMode::Idle, IDLE_BAR)));
+
+        let left = engine.bars[IDLE_BAR as usize].smax - engine.bars[IDLE_BAR as usize].s;
+        let (engine, events) = engine.advance(ticks(BarMode::Idle, left.ceil() as u32));
+
+        assert_eq!(events, vec![BarEvent::Locked(IDLE_BAR), BarEvent::IdleEat]);
+        assert!(engine.bars[IDLE_BAR as usize].locked);
+        assert_eq!(engine.running, Some((BarMode::Timer, LEISURE_BAR)));
+        assert!(engine.bars[LEISURE_BAR as usize].is_timing);
+    }
+
+    #[test]
+    fn advancing_in_steps_matches_one_advance() {
+        let mut bars = fresh();
+        bars[3].s = 100.0;
+        let start = BarEngine::new(bars, Some((BarMode::Timer, 1)));
+
+        let (whole, _) = start.clone().advance(ticks(BarMode::Timer, 6));
+        let stepped = (0..6).fold(start, |engine, _| engine.advance(TIMER_TICK).0);
+
+        assert_eq!(whole.running, stepped.running);
+        assert!(whole.bars == stepped.bars);
+    }
+}
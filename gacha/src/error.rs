@@ -0,0 +1,194 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use serde_json::{Value, json};
+
+use crate::ledger::Overdraft;
+use crate::{BarError, PersistenceError};
+
+// Every route fails with one of these. The body always has the same shape:
+// {"code": "insufficient_funds", "message": "insufficient flux: need 360, have 120", "details": {...}}
+#[derive(thiserror::Error, Debug)]
+pub(crate) enum ApiError {
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+    #[error("{0} not found")]
+    NotFound(&'static str),
+    #[error("{0} already exists")]
+    Conflict(&'static str),
+    #[error("invalid request: {0}")]
+    Invalid(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("too many attempts, try again after {0}")]
+    Throttled(DateTime<Utc>),
+    #[error("insufficient {currency}: need {need}, have {have}")]
+    Insufficient {
+        currency: &'static str,
+        need: i128,
+        have: i128,
+    },
+    #[error(transparent)]
+    Bar(#[from] BarError),
+    #[error("storage error: {0}")]
+    Persistence(PersistenceError),
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::Invalid(_) => StatusCode::BAD_REQUEST,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Throttled(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Insufficient { .. } => StatusCode::PAYMENT_REQUIRED,
+            ApiError::Bar(_) => StatusCode::CONFLICT,
+            ApiError::Persistence(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Invalid(_) => "invalid_request",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::Throttled(_) => "too_many_attempts",
+            ApiError::Insufficient { .. } => "insufficient_funds",
+            ApiError::Bar(BarError::NoBar) => "bar_unavailable",
+            ApiError::Bar(BarError::Full { .. }) => "bar_full",
+            ApiError::Bar(BarError::Locked(_)) => "bar_locked",
+            ApiError::Bar(BarError::Busy) => "bar_busy",
+            ApiError::Persistence(_) => "storage_error",
+        }
+    }
+    fn details(&self) -> Value {
+        match self {
+            ApiError::NotFound(what) | ApiError::Conflict(what) => json!({ "resource": what }),
+            ApiError::Insufficient {
+                currency,
+                need,
+                have,
+            } => json!({ "currency": currency, "need": need, "have": have }),
+            ApiError::Bar(BarError::Full { bar, need, free }) => {
+                json!({ "bar": bar, "need": need, "free": free })
+            }
+            ApiError::Bar(BarError::Locked(bar)) => json!({ "bar": bar }),
+            ApiError::Throttled(until) => json!({ "retry_at": until }),
+            _ => Value::Null,
+        }
+    }
+}
+
+impl From<PersistenceError> for ApiError {
+    fn from(e: PersistenceError) -> Self {
+        match e {
+            PersistenceError::NotFound => ApiError::NotFound("user"),
+            e => ApiError::Persistence(e),
+        }
+    }
+}
+impl From<Overdraft> for ApiError {
+    fn from(e: Overdraft) -> Self {
+        ApiError::Insufficient {
+            currency: e.currency.name(),
+            need: e.need as i128,
+            have: e.have as i128,
+        }
+    }
+}
+impl From<rusqlite::Error> for ApiError {
+    fn from(e: rusqlite::Error) -> Self {
+        ApiError::Persistence(e.into())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let ApiError::Persistence(e) = &self {
+            println!("Storage error: {}", e);
+        }
+        let body = json!({
+            "code": self.code(),
+            "message": self.to_string(),
+            "details": self.details(),
+        });
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::{Currency, LedgerSource};
+    use crate::{User, UserId};
+
+    async fn rendered(e: ApiError) -> (StatusCode, Value) {
+        let response = e.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn bodies_share_one_shape() {
+        let (status, body) = rendered(ApiError::NotFound("voucher")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            body,
+            json!({
+                "code": "not_found",
+                "message": "voucher not found",
+                "details": { "resource": "voucher" },
+            })
+        );
+
+        let (status, body) = rendered(ApiError::Invalid("amount must be at least 1".into())).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["code"], "invalid_request");
+        assert_eq!(body["details"], Value::Null);
+
+        let full = BarError::Full {
+            bar: 2,
+            need: 90.0,
+            free: 30.0,
+        };
+        let (status, body) = rendered(full.into()).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body["code"], "bar_full");
+        assert_eq!(
+            body["details"],
+            json!({ "bar": 2, "need": 90.0, "free": 30.0 })
+        );
+    }
+
+    #[tokio::test]
+    async fn conversions_keep_their_meaning() {
+        let mut user = User::new(UserId("tester".into()), "tester".into(), None);
+        let overdraft = user
+            .adjust(Currency::Flux, -360, LedgerSource::Purchase, None)
+            .unwrap_err();
+        let (status, body) = rendered(overdraft.into()).await;
+        assert_eq!(status, StatusCode::PAYMENT_REQUIRED);
+        assert_eq!(body["code"], "insufficient_funds");
+        assert_eq!(
+            body["details"],
+            json!({ "currency": "flux", "need": 360, "have": 0 })
+        );
+
+        let (status, body) = rendered(PersistenceError::NotFound.into()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["details"]["resource"], "user");
+
+        let until = Utc::now();
+        let (status, body) = rendered(ApiError::Throttled(until)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(body["code"], "too_many_attempts");
+        assert_eq!(body["details"]["retry_at"], json!(until));
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+
+use axum::extract::{Query, State};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use chrono::{DateTime, Duration, Utc};
+use futures_util::stream::{self, Stream};
+use rand::{Rng, rng};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::engine::BarEvent;
+use crate::{AppState, Bar, SerializedRarity, UserId};
+
+// Slow clients past this many of their account's events catch up from the
+// backlog, or get a `lagged` event and should refetch.
+const CHANNEL_CAPACITY: usize = 256;
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    BarTick {
+        bars: Vec<Bar>,
+    },
+    Overdrive {
+        bar: u8,
+    },
+    BarLocked {
+        bar: u8,
+    },
+    BarUnlocked {
+        bar: u8,
+    },
+    IdleEat,
+    VoucherConsumed {
+        uuid: Uuid,
+        name: String,
+    },
+    // What was left of a consumed voucher, handed back as a new one.
+    RemainderRefunded {
+        uuid: Uuid,
+        name: String,
+        minutes: f64,
+    },
+    // Swept out unused, `refund` flux was handed back for it.
+    VoucherExpired {
+        uuid: Uuid,
+        name: String,
+        refund: u64,
+    },
+    // Capped templates that got stock back at the daily reset.
+    Restocked {
+        templates: Vec<u64>,
+    },
+    Pull {
+        banner: String,
+        results: Vec<SerializedRarity>,
+        vouchers: u16,
+    },
+    Wallet {
+        astrum: u64,
+        astrai: u64,
+        flux: i128,
+    },
+    // Events were lost, `missed` of them or 0 when that isn't known. The
+    // client should refetch what it shows.
+    Lagged {
+        missed: u64,
+    },
+}
+impl From<BarEvent> for Event {
+    fn from(event: BarEvent) -> Self {
+        match event {
+            BarEvent::Overdrive(bar) => Event::Overdrive { bar },
+            BarEvent::Locked(bar) => Event::BarLocked { bar },
+            BarEvent::Unlocked(bar) => Event::BarUnlocked { bar },
+            BarEvent::IdleEat => Event::IdleEat,
+        }
+    }
+}
+
+// Events kept per account for clients that reconnect, see `subscribe`.
+const BACKLOG: usize = 512;
+// How long the backlog of an account nobody listens to is kept, which is how
+// long a client has to reconnect and resume.
+const FEED_IDLE_MINUTES: i64 = 10;
+
+#[derive(Clone)]
+pub(crate) struct Published {
+    pub(crate) seq: u64,
+    pub(crate) event: Event,
+}
+
+// One account's channel and backlog. The epoch is random per feed, so tokens
+// from a feed that was dropped, or from an earlier run of the daemon, never
+// resume on a new one.
+struct Feed {
+    tx: broadcast::Sender<Published>,
+    epoch: u64,
+    seq: u64,
+    events: VecDeque<Published>,
+    active: DateTime<Utc>,
+}
+impl Feed {
+    fn new() -> Self {
+        Self {
+            tx: broadcast::channel(CHANNEL_CAPACITY).0,
+            epoch: rng().random(),
+            seq: 0,
+            events: VecDeque::new(),
+            active: Utc::now(),
+        }
+    }
+    // The events after the one `token` names, None if that isn't possible.
+    fn since(&self, token: &str) -> Option<Vec<Published>> {
+        let (epoch, after) = token.split_once('.')?;
+        let after: u64 = after.parse().ok()?;
+        if u64::from_str_radix(epoch, 16).ok()? != self.epoch {
+            return None;
+        }
+        let kept_from = self.events.front().map_or(self.seq + 1, |first| first.seq);
+        if after > self.seq || after + 1 < kept_from {
+            return None;
+        }
+        Some(
+            self.events
+                .iter()
+                .filter(|p| p.seq > after)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+pub(crate) enum Received {
+    Event(Published),
+    // Fell too far behind to catch up, the client has to refetch.
+    Lost(u64),
+    Closed,
+}
+
+pub(crate) struct Subscription {
+    events: Events,
+    user: UserId,
+    rx: broadcast::Receiver<Published>,
+    epoch: u64,
+    // Replayed before anything live.
+    pending: VecDeque<Published>,
+    // Whether the resume token could be honoured.
+    pub(crate) resumed: bool,
+    pub(crate) last: u64,
+}
+impl Subscription {
+    pub(crate) fn token(&self, seq: u64) -> String {
+        format!("{:x}.{}", self.epoch, seq)
+    }
+    // The next event, missed ones first. Falling behind the channel catches
+    // up from the backlog like a reconnect would.
+    pub(crate) async fn recv(&mut self) -> Received {
+        loop {
+            if let Some(published) = self.pending.pop_front() {
+                self.last = published.seq;
+                return Received::Event(published);
+            }
+            match self.rx.recv().await {
+                Ok(published) if published.seq > self.last => {
+                    self.last = published.seq;
+                    return Received::Event(published);
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(missed)) => {
+                    let token = self.token(self.last);
+                    *self = self.events.subscribe(&self.user, Some(&token));
+                    if !self.resumed {
+                        return Received::Lost(missed);
+                    }
+                }
+                Err(RecvError::Closed) => return Received::Closed,
+            }
+        }
+    }
+}
+
+// Every account has its own channel, so a busy account can't push another
+// one's subscribers behind. Every event gets a per account sequence number
+// and the last few are kept, so a client can pick up where it left off with
+// a resume token.
+#[derive(Clone)]
+pub(crate) struct Events {
+    feeds: Arc<Mutex<HashMap<UserId, Feed>>>,
+}
+impl Events {
+    pub(crate) fn new() -> Self {
+        Self {
+            feeds: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+    pub(crate) fn publish(&self, user: &UserId, event: Event) {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(user.clone()).or_insert_with(Feed::new);
+        feed.seq += 1;
+        feed.active = Utc::now();
+        let published = Published {
+            seq: feed.seq,
+            event,
+        };
+        if feed.events.len() == BACKLOG {
+            feed.events.pop_front();
+        }
+        feed.events.push_back(published.clone());
+        let _ = feed.tx.send(published);
+    }
+
+    // Subscribing and reading the backlog happen under the same lock, so no
+    // event falls between the replay and the live stream.
+    pub(crate) fn subscribe(&self, user: &UserId, resume: Option<&str>) -> Subscription {
+        let mut feeds = self.feeds.lock().unwrap();
+        let feed = feeds.entry(user.clone()).or_insert_with(Feed::new);
+        feed.active = Utc::now();
+        let missed = resume.and_then(|token| feed.since(token));
+        Subscription {
+            events: self.clone(),
+            user: user.clone(),
+            rx: feed.tx.subscribe(),
+            epoch: feed.epoch,
+            resumed: missed.is_some(),
+            pending: missed.unwrap_or_default().into(),
+            last: feed.seq,
+        }
+    }
+
+    // Drops the feeds of accounts nobody has listened to or published for
+    // a while, backlog included.
+    pub(crate) fn drop_idle(&self) {
+        let idle = Utc::now() - Duration::minutes(FEED_IDLE_MINUTES);
+        self.feeds
+            .lock()
+            .unwrap()
+            .retain(|_, feed| feed.tx.receiver_count() > 0 || feed.active > idle);
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EventsParams {
+    resume: Option<String>,
+}
+
+// Every event carries its resume token as the SSE id, which EventSource
+// sends back as `Last-Event-ID` when it reconnects. Clients that reconnect
+// by hand can pass it as `resume`, like on /ws.
+pub(crate) async fn events(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    Query(params): Query<EventsParams>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let resume = headers
+        .get("last-event-id")
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .or(params.resume);
+    let sub = state.events.subscribe(&id, resume.as_deref());
+    let lost = resume.is_some() && !sub.resumed;
+
+    let stream = stream::unfold((sub, lost), |(mut sub, lost)| async move {
+        let sse = |event: &Event| {
+            SseEvent::default()
+                .json_data(event)
+                .unwrap_or_else(|_| SseEvent::default().comment("unserializable event"))
+        };
+        if lost {
+            let lagged = sse(&Event::Lagged { missed: 0 });
+            return Some((Ok(lagged), (sub, false)));
+        }
+        let event = match sub.recv().await {
+            Received::Event(published) => sse(&published.event).id(sub.token(published.seq)),
+            Received::Lost(missed) => sse(&Event::Lagged { missed }),
+            Received::Closed => return None,
+        };
+        Some((Ok(event), (sub, false)))
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::IntoResponse;
+    use futures_util::StreamExt;
+
+    fn account(name: &str) -> UserId {
+        UserId(name.into())
+    }
+    // Bars stand in for the payload, so tests can tell events apart.
+    fn overdrive(bar: u8) -> Event {
+        Event::Overdrive { bar }
+    }
+    async fn next(sub: &mut Subscription) -> (u64, u8) {
+        match sub.recv().await {
+            Received::Event(Published {
+                seq,
+                event: Event::Overdrive { bar },
+            }) => (seq, bar),
+            Received::Event(_) => panic!("unexpected event"),
+            Received::Lost(missed) => panic!("lost {} events", missed),
+            Received::Closed => panic!("closed"),
+        }
+    }
+
+    #[tokio::test]
+    async fn accounts_only_see_their_own_events() {
+        let events = Events::new();
+        let (alice, bob) = (account("alice"), account("bob"));
+        let mut alices = events.subscribe(&alice, None);
+        let mut bobs = events.subscribe(&bob, None);
+
+        events.publish(&alice, overdrive(1));
+        events.publish(&bob, overdrive(2));
+        events.publish(&alice, overdrive(3));
+
+        assert_eq!(next(&mut alices).await, (1, 1));
+        assert_eq!(next(&mut alices).await, (2, 3));
+        assert_eq!(next(&mut bobs).await, (1, 2));
+        assert!(alices.rx.is_empty() && bobs.rx.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resuming_replays_what_was_missed() {
+        let events = Events::new();
+        let user = account("tester");
+        let first = events.subscribe(&user, None);
+        for bar in 1..=3 {
+            events.publish(&user, overdrive(bar));
+        }
+
+        let mut resumed = events.subscribe(&user, Some(&first.token(1)));
+        assert!(resumed.resumed);
+        assert_eq!(next(&mut resumed).await, (2, 2));
+        assert_eq!(next(&mut resumed).await, (3, 3));
+        events.publish(&user, overdrive(4));
+        assert_eq!(next(&mut resumed).await, (4, 4));
+
+        // Tokens from another feed, from the future or past the backlog
+        // can't be honoured.
+        let elsewhere = events.subscribe(&account("other"), None).token(1);
+        for token in [elsewhere, first.token(9), "zz.1".into(), "garbage".into()] {
+            assert!(!events.subscribe(&user, Some(&token)).resumed);
+        }
+        for bar in 0..BACKLOG {
+            events.publish(&user, overdrive(bar as u8));
+        }
+        assert!(!events.subscribe(&user, Some(&first.token(1))).resumed);
+        assert!(events.subscribe(&user, Some(&first.token(5))).resumed);
+    }
+
+    #[tokio::test]
+    async fn last_event_id_resumes_the_stream() {
+        let state = AppState::in_memory();
+        let user = account("tester");
+        let sub = state.events.subscribe(&user, None);
+        let (first, second) = (sub.token(1), sub.token(2));
+        for bar in 1..=3 {
+            state.events.publish(&user, overdrive(bar));
+        }
+        let read = async |resume: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert("last-event-id", resume.parse().unwrap());
+            let params = EventsParams { resume: None };
+            let sse = events(
+                State(state.clone()),
+                AuthUser(user.clone()),
+                Query(params),
+                headers,
+            );
+            let mut body = sse.await.into_response().into_body().into_data_stream();
+            let frame = body.next().await.unwrap().unwrap();
+            String::from_utf8(frame.to_vec()).unwrap()
+        };
+
+        let frame = read(&first).await;
+        assert!(frame.contains(r#""type":"overdrive","bar":2"#));
+        assert!(frame.contains(&format!("id: {}", second)));
+
+        // A token that can't be honoured tells the client to refetch.
+        let frame = read("0.1").await;
+        assert!(frame.contains(r#""type":"lagged","missed":0"#));
+    }
+
+    #[tokio::test]
+    async fn falling_behind_catches_up_from_the_backlog() {
+        let events = Events::new();
+        let user = account("tester");
+        let mut sub = events.subscribe(&user, None);
+        let published = CHANNEL_CAPACITY + 10;
+        for n in 1..=published {
+            events.publish(&user, overdrive(n as u8));
+        }
+        for n in 1..=published {
+            assert_eq!(next(&mut sub).await, (n as u64, n as u8));
+        }
+    }
+
+    #[tokio::test]
+    async fn falling_past_the_backlog_loses_events() {
+        let events = Events::new();
+        let user = account("tester");
+        let mut sub = events.subscribe(&user, None);
+        for bar in 0..BACKLOG + 10 {
+            events.publish(&user, overdrive(bar as u8));
+        }
+        assert!(matches!(sub.recv().await, Received::Lost(missed) if missed > 0));
+
+        // Carries on live from there.
+        events.publish(&user, overdrive(7));
+        assert_eq!(next(&mut sub).await, (BACKLOG as u64 + 11, 7));
+    }
+
+    #[tokio::test]
+    async fn only_feeds_nobody_uses_are_dropped() {
+        let events = Events::new();
+        let (listened, recent, idle) = (account("listened"), account("recent"), account("idle"));
+        let _sub = events.subscribe(&listened, None);
+        for user in [&listened, &recent, &idle] {
+            events.publish(user, overdrive(1));
+        }
+        let resume = events.subscribe(&idle, None).token(1);
+        let long_ago = Utc::now() - Duration::minutes(FEED_IDLE_MINUTES + 1);
+        for user in [&listened, &idle] {
+            events.feeds.lock().unwrap().get_mut(user).unwrap().active = long_ago;
+        }
+
+        events.drop_idle();
+        let feeds = events.feeds.lock().unwrap();
+        assert!(feeds.contains_key(&listened) && feeds.contains_key(&recent));
+        assert!(!feeds.contains_key(&idle));
+        drop(feeds);
+        // The backlog went with it, the old token starts over.
+        assert!(!events.subscribe(&idle, Some(&resume)).resumed);
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+use crate::config::{self, Expiry, ExpiryRule};
+use crate::events::Event;
+use crate::ledger::{Currency, LedgerSource, Overdraft};
+use crate::pricing::StoreInfo;
+use crate::{AppState, PersistenceError, User, UserId, UserRepo, Voucher, repo, save_user};
+
+const SWEEP_EVERY: StdDuration = StdDuration::from_secs(60);
+
+// Templates share the columns, they just never get an expiry.
+const COLUMNS: &[(&str, &str)] = &[
+    ("acquired", "INTEGER"),
+    ("expires", "INTEGER"),
+    ("refund", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+// Vouchers already in the inventory count as acquired now and keep, like
+// every voucher did before expiry.
+pub(crate) fn add_columns(conn: &Connection) -> Result<(), PersistenceError> {
+    repo::add_voucher_columns(conn, COLUMNS)?;
+    conn.execute(
+        "UPDATE vouchers SET acquired = ?1 WHERE acquired IS NULL",
+        params![Utc::now().timestamp()],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS vouchers_expires ON vouchers (expires)",
+        [],
+    )?;
+    Ok(())
+}
+
+// Where a voucher came from, which picks its expiry rule.
+pub(crate) enum Source {
+    Pull,
+    Purchase,
+    Remainder,
+}
+
+fn rule(expiry: &Expiry, id: u64, source: Source) -> (Option<f64>, f64) {
+    if let Some(template) = expiry.templates.iter().find(|t| t.id == id) {
+        return (template.hours, template.refund);
+    }
+    let ExpiryRule { hours, refund } = match source {
+        Source::Pull => &expiry.pull,
+        Source::Purchase => &expiry.purchase,
+        Source::Remainder => &expiry.remainder,
+    };
+    (*hours, *refund)
+}
+
+impl Voucher {
+    // Called when a voucher lands in the inventory.
+    pub(crate) fn acquire(&mut self, source: Source) {
+        self.acquire_under(&config::economy().expiry, source, Utc::now());
+    }
+    fn acquire_under(&mut self, expiry: &Expiry, source: Source, now: DateTime<Utc>) {
+        let (hours, refund) = rule(expiry, self.id, source);
+        self.acquired = Some(now);
+        self.expires = hours.map(|h| now + Duration::seconds((h * 3600.0) as i64));
+        self.refund = (self.paid.unwrap_or(self.cost) as f64 * refund).round() as u64;
+    }
+    pub(crate) fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+impl User {
+    // Takes expired vouchers out of the inventory and pays their refunds.
+    pub(crate) fn expire_vouchers(
+        &mut self,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Voucher>, Overdraft> {
+        let (expired, kept) = std::mem::take(&mut self.vouchers)
+            .into_iter()
+            .partition(|v| v.expired(now));
+        self.vouchers = kept;
+        for voucher in expired.iter() {
+            self.adjust(
+                Currency::Flux,
+                voucher.refund as i64,
+                LedgerSource::Refund,
+                Some(voucher.uuid),
+            )?;
+        }
+        Ok(expired)
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct VoucherInfo {
+    #[serde(flatten)]
+    pub(crate) voucher: Voucher,
+    // Seconds left, None when it keeps.
+    expires_in: Option<i64>,
+    // Only in store listings.
+    #[serde(flatten)]
+    pub(crate) store: Option<StoreInfo>,
+}
+// Soonest to expire first, the rest in the order they came in. Anything the
+// sweeper hasn't got to yet is left out.
+pub(crate) fn listing(vouchers: Vec<Voucher>) -> Vec<VoucherInfo> {
+    let now = Utc::now();
+    let mut listed: Vec<VoucherInfo> = vouchers
+        .into_iter()
+        .filter(|v| !v.expired(now))
+        .map(|voucher| VoucherInfo {
+            expires_in: voucher.expires.map(|e| (e - now).num_seconds()),
+            voucher,
+            store: None,
+        })
+        .collect();
+    listed.sort_by_key(|info| info.expires_in.unwrap_or(i64::MAX));
+    listed
+}
+
+fn sweep(state: &AppState) -> Result<(), PersistenceError> {
+    let now = Utc::now();
+    let ids: Vec<String> = {
+        let conn = state.repo.db.lock().unwrap();
+        conn.prepare("SELECT DISTINCT user_id FROM vouchers WHERE expires <= ?1")?
+            .query_map(params![now.timestamp()], |row| row.get(0))?
+            .collect::<Result<_, _>>()?
+    };
+    for id in ids {
+        // Straight from the repo, a sweep shouldn't keep a session alive.
+        let (mut user, conn) = state.repo.load(UserId(id))?;
+        let expired = match user.expire_vouchers(now) {
+            Ok(expired) => expired,
+            Err(e) => {
+                println!("Expiry sweep skipped {}: {}", user.id.0, e);
+                continue;
+            }
+        };
+        if let Err(e) = save_user(&user, &conn, state) {
+            println!("Expiry sweep couldn't save {}: {}", user.id.0, e);
+            continue;
+        }
+        announce(state, &user.id, expired);
+    }
+    Ok(())
+}
+
+// Lets the account know, once the refunds are saved.
+pub(crate) fn announce(state: &AppState, user: &UserId, expired: Vec<Voucher>) {
+    for voucher in expired {
+        state.events.publish(
+            user,
+            Event::VoucherExpired {
+                uuid: voucher.uuid,
+                name: voucher.name,
+                refund: voucher.refund,
+            },
+        );
+    }
+}
+
+pub(crate) fn spawn_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_EVERY);
+        loop {
+            interval.tick().await;
+            if let Err(e) = sweep(&state) {
+                println!("Voucher sweep failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TemplateExpiry;
+
+    fn expiry() -> Expiry {
+        Expiry {
+            purchase: ExpiryRule {
+                hours: Some(24.0),
+                refund: 0.5,
+            },
+            templates: vec![TemplateExpiry {
+                id: 4,
+                hours: None,
+                refund: 0.9,
+            }],
+            ..Expiry::default()
+        }
+    }
+
+    #[test]
+    fn template_rules_win_over_source_rules() {
+        assert_eq!(rule(&expiry(), 4, Source::Purchase), (None, 0.9));
+        assert_eq!(rule(&expiry(), 1, Source::Purchase), (Some(24.0), 0.5));
+        assert_eq!(rule(&expiry(), 1, Source::Pull), (None, 0.0));
+    }
+
+    #[test]
+    fn refunds_are_a_share_of_the_cost() {
+        let now = Utc::now();
+        let mut coffee = Voucher::coffee();
+        coffee.acquire_under(&expiry(), Source::Purchase, now);
+        assert_eq!((coffee.expires, coffee.refund), (None, 135));
+
+        let mut day = Voucher::off_day();
+        day.acquire_under(&expiry(), Source::Purchase, now);
+        assert_eq!(day.expires, Some(now + Duration::hours(24)));
+        assert_eq!(day.refund, 2860);
+        assert!(!day.expired(now) && day.expired(now + Duration::hours(24)));
+
+        let mut bought = Voucher::off_day();
+        bought.paid = Some(6000);
+        bought.acquire_under(&expiry(), Source::Purchase, now);
+        assert_eq!(bought.refund, 3000);
+    }
+}
@@ -0,0 +1,196 @@
+use axum::Json;
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, Row, params};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthUser;
+use crate::error::ApiError;
+use crate::{
+    AppState, PersistenceError, PullRecord, SerializedRarity, SqliteRepo, User, UserId, load_user,
+};
+
+pub(crate) const PAGE_SIZE_MAX: u32 = 200;
+
+// The pulls queued on the account since it was loaded, written by its save.
+pub(crate) fn write_pulls(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO pull_history (user_id, pulled_at, rarity, sss_pity, s_pity, a_pity,
+        astrai_spent, astrum_spent, vouchers, slip_consumed, banner, items, rng_day,
+        rng_word_pos, floored)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+    )?;
+    for record in user.pulls.iter() {
+        stmt.execute(params![
+            user.id.0,
+            record.pulled_at.timestamp(),
+            record.rarity.as_str(),
+            record.sss_pity,
+            record.s_pity,
+            record.a_pity,
+            record.astrai_spent as i64,
+            record.astrum_spent as i64,
+            record.vouchers,
+            record.slip_consumed,
+            record.banner,
+            serde_json::to_string(&record.items)?,
+            record.rng_day,
+            record.rng_word_pos,
+            record.floored,
+        ])?;
+    }
+    Ok(())
+}
+
+impl SqliteRepo {
+    fn query_pulls(
+        conn: &Connection,
+        id: &UserId,
+        filter: &PullHistoryRequest,
+    ) -> Result<(Vec<PullRecord>, u64), PersistenceError> {
+        let rarity = filter.rarity.map(|r| r.as_str());
+        let banner = filter.banner.as_deref();
+        let from = filter.from.map(|f| f.timestamp());
+        let to = filter.to.map(|t| t.timestamp());
+        let per_page = filter.per_page.clamp(1, PAGE_SIZE_MAX);
+        let offset = filter.page as i64 * per_page as i64;
+
+        let filters = "user_id = ?1
+            AND (?2 IS NULL OR rarity = ?2)
+            AND (?3 IS NULL OR pulled_at >= ?3)
+            AND (?4 IS NULL OR pulled_at < ?4)
+            AND (?5 IS NULL OR banner = ?5)";
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM pull_history WHERE {}", filters),
+            params![id.0, rarity, from, to, banner],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM pull_history WHERE {} ORDER BY id DESC LIMIT ?6 OFFSET ?7",
+            RECORD_COLUMNS, filters
+        ))?;
+        let records = stmt
+            .query_map(
+                params![id.0, rarity, from, to, banner, per_page, offset],
+                read_record,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((records, total as u64))
+    }
+
+    // Oldest first, the order they were drawn from the day's stream in.
+    pub(crate) fn pulls_on_day(
+        conn: &Connection,
+        id: &UserId,
+        day: &str,
+    ) -> Result<Vec<PullRecord>, PersistenceError> {
+        let records = conn
+            .prepare(&format!(
+                "SELECT {} FROM pull_history WHERE user_id = ?1 AND rng_day = ?2 ORDER BY id",
+                RECORD_COLUMNS
+            ))?
+            .query_map(params![id.0, day], read_record)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    }
+
+    // How often each rarity dropped for the account, over every banner.
+    pub(crate) fn rarity_counts(
+        conn: &Connection,
+        id: &UserId,
+    ) -> Result<RarityCounts, PersistenceError> {
+        let mut counts = RarityCounts::default();
+        let rows = conn
+            .prepare(
+                "SELECT rarity, COUNT(*) FROM pull_history WHERE user_id = ?1 GROUP BY rarity",
+            )?
+            .query_map(params![id.0], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (rarity, n) in rows {
+            match SerializedRarity::from_str(&rarity) {
+                SerializedRarity::MythicSSS => counts.mythic = n,
+                SerializedRarity::S => counts.s = n,
+                SerializedRarity::A => counts.a = n,
+                SerializedRarity::B => counts.b = n,
+                SerializedRarity::NoTickets => (),
+            }
+        }
+        Ok(counts)
+    }
+}
+
+#[derive(Serialize, Default)]
+pub(crate) struct RarityCounts {
+    pub(crate) mythic: u64,
+    pub(crate) s: u64,
+    pub(crate) a: u64,
+    pub(crate) b: u64,
+}
+
+const RECORD_COLUMNS: &str = "pulled_at, rarity, sss_pity, s_pity, a_pity, astrai_spent,
+    astrum_spent, vouchers, slip_consumed, banner, items, rng_day, rng_word_pos, floored";
+
+fn read_record(row: &Row) -> rusqlite::Result<PullRecord> {
+    let rarity: String = row.get(1)?;
+    let items: String = row.get(10)?;
+    Ok(PullRecord {
+        pulled_at: DateTime::from_timestamp(row.get(0)?, 0).unwrap_or_default(),
+        banner: row.get(9)?,
+        rarity: SerializedRarity::from_str(&rarity),
+        sss_pity: row.get(2)?,
+        s_pity: row.get(3)?,
+        a_pity: row.get(4)?,
+        astrai_spent: row.get::<_, i64>(5)? as u64,
+        astrum_spent: row.get::<_, i64>(6)? as u64,
+        vouchers: row.get(7)?,
+        slip_consumed: row.get(8)?,
+        floored: row.get(13)?,
+        items: serde_json::from_str(&items).unwrap_or_default(),
+        rng_day: row.get(11)?,
+        rng_word_pos: row.get(12)?,
+    })
+}
+
+pub(crate) fn default_per_page() -> u32 {
+    50
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PullHistoryRequest {
+    #[serde(default)]
+    page: u32,
+    #[serde(default = "default_per_page")]
+    per_page: u32,
+    rarity: Option<SerializedRarity>,
+    banner: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+}
+#[derive(Serialize)]
+pub(crate) struct PullHistoryResponse {
+    entries: Vec<PullRecord>,
+    page: u32,
+    per_page: u32,
+    total: u64,
+}
+pub(crate) async fn pull_history(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<PullHistoryRequest>,
+) -> Result<Json<PullHistoryResponse>, ApiError> {
+    let (user, conn) = load_user(userid.clone(), &state)?;
+
+    let (entries, total) = SqliteRepo::query_pulls(&conn, &user.id, &req)?;
+
+    Ok(Json(PullHistoryResponse {
+        entries,
+        page: req.page,
+        per_page: req.per_page.clamp(1, PAGE_SIZE_MAX),
+        total,
+    }))
+}
@@ -0,0 +1,406 @@
+use axum::Json;
+use axum::extract::State;
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, params};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::auth::AuthUser;
+use crate::error::ApiError;
+use crate::history::{PAGE_SIZE_MAX, default_per_page};
+use crate::{AppState, PersistenceError, User, UserId, load_user};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum Currency {
+    Astrum,
+    Astrai,
+    Flux,
+}
+impl Currency {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Currency::Astrum => "astrum",
+            Currency::Astrai => "astrai",
+            Currency::Flux => "flux",
+        }
+    }
+}
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub(crate) enum LedgerSource {
+    Opening,
+    Pull,
+    PullReward,
+    Purchase,
+    Refund,
+    Daily,
+    IsrdoStake,
+    IsrdoPayout,
+    Bundle,
+}
+
+// A debit bigger than the balance it comes out of.
+#[derive(thiserror::Error, Debug)]
+#[error("insufficient {}: need {need}, have {have}", currency.name())]
+pub(crate) struct Overdraft {
+    pub(crate) currency: Currency,
+    pub(crate) need: i64,
+    pub(crate) have: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct LedgerEntry {
+    uuid: Uuid,
+    at: DateTime<Utc>,
+    currency: Currency,
+    amount: i64,
+    balance_after: i64,
+    source: LedgerSource,
+    related: Option<Uuid>,
+}
+
+impl User {
+    // Every balance change goes through here so the ledger can't miss one.
+    // Entries are queued on the user and written by the next save. A debit
+    // the balance can't cover is refused and changes nothing.
+    pub(crate) fn adjust(
+        &mut self,
+        currency: Currency,
+        amount: i64,
+        source: LedgerSource,
+        related: Option<Uuid>,
+    ) -> Result<(), Overdraft> {
+        if amount == 0 {
+            return Ok(());
+        }
+        let have = self.balance(currency);
+        if amount < 0 && have + amount < 0 {
+            return Err(Overdraft {
+                currency,
+                need: -amount,
+                have,
+            });
+        }
+        let balance_after = have + amount;
+        match currency {
+            Currency::Astrum => self.astrum = balance_after as u64,
+            Currency::Astrai => self.astrai = balance_after as u64,
+            Currency::Flux => self.flux = balance_after as i128,
+        }
+        self.ledger.push(LedgerEntry {
+            uuid: Uuid::now_v7(),
+            at: Utc::now(),
+            currency,
+            amount,
+            balance_after,
+            source,
+            related,
+        });
+        Ok(())
+    }
+    pub(crate) fn balance(&self, currency: Currency) -> i64 {
+        match currency {
+            Currency::Astrum => self.astrum as i64,
+            Currency::Astrai => self.astrai as i64,
+            Currency::Flux => self.flux as i64,
+        }
+    }
+}
+
+fn as_text<T: Serialize>(value: &T) -> String {
+    match serde_json::to_value(value) {
+        Ok(Value::String(text)) => text,
+        _ => String::new(),
+    }
+}
+fn from_text<T: DeserializeOwned>(text: String) -> Option<T> {
+    serde_json::from_value(Value::String(text)).ok()
+}
+
+// Saving the same user twice must not book its entries twice, hence the
+// INSERT OR IGNORE on the entry uuid.
+pub(crate) fn write_entries(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let mut stmt = conn.prepare(
+        "INSERT OR IGNORE INTO ledger (uuid, user_id, at, currency, amount, balance_after,
+        source, related) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    )?;
+    for entry in user.ledger.iter() {
+        stmt.execute(params![
+            entry.uuid.to_string(),
+            user.id.0,
+            entry.at.timestamp(),
+            as_text(&entry.currency),
+            entry.amount,
+            entry.balance_after,
+            as_text(&entry.source),
+            entry.related.map(|r| r.to_string()),
+        ])?;
+    }
+    Ok(())
+}
+
+// Migration step: books the balances users already had as an opening entry,
+// so the ledger sums match the wallets from day one.
+pub(crate) fn open_ledger(conn: &Connection) -> Result<(), PersistenceError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS ledger (
+            uuid TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL REFERENCES users (id),
+            at INTEGER NOT NULL,
+            currency TEXT NOT NULL,
+            amount INTEGER NOT NULL,
+            balance_after INTEGER NOT NULL,
+            source TEXT NOT NULL,
+            related TEXT
+        );
+        CREATE INDEX IF NOT EXISTS ledger_user ON ledger (user_id, at);",
+    )?;
+
+    let wallets: Vec<(String, i64, i64, i64)> = conn
+        .prepare("SELECT user_id, astrum, astrai, flux FROM wallets")?
+        .query_map([], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut stmt = conn.prepare(
+        "INSERT INTO ledger (uuid, user_id, at, currency, amount, balance_after, source)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?5, ?6)",
+    )?;
+    for (id, astrum, astrai, flux) in wallets {
+        for (currency, amount) in [
+            (Currency::Astrum, astrum),
+            (Currency::Astrai, astrai),
+            (Currency::Flux, flux),
+        ] {
+            stmt.execute(params![
+                Uuid::now_v7().to_string(),
+                id,
+                Utc::now().timestamp(),
+                as_text(&currency),
+                amount,
+                as_text(&LedgerSource::Opening),
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub(crate) struct Drift {
+    currency: Currency,
+    stored: i64,
+    ledger: i64,
+    drift: i64,
+}
+pub(crate) fn reconcile(conn: &Connection, user: &User) -> Result<Vec<Drift>, PersistenceError> {
+    let mut drifts = Vec::new();
+    for currency in [Currency::Astrum, Currency::Astrai, Currency::Flux] {
+        let ledger: i64 = conn.query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM ledger WHERE user_id = ?1 AND currency = ?2",
+            params![user.id.0, as_text(&currency)],
+            |row| row.get(0),
+        )?;
+        let stored = user.balance(currency);
+        drifts.push(Drift {
+            currency,
+            stored,
+            ledger,
+            drift: stored - ledger,
+        });
+    }
+    Ok(drifts)
+}
+
+// Startup check over every account; only prints, nothing is corrected.
+pub(crate) fn reconcile_all(state: &AppState) {
+    for id in state.repo.user_ids().unwrap_or_default() {
+        let Ok((user, conn)) = load_user(id.clone(), state) else {
+            continue;
+        };
+        for drift in reconcile(&conn, &user).unwrap_or_default() {
+            if drift.drift != 0 {
+                println!(
+                    "Ledger drift for {}: {:?} stored {} ledger {} ({:+})",
+                    id, drift.currency, drift.stored, drift.ledger, drift.drift
+                );
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LedgerRequest {
+    #[serde(default)]
+    page: u32,
+    #[serde(default = "default_per_page")]
+    per_page: u32,
+    currency: Option<Currency>,
+    source: Option<LedgerSource>,
+}
+#[derive(Serialize)]
+pub(crate) struct LedgerResponse {
+    entries: Vec<LedgerEntry>,
+    page: u32,
+    per_page: u32,
+    total: u64,
+}
+fn query_entries(
+    conn: &Connection,
+    id: &UserId,
+    req: &LedgerRequest,
+) -> Result<(Vec<LedgerEntry>, u64), PersistenceError> {
+    let currency = req.currency.map(|c| as_text(&c));
+    let source = req.source.map(|s| as_text(&s));
+    let per_page = req.per_page.clamp(1, PAGE_SIZE_MAX);
+    let filters = "user_id = ?1 AND (?2 IS NULL OR currency = ?2) AND (?3 IS NULL OR source = ?3)";
+
+    let total: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM ledger WHERE {}", filters),
+        params![id.0, currency, source],
+        |row| row.get(0),
+    )?;
+    let entries = conn
+        .prepare(&format!(
+            "SELECT uuid, at, currency, amount, balance_after, source, related FROM ledger
+            WHERE {} ORDER BY at DESC, uuid DESC LIMIT ?4 OFFSET ?5",
+            filters
+        ))?
+        .query_map(
+            params![
+                id.0,
+                currency,
+                source,
+                per_page,
+                req.page as i64 * per_page as i64
+            ],
+            |row| {
+                let uuid: String = row.get(0)?;
+                let related: Option<String> = row.get(6)?;
+                Ok(LedgerEntry {
+                    uuid: Uuid::parse_str(&uuid).unwrap_or_default(),
+                    at: DateTime::from_timestamp(row.get(1)?, 0).unwrap_or_default(),
+                    currency: from_text(row.get(2)?).unwrap_or(Currency::Flux),
+                    amount: row.get(3)?,
+                    balance_after: row.get(4)?,
+                    source: from_text(row.get(5)?).unwrap_or(LedgerSource::Opening),
+                    related: related.and_then(|r| Uuid::parse_str(&r).ok()),
+                })
+            },
+        )?
+        .collect::<Result<_, _>>()?;
+
+    Ok((entries, total as u64))
+}
+pub(crate) async fn ledger(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<LedgerRequest>,
+) -> Result<Json<LedgerResponse>, ApiError> {
+    let (user, conn) = load_user(userid.clone(), &state)?;
+    let (entries, total) = query_entries(&conn, &user.id, &req)?;
+
+    Ok(Json(LedgerResponse {
+        entries,
+        page: req.page,
+        per_page: req.per_page.clamp(1, PAGE_SIZE_MAX),
+        total,
+    }))
+}
+
+pub(crate) async fn ledger_reconcile(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+) -> Result<Json<Vec<Drift>>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    let drifts = reconcile(&conn, &user)?;
+    Ok(Json(drifts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "Tester".into(), None)
+    }
+    fn ledger_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id TEXT PRIMARY KEY);
+            INSERT INTO users (id) VALUES ('tester');
+            CREATE TABLE wallets (user_id TEXT, astrum INTEGER, astrai INTEGER, flux INTEGER);",
+        )
+        .unwrap();
+        open_ledger(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn adjust_books_the_balance_after() {
+        let mut user = user();
+        user.adjust(Currency::Astrum, 320, LedgerSource::Daily, None)
+            .unwrap();
+        user.adjust(Currency::Astrum, -160, LedgerSource::Pull, None)
+            .unwrap();
+        user.adjust(Currency::Flux, 0, LedgerSource::Daily, None)
+            .unwrap();
+
+        assert_eq!(user.astrum, 160);
+        let booked: Vec<(i64, i64)> = user
+            .ledger
+            .iter()
+            .map(|e| (e.amount, e.balance_after))
+            .collect();
+        assert_eq!(booked, vec![(320, 320), (-160, 160)]);
+    }
+
+    #[test]
+    fn adjust_refuses_an_overdraft() {
+        let mut user = user();
+        user.adjust(Currency::Astrai, 1, LedgerSource::Daily, None)
+            .unwrap();
+        let e = user
+            .adjust(Currency::Astrai, -2, LedgerSource::Pull, None)
+            .unwrap_err();
+        assert_eq!((e.currency, e.need, e.have), (Currency::Astrai, 2, 1));
+        assert!(
+            user.adjust(Currency::Flux, -1, LedgerSource::Purchase, None)
+                .is_err()
+        );
+
+        assert_eq!((user.astrai, user.flux), (1, 0));
+        assert_eq!(user.ledger.len(), 1);
+    }
+
+    #[test]
+    fn reconcile_finds_changes_that_skipped_the_ledger() {
+        let conn = ledger_db();
+        let mut user = user();
+        user.adjust(Currency::Flux, 500, LedgerSource::Daily, None)
+            .unwrap();
+        user.adjust(Currency::Flux, -120, LedgerSource::Purchase, None)
+            .unwrap();
+        user.adjust(Currency::Astrum, 160, LedgerSource::Daily, None)
+            .unwrap();
+        write_entries(&conn, &user).unwrap();
+        // A second save of the same entries books nothing new.
+        write_entries(&conn, &user).unwrap();
+        assert!(
+            reconcile(&conn, &user)
+                .unwrap()
+                .iter()
+                .all(|d| d.drift == 0)
+        );
+
+        user.astrai += 3;
+        let drifts = reconcile(&conn, &user).unwrap();
+        let drifted: Vec<(Currency, i64)> = drifts
+            .iter()
+            .filter(|d| d.drift != 0)
+            .map(|d| (d.currency, d.drift))
+            .collect();
+        assert_eq!(drifted, vec![(Currency::Astrai, 3)]);
+    }
+}
@@ -0,0 +1,2224 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::{Json, Router, routing::get, routing::post};
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+use rusqlite::Error as rusqError;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use serde_json::Error as serdeError;
+use serde_json::{self};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::task::{AbortHandle, JoinHandle};
+use tower_http::cors::CorsLayer;
+use uuid::Uuid;
+
+mod auth;
+mod banner;
+mod bundle;
+mod config;
+mod engine;
+mod error;
+mod events;
+mod expiry;
+mod history;
+mod ledger;
+mod loot;
+mod migrations;
+mod pity;
+mod pricing;
+mod repo;
+mod rng;
+mod session;
+mod sim;
+mod stock;
+mod ws;
+
+// Custom Types1
+use BarType::*;
+use Coeff::*;
+use auth::{ApiKey, AuthUser, Scope};
+use banner::BannerPity;
+use engine::BarMode;
+use error::ApiError;
+use events::{Event, Events};
+use gacha_protocol::{self, Rarities};
+use ledger::{Currency, LedgerSource, Overdraft};
+use loot::Granted;
+use rand::Rng;
+use rng::{PullStream, RngSource, StreamPos};
+use session::{BarClock, Session, Sessions};
+//use std::time::Duration as Duration_Time;
+
+#[derive(thiserror::Error, Debug)]
+enum PersistenceError {
+    #[error("Serialization of User Error")]
+    JsonError(#[from] serdeError),
+    #[error("Sqlite user loading error!")]
+    DBError(#[from] rusqError),
+    #[error("User Not Found")]
+    NotFound,
+    #[error("Password hashing failed")]
+    HashError,
+    //#[error("Invalid ID, Unable to construct voucher")]
+    //InvalidIDVoucher,
+}
+struct SqliteRepo {
+    db: Mutex<Connection>,
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+struct UserId(String);
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+struct Voucher {
+    id: u64,
+    uuid: Uuid,
+    name: String,
+    cost: u64,
+    #[serde(default)]
+    dur: f64,
+    new: bool,
+    description: String,
+    coeff: Coeff,
+    // Vouchers from before expiry existed have no `acquired` and never expire.
+    #[serde(default)]
+    acquired: Option<DateTime<Utc>>,
+    #[serde(default)]
+    expires: Option<DateTime<Utc>>,
+    // Flux handed back if it expires unused.
+    #[serde(default)]
+    refund: u64,
+    // Flux one voucher was bought for, None when it wasn't bought. Refunds
+    // hand back this rather than the template's cost.
+    #[serde(default)]
+    paid: Option<u64>,
+}
+impl Voucher {
+    fn minutes(&self) -> f64 {
+        self.dur.round()
+    }
+    fn hours(&self) -> f64 {
+        ((self.dur / 60.0) * 100.0).round() / 100.0
+    }
+}
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+enum Coeff {
+    PureC(f64, u32),
+    FunG(f64, u32),
+    G(f64, u32),
+    Expansion(f64, u32),
+    Base(f64, u32),
+    Maintenance(f64, u32),
+    System(f64, u32),
+}
+
+impl Coeff {
+    fn get_val(&self) -> u64 {
+        if let PureC(coeff, base_cost) = self {
+            return (*coeff * *base_cost as f64) as u64;
+        }
+        if let FunG(coeff, base_cost) = self {
+            return (*coeff * *base_cost as f64) as u64;
+        }
+        if let G(coeff, base_cost) = self {
+            return (*coeff * *base_cost as f64) as u64;
+        }
+        if let Expansion(coeff, base_cost) = self {
+            return (*coeff * *base_cost as f64) as u64;
+        }
+        if let Base(coeff, base_const) = self {
+            return (*coeff * *base_const as f64) as u64;
+        }
+        if let Maintenance(coeff, base_const) = self {
+            return (*coeff * *base_const as f64) as u64;
+        }
+        if let System(coeff, base_const) = self {
+            return (*coeff * *base_const as f64) as u64;
+        }
+
+        return 0;
+    }
+    fn pure_c() -> Self {
+        Self::PureC(1.72, 120)
+    }
+    fn fun_g() -> Self {
+        Self::FunG(1.48, 120)
+    }
+    fn g() -> Self {
+        Self::G(1.24, 120)
+    }
+    fn exp() -> Self {
+        Self::Expansion(1.12, 120)
+    }
+    fn base() -> Self {
+        Self::Base(1.0, 120)
+    }
+    fn maint() -> Self {
+        Self::Maintenance(1.0, 120)
+    }
+    fn system() -> Self {
+        Self::System(1.1, 120)
+    }
+}
+
+impl From<String> for Coeff {
+    fn from(value: String) -> Self {
+        Self::from(value.as_str())
+    }
+}
+impl From<&str> for Coeff {
+    fn from(value: &str) -> Self {
+        match value {
+            "g" => Self::g(),
+            "fun_g" => Self::fun_g(),
+            "exp" => Self::exp(),
+            "pure_c" => Self::pure_c(),
+            "maint" => Self::maint(),
+            "base" => Self::base(),
+            _ => Self::system(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+struct Bar {
+    id: u8,
+    locked: bool,
+    is_timing: bool,
+    overdrive: bool,
+    c: f64,
+    s: f64,
+    smax: f64,
+    tmax: f64,
+    tbase: f64,
+    s_reduction: f64,
+    overdrive_val: f64,
+}
+#[derive(thiserror::Error, Debug)]
+enum BarError {
+    #[error("this voucher doesn't run on a bar")]
+    NoBar,
+    #[error("bar {bar} is too full: need {need} minutes, {free} free")]
+    Full { bar: u8, need: f64, free: f64 },
+    #[error("bar {0} is locked")]
+    Locked(u8),
+    #[error("bar timer is unavailable")]
+    Busy,
+}
+enum BarType {
+    Stab,
+    Exp,
+    Maint,
+    Leisure,
+    Meta,
+    Idle,
+    Sys,
+}
+impl From<Voucher> for BarType {
+    fn from(value: Voucher) -> Self {
+        match value.coeff {
+            Base(..) => Idle,
+            Expansion(..) => Exp,
+            G(..) => Stab,
+            FunG(..) => Meta,
+            PureC(..) => Leisure,
+            Maintenance(..) => Maint,
+            System(..) => Sys,
+        }
+    }
+}
+impl BarType {
+    // The bar vouchers of this type run on, system ones have none.
+    fn bar(&self) -> Option<u8> {
+        match self {
+            Stab => Some(0),
+            Exp => Some(1),
+            Maint => Some(2),
+            Leisure => Some(3),
+            Meta => Some(4),
+            Idle => Some(5),
+            Sys => None,
+        }
+    }
+    fn get_fx_pool(voucher: Voucher, state: Session) -> Result<JoinHandle<f64>, BarError> {
+        let variant: usize = BarType::from(voucher.clone())
+            .bar()
+            .map_or(255, usize::from);
+
+        match variant {
+            255 => Err(BarError::NoBar),
+            _ => {
+                if let Some(timer) = state.timer.lock().unwrap().take() {
+                    timer.abort();
+                }
+                let mut bars = state.bars.lock().unwrap();
+                let bar = bars.get(variant).unwrap().clone();
+                let mut timer = state.timer.lock().unwrap();
+                let initial_s = bar.s;
+                let id = bar.id;
+
+                let cost_hours = voucher.minutes();
+
+                if bar.smax - bar.s < cost_hours {
+                    return Err(BarError::Full {
+                        bar: id,
+                        need: cost_hours,
+                        free: bar.smax - bar.s,
+                    });
+                }
+
+                bars.iter_mut().for_each(|f| {
+                    f.is_timing = false;
+                });
+                bars.iter_mut().for_each(|f| {
+                    if f.id == id {
+                        f.smax = cost_hours;
+                        f.s = 0.0;
+                    }
+                });
+                *timer = Some(bar.start_timer(state.clone()));
+
+                let state_i = state.clone();
+                let job = tokio::spawn(async move {
+                    let mut interval =
+                        tokio::time::interval(Duration::seconds(2).to_std().unwrap());
+                    let init_s = initial_s;
+                    let result = loop {
+                        interval.tick().await;
+                        // Done once the timer has moved off this bar, either full or stopped.
+                        if state_i.running() != Some((BarMode::Timer, id)) {
+                            let mut bars = state_i.bars.lock().unwrap();
+                            let bar = bars.get_mut(id as usize).unwrap();
+                            let result = bar.s;
+
+                            bar.smax = Bar::by_id(id).smax;
+                            bar.s += init_s;
+
+                            break result;
+                        }
+                    };
+                    result
+                });
+                state.track(job.abort_handle());
+                Ok(job)
+            }
+        }
+    }
+}
+impl Bar {
+    fn _vec() -> Vec<Bar> {
+        let mut vec = Vec::<Bar>::new();
+        for x in 0..6 {
+            vec.push(Bar::by_id(x));
+        }
+
+        vec
+    }
+    fn by_id(id: u8) -> Self {
+        let mut bar = Self {
+            id: id,
+            c: 0.0,
+            is_timing: false,
+            overdrive: false,
+            s: 0.0,
+            smax: 0.0,
+            locked: false,
+            tbase: 0.0,
+            tmax: 0.0,
+            s_reduction: 0.0,
+            overdrive_val: 0.0,
+        };
+        if let Some(tuning) = config::economy().bars.get(id as usize) {
+            bar.c = tuning.c;
+            bar.smax = tuning.smax;
+            bar.tmax = tuning.tmax;
+            bar.overdrive_val = (0.24 * bar.smax) + 15.0;
+        }
+        bar
+    }
+
+    fn start_idle(&self, state: Session) -> AbortHandle {
+        state.drive(BarMode::Idle, self.id)
+    }
+    fn start_timer(&self, state: Session) -> AbortHandle {
+        state.drive(BarMode::Timer, self.id)
+    }
+
+    fn reset(&mut self) {
+        self.locked = false;
+        self.s = 0.0;
+        self.s_reduction = 0.0;
+        self.overdrive = false;
+        self.tbase = 0.0;
+    }
+    fn reduce_tbase(&mut self, time: f64, c: f64) {
+        if self.locked {
+            self.tbase -= (time * c).max(0.0);
+
+            if self.tbase <= 0.0 {
+                self.reset();
+            }
+        }
+    }
+    fn reduce_s(&mut self, time: f64, c: f64) {
+        if self.locked {
+            let percentage = self.tbase.max(0.1) / self.tmax;
+            let s = percentage * self.smax;
+            self.s = s.max(0.0);
+        }
+        if self.s_reduction < self.overdrive_val {
+            if !(self.s - (time * c) < 0.0) {
+                self.s -= time * c;
+                self.s_reduction += c * time;
+            } else {
+                self.s = 0.0;
+            }
+        }
+    }
+}
+
+fn round_h(val: f64) -> f64 {
+    ((val / 60.0) * 100.0).round() / 100.0
+}
+// A voucher's name without the " [2H]" its duration was tagged with.
+fn untagged(name: &str) -> &str {
+    match name.rsplit_once(" [") {
+        Some((name, tag)) if tag.ends_with(']') => name,
+        _ => name,
+    }
+}
+impl Voucher {
+    fn by_id(id: u64, user: &User) -> Result<Self, PersistenceError> {
+        match id {
+            999 => Ok(Self::mythic_week()),
+            1 => Ok(Self::off_day()),
+            4 => Ok(Self::coffee()),
+            _ => {
+                let mut vouchers = user.templates.clone();
+                vouchers.retain(|v| v.id == id);
+
+                let mut voucher = vouchers.first().ok_or(PersistenceError::NotFound)?.clone();
+                voucher.uuid = uuid::Uuid::now_v7();
+                Ok(voucher)
+            }
+        }
+    }
+    fn new(
+        id: u64,
+        uuid: Uuid,
+        name: String,
+        cost: u64,
+        dur: f64,
+        new: bool,
+        desc: String,
+        coeff: Coeff,
+    ) -> Self {
+        Self {
+            id,
+            uuid,
+            name,
+            cost,
+            dur,
+            new,
+            description: desc,
+            coeff,
+            acquired: None,
+            expires: None,
+            refund: 0,
+            paid: None,
+        }
+    }
+    fn _get_templates(user: &User) -> Vec<Self> {
+        let mut vec: Vec<Self> = Vec::new();
+        let codes: [u16; 3] = [1, 4, 999];
+
+        for x in codes.into_iter() {
+            vec.push(Voucher::by_id(x as u64, user).unwrap());
+        }
+        vec
+    }
+    fn from_req_voucher(user: &User, voucher: ReqVoucher) -> Self {
+        let mut highest_found: u64 = 0;
+
+        let highest_c = &mut highest_found;
+        user.templates
+            .iter()
+            .map(|t| {
+                if t.id > *highest_c && t.id != 999 {
+                    *highest_c = t.id;
+                }
+            })
+            .count();
+
+        let mut id = highest_found + 100;
+        if id == 999 {
+            id += 1;
+        }
+
+        let coeff: Coeff = voucher.coeff.into();
+        let name = format!("{}", voucher.name);
+        let dur = voucher.dur * 60.0;
+        let cost = pricing::base_cost(&coeff, dur);
+        let description = voucher.description;
+
+        Voucher::new(
+            id,
+            uuid::Uuid::now_v7(),
+            name,
+            cost,
+            dur,
+            true,
+            description,
+            coeff,
+        )
+    }
+    // Refuses durations in hours that aren't a number above 0, or that are
+    // longer than the bar the voucher runs on can hold.
+    fn check_hours(&self, hours: f64) -> Result<(), ApiError> {
+        if !hours.is_finite() || hours <= 0.0 {
+            return Err(ApiError::Invalid(
+                "dur must be a number of hours above 0".into(),
+            ));
+        }
+        if let Some(id) = BarType::from(self.clone()).bar() {
+            let smax = Bar::by_id(id).smax;
+            if hours * 60.0 > smax {
+                return Err(ApiError::Invalid(format!(
+                    "dur can be at most {}H on bar {}",
+                    round_h(smax),
+                    id
+                )));
+            }
+        }
+        Ok(())
+    }
+    fn from_purchase_req(req: PurchaseRequest, user: &User) -> Result<Self, ApiError> {
+        let mut req_voucher =
+            Voucher::by_id(req.id, user).map_err(|_| ApiError::NotFound("voucher"))?;
+
+        if pricing::fixed_price(req_voucher.id) {
+            req_voucher.dur = 1.0;
+            return Ok(req_voucher);
+        }
+        req_voucher.check_hours(req.dur)?;
+
+        req_voucher.name = format!("{} [{}H]", &req_voucher.name, req.dur.to_string());
+        req_voucher.dur = (req.dur * 60.0).round();
+        req_voucher.cost = pricing::base_cost(&req_voucher.coeff, req_voucher.dur);
+
+        Ok(req_voucher)
+    }
+    fn mythic_week() -> Self {
+        Self {
+            id: 999,
+            uuid: uuid::Uuid::now_v7(),
+            name: "Mythic Week Off".to_string(),
+            cost: 35480,
+            new: true,
+            dur: 168.0 * 60.0,
+            description: "Get a full week off".to_string(),
+            coeff: Coeff::pure_c(),
+            acquired: None,
+            expires: None,
+            refund: 0,
+            paid: None,
+        }
+    }
+    fn off_day() -> Self {
+        Self {
+            id: 1,
+            uuid: uuid::Uuid::now_v7(),
+            name: String::from("Full Day Off"),
+            cost: 5720,
+            dur: 24.0 * 60.0,
+            new: true,
+            description: String::from("Get one full day off"),
+            coeff: Coeff::pure_c(),
+            acquired: None,
+            expires: None,
+            refund: 0,
+            paid: None,
+        }
+    }
+    fn coffee() -> Self {
+        Self {
+            id: 4,
+            uuid: uuid::Uuid::now_v7(),
+            name: String::from("Coffee (Premium)"),
+            cost: 150,
+            dur: 0.0 * 60.0,
+            new: true,
+            description: String::from("Get 1 cup of Premium Coffee (250ml)"),
+            coeff: Coeff::base(),
+            acquired: None,
+            expires: None,
+            refund: 0,
+            paid: None,
+        }
+    }
+}
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Copy)]
+enum Category {
+    SNode,
+    ANode,
+    BNode,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct ISRDO {
+    uuid: uuid::Uuid,
+    description: String,
+    payout: u16,
+}
+
+trait UserRepo {
+    fn load<'a>(
+        &'a self,
+        id: UserId,
+    ) -> Result<(User, MutexGuard<'a, Connection>), PersistenceError>;
+    fn save(&self, user: &User, conn: &Connection) -> Result<(), PersistenceError>;
+}
+#[derive(Serialize, Deserialize)]
+struct User {
+    id: UserId,
+    astrai: u64,
+    astrum: u64,
+    flux: i128,
+    has_slip: bool,
+    username: String,
+    email: Option<String>,
+    dailies: Vec<Daily>,
+    vouchers: Vec<Voucher>,
+    templates: Vec<Voucher>,
+    bars: Vec<Bar>,
+    active_timer: bool,
+    active_bar: u8,
+    pause_drip: bool,
+    timer: Option<Timer>,
+    timeout_map: HashMap<u64, i64>,
+    isrdos: Vec<ISRDO>,
+    sss_pity: u16,
+    s_pity: u16,
+    a_pity: u16,
+    total_pulls: u128,
+    total_flux_aq: u128,
+    total_astrum_aq: u128,
+    todays_flux: (i64, u64),
+    #[serde(skip)]
+    ledger: Vec<ledger::LedgerEntry>,
+    #[serde(skip)]
+    bar_clock: Option<BarClock>,
+    #[serde(skip)]
+    banner_pity: HashMap<String, BannerPity>,
+    #[serde(skip)]
+    orders: Vec<bundle::Order>,
+    #[serde(skip)]
+    purchases: Vec<pricing::Purchase>,
+    #[serde(skip)]
+    pulls: Vec<PullRecord>,
+    #[serde(skip)]
+    stream: Option<StreamPos>,
+}
+impl User {
+    fn new(id: UserId, username: String, email: Option<String>) -> Self {
+        let mut user = Self {
+            id,
+            astrai: 0,
+            astrum: 0,
+            flux: 0,
+            has_slip: false,
+            username,
+            email,
+            dailies: Daily::_init(),
+            vouchers: Vec::new(),
+            templates: Vec::new(),
+            bars: Bar::_vec(),
+            active_timer: false,
+            active_bar: 0,
+            pause_drip: true,
+            timer: None,
+            timeout_map: HashMap::new(),
+            isrdos: Vec::new(),
+            sss_pity: 0,
+            s_pity: 0,
+            a_pity: 0,
+            total_pulls: 0,
+            total_flux_aq: 0,
+            total_astrum_aq: 0,
+            todays_flux: (0, 0),
+            ledger: Vec::new(),
+            bar_clock: None,
+            banner_pity: HashMap::new(),
+            orders: Vec::new(),
+            purchases: Vec::new(),
+            pulls: Vec::new(),
+            stream: None,
+        };
+        user.templates = Voucher::_get_templates(&user);
+        user
+    }
+}
+impl SqliteRepo {
+    /*fn mark_all_vouchers_new(&self) -> Result<(), rusqlite::Error> {
+        let conn = self.db.lock().unwrap();
+        conn.execute(
+            "UPDATE users
+             SET data = json_set(
+                 data,
+                 '$.templates',
+                 (SELECT json_group_array(json_set(value, '$.new', json('true')))
+                  FROM json_each(users.data, '$.vouchers'))
+             )",
+            [],
+        )?;
+        Ok(())
+    }*/
+    fn new(path: &Path) -> Self {
+        let conn = Connection::open(path).expect("Error opening DB!");
+        //let init = false;
+        /*let mut user = User {
+            id: UserId("testing".to_string()),
+            astrum: 1600,
+            astrai: 30,
+            flux: 500,
+            has_slip: false,
+            email: None,
+            active_timer: false,
+            timer: None,
+            timeout_map: HashMap::new(),
+            a_pity: 0,
+            s_pity: 0,
+            sss_pity: 0,
+            dailies: Daily::init(),
+            todays_flux: (0, 0),
+            total_pulls: 0,
+            total_flux_aq: 0,
+            total_astrum_aq: 0,
+            username: "Test User".to_string(),
+            vouchers: Vec::<Voucher>::new(),
+            templates: Vec::<Voucher>::new(),
+            isrdos: Vec::<ISRDO>::new(),
+            pause_drip: false,
+        };*/
+        //user.templates = Voucher::get_templates(&user);
+
+        migrations::migrate(&conn, false).expect("Database migration failed!");
+
+        //if init {
+        //    let _ = conn.execute(
+        //       "INSERT OR REPLACE INTO users (id, data) Values(?1, ?2)",
+        //        params![user.id.0, serde_json::to_string(&user).unwrap()],
+        //    );
+        //}
+
+        Self {
+            db: Mutex::new(conn),
+        }
+    }
+}
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum SerializedRarity {
+    MythicSSS,
+    S,
+    A,
+    B,
+    NoTickets,
+}
+impl SerializedRarity {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SerializedRarity::MythicSSS => "MythicSSS",
+            SerializedRarity::S => "S",
+            SerializedRarity::A => "A",
+            SerializedRarity::B => "B",
+            SerializedRarity::NoTickets => "NoTickets",
+        }
+    }
+    fn from_str(value: &str) -> Self {
+        match value {
+            "MythicSSS" => SerializedRarity::MythicSSS,
+            "S" => SerializedRarity::S,
+            "A" => SerializedRarity::A,
+            "B" => SerializedRarity::B,
+            _ => SerializedRarity::NoTickets,
+        }
+    }
+    fn rarity(&self) -> Option<Rarities> {
+        match self {
+            SerializedRarity::MythicSSS => Some(Rarities::MythicSSS),
+            SerializedRarity::S => Some(Rarities::S),
+            SerializedRarity::A => Some(Rarities::A),
+            SerializedRarity::B => Some(Rarities::B),
+            SerializedRarity::NoTickets => None,
+        }
+    }
+}
+impl TryFrom<Rarities> for SerializedRarity {
+    type Error = PersistenceError;
+    fn try_from(value: Rarities) -> Result<Self, Self::Error> {
+        match value {
+            Rarities::MythicSSS => Ok(SerializedRarity::MythicSSS),
+            Rarities::S => Ok(SerializedRarity::S),
+            Rarities::A => Ok(SerializedRarity::A),
+            Rarities::B => Ok(SerializedRarity::B),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+struct Timer {
+    id: String,
+    owner: UserId,
+    started: DateTime<Utc>,
+    ended: Option<DateTime<Utc>>,
+    category: Category,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    banner_id: Option<String>,
+}
+#[derive(Serialize)]
+struct PullResponse {
+    result: SerializedRarity,
+    vouchers: u8,
+    items: Vec<Granted>,
+}
+#[derive(Deserialize)]
+struct MultiPullRequest {
+    count: u8,
+    #[serde(default)]
+    banner_id: Option<String>,
+}
+#[derive(Serialize, Clone)]
+struct PullRecord {
+    pulled_at: DateTime<Utc>,
+    banner: String,
+    rarity: SerializedRarity,
+    sss_pity: u16,
+    s_pity: u16,
+    a_pity: u16,
+    astrai_spent: u64,
+    astrum_spent: u64,
+    vouchers: u8,
+    slip_consumed: bool,
+    // The tenth pull of a block with no A or better yet, where a B becomes an A.
+    floored: bool,
+    items: Vec<Granted>,
+    // Where in the account's stream for that day the pull started drawing.
+    rng_day: Option<String>,
+    rng_word_pos: Option<i64>,
+}
+#[derive(Serialize)]
+struct MultiPullResponse {
+    results: Vec<PullResponse>,
+    vouchers: u16,
+    flux: i128,
+    astrai_spent: u64,
+    astrum_spent: u64,
+}
+
+#[derive(Clone)]
+struct AppState {
+    repo: Arc<SqliteRepo>,
+    sessions: Arc<Sessions>,
+    events: Events,
+    rng: Arc<RngSource>,
+    logins: Arc<auth::LoginThrottle>,
+}
+#[cfg(test)]
+impl AppState {
+    // What serve() builds, over a fresh in-memory database.
+    fn in_memory() -> Self {
+        let events = Events::new();
+        AppState {
+            repo: SqliteRepo::new(Path::new(":memory:")).into(),
+            sessions: Arc::new(Sessions::new(events.clone())),
+            events,
+            rng: Arc::new(RngSource::new(false)),
+            logins: Arc::default(),
+        }
+    }
+}
+
+fn load_user(
+    userid: String,
+    state: &AppState,
+) -> Result<(User, MutexGuard<'_, Connection>), ApiError> {
+    let id = UserId(userid);
+    state.sessions.touch(&id);
+    Ok(state.repo.load(id)?)
+}
+
+fn save_user(
+    user: &User,
+    conn: &MutexGuard<'_, Connection>,
+    state: &AppState,
+) -> Result<(), ApiError> {
+    state.repo.save(user, conn)?;
+    // Queued ledger entries mean a balance moved.
+    if !user.ledger.is_empty() {
+        state.events.publish(
+            &user.id,
+            Event::Wallet {
+                astrum: user.astrum,
+                astrai: user.astrai,
+                flux: user.flux,
+            },
+        );
+    }
+    Ok(())
+}
+
+// Pity moves first, then the banner's featured prize (if this rarity has one)
+// and the rarity's loot table pay out, then the pull is paid for.
+fn apply_outcome(
+    user: &mut User,
+    banner: &config::Banner,
+    pity: &mut BannerPity,
+    outcome: &Rarities,
+    rng: &mut impl Rng,
+) -> Result<PullRecord, Overdraft> {
+    let mut record = PullRecord {
+        pulled_at: Utc::now(),
+        banner: banner.id.clone(),
+        rarity: SerializedRarity::NoTickets,
+        sss_pity: pity.sss_pity,
+        s_pity: pity.s_pity,
+        a_pity: pity.a_pity,
+        astrai_spent: 0,
+        astrum_spent: 0,
+        vouchers: 0,
+        slip_consumed: false,
+        floored: false,
+        items: Vec::new(),
+        rng_day: None,
+        rng_word_pos: None,
+    };
+
+    pity::advance(pity, outcome);
+    let featured = banner.rate_up(outcome).zip(pity.guaranteed(outcome));
+    let (drops, used) = loot::drops(user, featured, outcome, rng);
+    record.slip_consumed = used;
+    for drop in drops {
+        record.items.push(user.grant(drop)?);
+    }
+    record.vouchers = record
+        .items
+        .iter()
+        .filter(|item| matches!(item, Granted::Voucher { .. }))
+        .count() as u8;
+
+    user.total_pulls += 1;
+    if user.astrai > 0 {
+        user.adjust(Currency::Astrai, -1, LedgerSource::Pull, None)?;
+        record.astrai_spent = 1;
+        return Ok(record);
+    }
+    let price = config::economy().astrum_per_pull;
+    user.adjust(Currency::Astrum, -(price as i64), LedgerSource::Pull, None)?;
+    record.astrum_spent = price;
+    Ok(record)
+}
+
+// One pull on `banner`. Everything drawn after the rarity comes from `rng`.
+fn pull(
+    user: &mut User,
+    banner: &config::Banner,
+    floor: bool,
+    rng: &mut impl Rng,
+) -> Result<PullRecord, Overdraft> {
+    let mut pity = user.pity(&banner.id);
+    let outcome = match pity::roll(&pity, rng) {
+        Rarities::B if floor => Rarities::A,
+        outcome => outcome,
+    };
+
+    let mut record = apply_outcome(user, banner, &mut pity, &outcome, rng)?;
+    user.set_pity(&banner.id, pity);
+    record.rarity = SerializedRarity::try_from(outcome).unwrap();
+    record.floored = floor;
+    Ok(record)
+}
+
+fn pull_once(
+    user: &mut User,
+    banner: &config::Banner,
+    floor: bool,
+    stream: &mut PullStream,
+) -> Result<PullRecord, Overdraft> {
+    let word_pos = stream.word_pos();
+    let mut record = pull(user, banner, floor, &mut stream.rng)?;
+    record.rng_day = Some(stream.day.clone());
+    record.rng_word_pos = Some(word_pos);
+    // Logged and the stream moved on in the same save as the pull itself.
+    user.pulls.push(record.clone());
+    user.stream = Some(stream.pos());
+    Ok(record)
+}
+
+// The body is optional, a pull without one goes to the standard banner.
+async fn handle_pull(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    req: Option<Json<PullRequest>>,
+) -> Result<Json<PullResponse>, ApiError> {
+    let banner_id = req.and_then(|Json(req)| req.banner_id);
+    let banner = banner::find(banner_id.as_deref())?;
+    let (mut user, conn) = load_user(userid, &state)?;
+    banner.check(&user)?;
+
+    if user.astrai < 1 && user.astrum < config::economy().astrum_per_pull {
+        return Ok(Json(PullResponse {
+            result: SerializedRarity::NoTickets,
+            vouchers: 0,
+            items: Vec::new(),
+        }));
+    }
+
+    let mut stream = state.rng.stream(&conn, &user.id)?;
+    let record = pull_once(&mut user, banner, false, &mut stream)?;
+    save_user(&user, &conn, &state)?;
+    state.events.publish(
+        &user.id,
+        Event::Pull {
+            banner: banner.id.clone(),
+            results: vec![record.rarity],
+            vouchers: record.vouchers as u16,
+        },
+    );
+
+    Ok(Json(PullResponse {
+        result: record.rarity,
+        vouchers: record.vouchers,
+        items: record.items.clone(),
+    }))
+}
+
+const MULTI_PULL_MAX: u8 = 100;
+
+fn pull_many(
+    user: &mut User,
+    banner: &config::Banner,
+    count: u8,
+    stream: &mut PullStream,
+) -> Result<Vec<PullRecord>, Overdraft> {
+    let mut records = Vec::new();
+    let mut floor_hit = false;
+    for idx in 0..count {
+        if idx % 10 == 0 {
+            floor_hit = false;
+        }
+        // Every full block of 10 holds at least one A or better.
+        let floor = idx % 10 == 9 && !floor_hit;
+
+        let record = pull_once(user, banner, floor, stream)?;
+        if record.rarity != SerializedRarity::B {
+            floor_hit = true;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+async fn handle_pull_multi(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<MultiPullRequest>,
+) -> Result<Json<MultiPullResponse>, ApiError> {
+    if req.count < 1 || req.count > MULTI_PULL_MAX {
+        return Err(ApiError::Invalid(format!(
+            "count must be between 1 and {}",
+            MULTI_PULL_MAX
+        )));
+    }
+    let banner = banner::find(req.banner_id.as_deref())?;
+    let (mut user, conn) = load_user(userid, &state)?;
+    banner.check(&user)?;
+    let mut resp = MultiPullResponse {
+        results: Vec::new(),
+        vouchers: 0,
+        flux: 0,
+        astrai_spent: 0,
+        astrum_spent: 0,
+    };
+
+    // All or nothing: astrai are spent first, the rest is paid in astrum.
+    if user.astrai + user.astrum / config::economy().astrum_per_pull < req.count as u64 {
+        resp.results.push(PullResponse {
+            result: SerializedRarity::NoTickets,
+            vouchers: 0,
+            items: Vec::new(),
+        });
+        return Ok(Json(resp));
+    }
+
+    let (astrai, astrum, flux) = (user.astrai, user.astrum, user.flux);
+    let mut stream = state.rng.stream(&conn, &user.id)?;
+    let records = pull_many(&mut user, banner, req.count, &mut stream)?;
+    for record in records.iter() {
+        resp.vouchers += record.vouchers as u16;
+        resp.results.push(PullResponse {
+            result: record.rarity,
+            vouchers: record.vouchers,
+            items: record.items.clone(),
+        });
+    }
+
+    resp.flux = user.flux - flux;
+    resp.astrai_spent = astrai - user.astrai;
+    resp.astrum_spent = astrum - user.astrum;
+
+    save_user(&user, &conn, &state)?;
+    state.events.publish(
+        &user.id,
+        Event::Pull {
+            banner: banner.id.clone(),
+            results: records.iter().map(|r| r.rarity).collect(),
+            vouchers: resp.vouchers,
+        },
+    );
+    Ok(Json(resp))
+}
+
+/*
+fn resolve_timer(timer: Timer) -> u64 {
+    let reward: i64;
+    let duration: Duration;
+
+    if let Some(ended) = timer.ended {
+        duration = ended - timer.started;
+    } else {
+        duration = Utc::now() - timer.started;
+    }
+
+    let hours = duration.num_hours();
+    let minutes = duration.num_minutes();
+
+    if hours < 1 && minutes < 1 {
+        return 0;
+    }
+
+    match timer.category {
+        Category::SNode => reward = ((minutes as f64 / 60.0) * 480.0) as i64,
+        Category::ANode => {
+            reward = ((minutes as f64 / 30.0) as f64 * 120.0) as i64;
+        }
+        Category::BNode => {
+            reward = ((minutes as f64 / 10.0) as f64 * 25.0) as i64;
+        }
+    }
+
+    if reward < 1 {
+        return 0;
+    } else {
+        return reward as u64;
+    }
+}*/
+
+#[derive(Deserialize, Clone)]
+struct VoucherRequest {
+    request_all: bool,
+    filter_by_id: u64,
+    store: bool,
+}
+async fn get_user_vouchers(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<VoucherRequest>,
+) -> Result<Json<Vec<expiry::VoucherInfo>>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    if req.store {
+        return Ok(Json(pricing::store(&user, &conn)?));
+    }
+
+    if req.request_all && req.filter_by_id == 0 {
+        return Ok(Json(expiry::listing(user.vouchers)));
+    }
+    if req.request_all && req.filter_by_id > 0 {
+        let vouchers_mapped: Vec<Voucher> = user
+            .vouchers
+            .into_iter()
+            .filter(|e| e.id == req.filter_by_id)
+            .collect();
+        return Ok(Json(expiry::listing(vouchers_mapped)));
+    }
+    Err(ApiError::Invalid("set either store or request_all".into()))
+}
+
+#[derive(Deserialize)]
+struct ReqVoucher {
+    name: String,
+    dur: f64,
+    coeff: String,
+    description: String,
+}
+#[derive(Deserialize)]
+struct CreateRequest {
+    voucher: ReqVoucher,
+}
+async fn create(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<CreateRequest>,
+) -> Result<StatusCode, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+    let voucher = req.voucher;
+    if voucher.dur < 0.1 || voucher.name.is_empty() {
+        return Err(ApiError::Invalid(
+            "a voucher needs a name and a duration of at least 0.1".into(),
+        ));
+    }
+
+    let hours = voucher.dur;
+    let template = Voucher::from_req_voucher(&user, voucher);
+    template.check_hours(hours)?;
+    user.templates.push(template);
+    let _ = save_user(&user, &conn, &state);
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Deserialize, Clone)]
+struct PurchaseRequest {
+    amount: u8,
+    id: u64,
+    dur: f64,
+}
+async fn purchase(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<PurchaseRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+    if req.amount < 1 {
+        return Err(ApiError::Invalid("amount must be at least 1".into()));
+    }
+    let req_voucher = Voucher::from_purchase_req(req.clone(), &user)?;
+    let now = Utc::now();
+    stock::check(&conn, &user.id, &req_voucher, req.amount as u32, now)?;
+    let demand = pricing::demand(&conn, &user.id, req_voucher.id, now)?;
+    let quote = pricing::price(&user, &req_voucher, req.amount as u32, demand, now);
+    let cost = quote.total;
+    if user.flux < cost as i128 {
+        return Err(ApiError::Insufficient {
+            currency: "flux",
+            need: cost as i128,
+            have: user.flux,
+        });
+    }
+
+    decrease_flux(
+        &mut user,
+        cost as i128,
+        LedgerSource::Purchase,
+        Some(req_voucher.uuid),
+    )?;
+
+    for idx in 0..req.amount {
+        let mut bought = req_voucher.clone();
+        if idx > 0 {
+            bought.uuid = Uuid::now_v7();
+        }
+        bought.paid = Some(quote.unit);
+        bought.acquire(expiry::Source::Purchase);
+        user.vouchers.push(bought);
+    }
+    user.purchases.push(pricing::Purchase {
+        template: req_voucher.id,
+        amount: req.amount as u32,
+        at: now,
+    });
+    save_user(&user, &conn, &state)?;
+
+    Ok(Json(serde_json::json!({
+        "name": &req_voucher.name,
+        "result": format!(
+        "Purchased Item: {}",
+        &req_voucher.name),
+    })))
+}
+
+#[derive(Deserialize)]
+struct DeleteRequest {
+    uuid: Uuid,
+    store: bool,
+}
+async fn delete_item(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<DeleteRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (mut user, conn) = load_user(userid.clone(), &state)?;
+
+    // One the sweeper hasn't got to yet goes the way it would have, with the
+    // expiry refund rather than the full one.
+    let now = Utc::now();
+    let voucher = user.vouchers.iter().find(|v| v.uuid == req.uuid);
+    if !req.store && voucher.is_some_and(|v| v.expired(now)) {
+        let expired = user.expire_vouchers(now)?;
+        save_user(&user, &conn, &state)?;
+        expiry::announce(&state, &user.id, expired);
+        return Ok(Json(serde_json::json!({
+            "status": "Item Expired",
+        })));
+    }
+
+    let template = user.templates.iter().find(|v| v.uuid == req.uuid);
+    let voucher = user.vouchers.iter().find(|v| v.uuid == req.uuid);
+    if voucher.is_none() && !req.store {
+        return Err(ApiError::NotFound("voucher"));
+    }
+    if template.is_none() && req.store {
+        return Err(ApiError::NotFound("template"));
+    }
+
+    let mut action = String::new();
+    if req.store {
+        user.templates.retain(|v| v.uuid != req.uuid);
+        action += "Deleted";
+    } else {
+        action += "Refunded";
+        if let Some(refund) = user.vouchers.iter().find(|v| v.uuid == req.uuid) {
+            let paid = refund.paid.unwrap_or(refund.cost) as i64;
+            user.adjust(Currency::Flux, paid, LedgerSource::Refund, Some(req.uuid))?;
+            user.vouchers.retain(|v| v.uuid != req.uuid);
+        }
+    }
+    let _ = save_user(&user, &conn, &state);
+
+    Ok(Json(serde_json::json!({
+        "status": format!("Item {}", action),
+    })))
+}
+
+#[derive(Deserialize)]
+struct ConsumeRequest {
+    uuid: Uuid,
+}
+async fn consume(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<ConsumeRequest>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let vec: Vec<Voucher>;
+    let voucher_opt: Option<Voucher>;
+    let session: Session;
+    {
+        let user = load_user(userid.clone(), &state)?.0;
+        session = state.sessions.get(&user);
+
+        voucher_opt = user
+            .vouchers
+            .clone()
+            .into_iter()
+            .filter(|v| v.uuid == req.uuid)
+            .next();
+    }
+
+    if voucher_opt.as_ref().is_some_and(|v| v.expired(Utc::now())) {
+        return Err(ApiError::Forbidden("voucher has expired".into()));
+    }
+    if let Some(voucher) = voucher_opt {
+        let avail = BarType::get_fx_pool(voucher.clone(), session.clone());
+        match avail {
+            Ok(result) => {
+                let username = userid.clone();
+                let state_i = state.clone();
+
+                let poller = tokio::spawn(async move {
+                    let state = state_i;
+                    let username = username;
+
+                    loop {
+                        tokio::time::sleep(Duration::seconds(5).to_std().unwrap()).await;
+                        if result.is_finished() {
+                            let new_res = result.await;
+                            if new_res.is_ok() {
+                                let time = new_res.unwrap();
+
+                                let mut voucher = voucher;
+                                let mut time_remain = voucher.minutes() - time;
+                                let prefix: &str;
+
+                                prefix = match time_remain {
+                                    t if t < 60.0 => "M",
+                                    _ => "H",
+                                };
+
+                                if time_remain <= 5.0 {
+                                    break;
+                                }
+                                let hours_remain = round_h(time_remain);
+                                let minutes_remain = time_remain;
+                                time_remain = match prefix {
+                                    "M" => time_remain.round(),
+                                    _ => hours_remain,
+                                };
+
+                                let Ok((mut user, conn)) = load_user(username, &state) else {
+                                    break;
+                                };
+                                let returned = match time {
+                                    0.0 => None,
+                                    // Barely used, handed back as it was.
+                                    t if t < 5.0 => Some(Voucher {
+                                        uuid: Uuid::now_v7(),
+                                        ..voucher
+                                    }),
+                                    // Named after the voucher itself, prizes
+                                    // don't have a template to go back to.
+                                    _ => {
+                                        voucher.name = format!(
+                                            "{} [{}{}]",
+                                            untagged(&voucher.name),
+                                            time_remain,
+                                            prefix
+                                        );
+                                        voucher.dur = minutes_remain;
+                                        Some(voucher)
+                                    }
+                                };
+
+                                if let Some(mut returned) = returned {
+                                    returned.acquire(expiry::Source::Remainder);
+                                    state.events.publish(
+                                        &user.id,
+                                        Event::RemainderRefunded {
+                                            uuid: returned.uuid,
+                                            name: returned.name.clone(),
+                                            minutes: returned.minutes(),
+                                        },
+                                    );
+                                    user.vouchers.push(returned);
+                                }
+                                let _ = save_user(&user, &conn, &state);
+                                break;
+                            }
+                            break;
+                        }
+                    }
+                });
+                session.track(poller.abort_handle());
+            }
+            // System vouchers have no bar to run on and are used up right away.
+            Err(BarError::NoBar) => (),
+            Err(error) => return Err(error.into()),
+        }
+    } else {
+        return Err(ApiError::NotFound("voucher"));
+    }
+
+    let (mut user, conn) = load_user(userid, &state)?;
+    let consumed = user.vouchers.iter().find(|f| f.uuid == req.uuid).cloned();
+    vec = user
+        .vouchers
+        .into_iter()
+        .filter(|f| f.uuid != req.uuid)
+        .collect();
+    user.vouchers = vec;
+
+    let _ = save_user(&user, &conn, &state);
+    if let Some(voucher) = consumed {
+        state.events.publish(
+            &user.id,
+            Event::VoucherConsumed {
+                uuid: voucher.uuid,
+                name: voucher.name,
+            },
+        );
+    }
+    Ok(Json(serde_json::json!({
+        "status": "Item Consumed",
+    })))
+}
+
+fn decrease_flux(
+    user: &mut User,
+    amount: i128,
+    source: LedgerSource,
+    related: Option<Uuid>,
+) -> Result<(), Overdraft> {
+    user.adjust(Currency::Flux, -(amount as i64), source, related)?;
+
+    if user.dailies[3].last_claimed < Daily::cycle(0) {
+        user.todays_flux.1 += amount as u64;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CreateAdvanced {
+    id: u64,
+    amount: u8,
+}
+async fn create_advanced(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<CreateAdvanced>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (mut user, conn) = load_user(userid.clone(), &state)?;
+
+    let voucher = Voucher::by_id(req.id, &user).map_err(|_| ApiError::NotFound("voucher"))?;
+    let now = Utc::now();
+    let demand = pricing::demand(&conn, &user.id, voucher.id, now)?;
+    let quote = pricing::price(&user, &voucher, req.amount as u32, demand, now);
+    let cost = quote.total as i128;
+    if user.flux < cost {
+        return Err(ApiError::Insufficient {
+            currency: "flux",
+            need: cost,
+            have: user.flux,
+        });
+    }
+    if req.amount < 1 {
+        return Err(ApiError::Invalid("amount must be at least 1".into()));
+    }
+    if req.amount > 26 {
+        return Err(ApiError::Invalid("at most 26 vouchers per order".into()));
+    }
+    stock::check(&conn, &user.id, &voucher, req.amount as u32, now)?;
+
+    for _ in 0..req.amount as usize {
+        let mut bought = Voucher::new(
+            voucher.id,
+            uuid::Uuid::now_v7(),
+            voucher.name.clone(),
+            voucher.cost,
+            voucher.dur,
+            true,
+            voucher.description.clone(),
+            voucher.coeff.clone().into(),
+        );
+        bought.paid = Some(quote.unit);
+        bought.acquire(expiry::Source::Purchase);
+        user.vouchers.push(bought);
+    }
+
+    decrease_flux(&mut user, cost, LedgerSource::Purchase, None)?;
+    user.purchases.push(pricing::Purchase {
+        template: voucher.id,
+        amount: req.amount as u32,
+        at: now,
+    });
+
+    save_user(&user, &conn, &state)?;
+    Ok(Json(serde_json::json!({"status": "created"})))
+}
+
+#[derive(Deserialize, Serialize, Clone, PartialEq)]
+struct Daily {
+    id: u8,
+    claimable: bool,
+    claimed: bool,
+    last_claimed: i64,
+}
+impl Daily {
+    fn _init() -> Vec<Self> {
+        let mut vec: Vec<Self> = Vec::new();
+        for i in 0..5 {
+            vec.push(Daily {
+                id: i as u8,
+                claimable: true,
+                claimed: false,
+                last_claimed: 0 as i64,
+            });
+        }
+        vec[3].claimable = false;
+        vec[4].claimable = false;
+
+        vec
+    }
+    fn cycle(offset: u8) -> i64 {
+        Self::cycle_at(Utc::now(), offset).timestamp()
+    }
+    fn cycle_at(now: DateTime<Utc>, offset: u8) -> DateTime<Utc> {
+        let mut cycle_start = Utc
+            .with_ymd_and_hms(now.year(), now.month(), now.day(), 4 + offset as u32, 0, 0)
+            .unwrap();
+
+        if now < cycle_start {
+            cycle_start -= Duration::days(1);
+        }
+
+        cycle_start
+    }
+}
+#[derive(Deserialize)]
+struct DailiesReq {
+    info: bool,
+    id: u8,
+}
+#[derive(Serialize)]
+struct DailiesResp {
+    dailies: Vec<Daily>,
+    astrum: u16,
+    flux: u16,
+    astrai: u16,
+    vouchers: u16,
+}
+async fn dailies(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<DailiesReq>,
+) -> Result<Json<DailiesResp>, ApiError> {
+    let (mut user, conn) = load_user(userid.clone(), &state)?;
+    let economy = config::economy();
+    let mut rw = config::Reward::default();
+    let mut ok = false;
+
+    if req.info {
+        for daily in user.dailies.iter_mut() {
+            if daily.id == 4 {
+                if daily.last_claimed >= Daily::cycle(3) {
+                    daily.claimable = false;
+                    daily.claimed = true;
+                } else {
+                    daily.claimed = false;
+                }
+                break;
+            }
+
+            if daily.last_claimed >= Daily::cycle(0) {
+                daily.claimable = false;
+                daily.claimed = true;
+            } else {
+                daily.claimable = true;
+                daily.claimed = false;
+            }
+
+            if daily.id == 3 {
+                if user.todays_flux.1 >= economy.flux_threshold && !daily.claimed {
+                    daily.claimable = true;
+                } else {
+                    daily.claimable = false;
+                }
+            }
+        }
+
+        ok = true;
+    }
+    if (req.id < 4 && !req.info) && user.dailies[req.id as usize].last_claimed < Daily::cycle(0) {
+        user.dailies[req.id as usize].last_claimed = Utc::now().timestamp();
+        user.dailies[req.id as usize].claimed = true;
+        user.dailies[req.id as usize].claimable = false;
+
+        if req.id == 3 && user.todays_flux.1 < economy.flux_threshold {
+            return Err(ApiError::Forbidden(format!(
+                "spend {} flux today to claim this daily, spent {}",
+                economy.flux_threshold, user.todays_flux.1
+            )));
+        } else if req.id == 3 {
+            user.todays_flux.1 = 0;
+        }
+
+        if req.id == 0 {
+            let session = state.sessions.get(&user);
+            let mut bars = session.bars.lock().unwrap();
+            bars.iter_mut().for_each(|bar| {
+                bar.s_reduction = 0.0;
+                bar.s = (bar.s - (60.0 as f64)).max(0.0);
+                bar.tbase = (bar.tbase - (8.0 * 60.0) as f64).max(0.0);
+                bar.is_timing = false;
+                bar.overdrive = false;
+
+                if bar.id == 5 {
+                    bar.reset();
+                }
+            });
+            bars.iter_mut().filter(|f| f.locked).for_each(|bar| {
+                if bar.tbase <= 0.0 {
+                    bar.locked = false;
+                }
+            });
+
+            let bar = bars.get_mut(5).unwrap();
+            *bar = Bar::by_id(5);
+            let mut timer = session.timer.lock().unwrap();
+            *timer = Some(bar.start_idle(session.clone()));
+            drop((timer, bars));
+            session.checkpoint(&mut user);
+        }
+        rw.add(&economy.dailies.rewards[req.id as usize]);
+
+        let count = user.dailies.iter().filter(|f| f.claimed).count();
+        if count == 3 {
+            rw.add(&economy.dailies.three_claimed);
+        }
+        ok = true;
+    }
+    if req.id == 4 && user.dailies[4].claimable {
+        user.dailies[req.id as usize].claimable = false;
+        user.dailies[req.id as usize].claimed = true;
+        user.dailies[req.id as usize].last_claimed = Utc::now().timestamp();
+
+        rw.add(&economy.dailies.rewards[4]);
+
+        ok = true;
+    }
+
+    if req.id < 5 && user.dailies[req.id as usize].claimable {
+        if user.dailies.iter().all(|f| f.claimed) {
+            rw.add(&economy.dailies.all_claimed);
+        }
+        ok = true;
+    }
+
+    if ok {
+        user.adjust(
+            Currency::Astrum,
+            rw.astrum as i64,
+            LedgerSource::Daily,
+            None,
+        )?;
+        user.adjust(
+            Currency::Astrai,
+            rw.astrai as i64,
+            LedgerSource::Daily,
+            None,
+        )?;
+        user.adjust(Currency::Flux, rw.flux as i64, LedgerSource::Daily, None)?;
+        let _ = save_user(&user, &conn, &state);
+        return Ok(Json(DailiesResp {
+            dailies: user.dailies.clone(),
+            astrum: rw.astrum,
+            flux: rw.flux,
+            astrai: rw.astrai,
+            vouchers: rw.vouchers,
+        }));
+    }
+    Err(ApiError::Forbidden(format!(
+        "daily {} is not claimable",
+        req.id
+    )))
+}
+
+#[derive(Deserialize)]
+struct ISRDORequest {
+    description: String,
+    coeff: f32,
+}
+#[derive(Serialize)]
+struct ISRDOResponse {
+    description: String,
+    payout: u16,
+    uuid: uuid::Uuid,
+}
+async fn isrdo(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<ISRDORequest>,
+) -> Result<Json<ISRDOResponse>, ApiError> {
+    let (mut user, conn) = load_user(userid.clone(), &state)?;
+    let payout: u16 = (req.coeff.min(4.96) * 80.0 * 1.24) as u16;
+
+    if req.description.is_empty() {
+        return Err(ApiError::Invalid("description is required".into()));
+    }
+    if user.isrdos.len() == 8 {
+        return Err(ApiError::Forbidden("at most 8 isrdos can be open".into()));
+    }
+    if user.flux < 80 {
+        return Err(ApiError::Insufficient {
+            currency: "flux",
+            need: 80,
+            have: user.flux,
+        });
+    }
+
+    let isrdo = ISRDO {
+        description: req.description.clone(),
+        payout: payout,
+        uuid: uuid::Uuid::now_v7(),
+    };
+
+    user.isrdos.push(isrdo.clone());
+    decrease_flux(
+        &mut user,
+        80_i128,
+        LedgerSource::IsrdoStake,
+        Some(isrdo.uuid),
+    )?;
+    let _ = save_user(&user, &conn, &state);
+
+    Ok(Json(ISRDOResponse {
+        description: req.description,
+        uuid: isrdo.uuid,
+        payout: isrdo.payout,
+    }))
+}
+
+async fn get_isrdos(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+) -> Result<Json<Vec<ISRDO>>, ApiError> {
+    let (user, _conn) = load_user(userid, &state)?;
+    if user.isrdos.len() < 1 {
+        return Err(ApiError::NotFound("isrdo"));
+    }
+    Ok(Json(user.isrdos))
+}
+
+#[derive(Deserialize)]
+struct ISRDOCompleteReq {
+    uuid: uuid::Uuid,
+}
+async fn isrdo_complete(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<ISRDOCompleteReq>,
+) -> Result<Json<i128>, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+    let payout: i128;
+
+    if let Some(isrdo) = user.isrdos.iter_mut().find(|i| i.uuid == req.uuid) {
+        payout = isrdo.payout as i128;
+        user.isrdos.retain(|f| f.uuid != req.uuid);
+
+        user.adjust(
+            Currency::Flux,
+            payout as i64,
+            LedgerSource::IsrdoPayout,
+            Some(req.uuid),
+        )?;
+        let _ = save_user(&user, &conn, &state);
+
+        return Ok(Json(payout));
+    }
+
+    Err(ApiError::NotFound("isrdo"))
+}
+
+async fn seven_am_unlock(
+    State(state): State<AppState>,
+    key: ApiKey,
+) -> Result<StatusCode, ApiError> {
+    if key.scope != Scope::SevenAmUnlock {
+        return Err(ApiError::Forbidden(
+            "api key is not scoped for the 7am unlock".into(),
+        ));
+    }
+    let (mut user, conn) = load_user(key.owner.0, &state)?;
+
+    let daily = &mut user.dailies[4];
+
+    let current_time_utc = Utc::now();
+    let current_timestmp = current_time_utc.timestamp();
+
+    let cutoff_start = Utc
+        .with_ymd_and_hms(
+            current_time_utc.year(),
+            current_time_utc.month(),
+            current_time_utc.day(),
+            7,
+            0,
+            0,
+        )
+        .unwrap()
+        .timestamp();
+    let cutoff = Utc
+        .with_ymd_and_hms(
+            current_time_utc.year(),
+            current_time_utc.month(),
+            current_time_utc.day(),
+            7,
+            15,
+            0,
+        )
+        .unwrap()
+        .timestamp();
+
+    if daily.last_claimed < cutoff_start
+        && current_timestmp > cutoff_start
+        && current_timestmp < cutoff
+    {
+        daily.claimable = true;
+        daily.claimed = false;
+    } else {
+        return Err(ApiError::Forbidden(
+            "the 7am unlock is closed or already used today".into(),
+        ));
+    }
+
+    save_user(&user, &conn, &state)?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    userid: String,
+    username: String,
+    email: Option<String>,
+    password: String,
+}
+async fn register(
+    State(state): State<AppState>,
+    Json(req): Json<RegisterRequest>,
+) -> Result<(StatusCode, Json<serde_json::Value>), ApiError> {
+    if req.userid.trim().is_empty()
+        || req.username.trim().is_empty()
+        || req.password.len() < auth::PASSWORD_MIN
+    {
+        return Err(ApiError::Invalid(format!(
+            "userid and username are required, passwords need at least {} characters",
+            auth::PASSWORD_MIN
+        )));
+    }
+    let hash = auth::hash_password(&req.password)?;
+    let user = User::new(UserId(req.userid.clone()), req.username, req.email);
+
+    // Checked and written in one transaction, two registrations racing for
+    // the same id can't both get through.
+    let conn = state.repo.db.lock().unwrap();
+    let tx = conn.unchecked_transaction()?;
+    let taken: bool = tx.query_row(
+        "SELECT EXISTS (SELECT 1 FROM users WHERE id = ?1)",
+        params![user.id.0],
+        |row| row.get(0),
+    )?;
+    if taken {
+        return Err(ApiError::Conflict("user"));
+    }
+    repo::write(&tx, &user)?;
+    auth::add_password(&tx, &user.id, &hash)?;
+    let token = auth::issue_token(&tx, &user.id)?;
+    tx.commit()?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "userid": req.userid,
+            "username": user.username,
+            "token": token,
+        })),
+    ))
+}
+
+async fn get_user_info(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let (user, _conn) = load_user(userid, &state)?;
+
+    Ok(Json(serde_json::json!(
+        {
+            "astrum": user.astrum,
+            "astrai": user.astrai,
+            "flux": user.flux,
+            "dripstate": user.pause_drip,
+        }
+    )))
+}
+
+#[derive(Deserialize)]
+struct NewLogoRequest {
+    uuid: Uuid,
+}
+async fn remove_new_logo(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<NewLogoRequest>,
+) -> Result<Json<String>, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+
+    if let Some(voucher) = user.vouchers.iter_mut().find(|f| f.uuid == req.uuid) {
+        voucher.new = false;
+    }
+
+    save_user(&user, &conn, &state)?;
+
+    Ok(Json("Flipped".into()))
+}
+
+/*
+fn drip(b_state: AppState) {
+    tokio::spawn(async {
+        let state = b_state;
+        let mut faucet_failure = false;
+        let mut drip_rate: i128 = 0;
+
+        let mut interval = tokio::time::interval(Duration_Time::from_mins(30));
+        loop {
+            interval.tick().await;
+            let (mut user, conn) = load_user(get_username(), &state).unwrap();
+
+            if user.pause_drip {
+                continue;
+            }
+
+            if Utc::now().hour() >= 3 && Utc::now().hour() < 6 {
+                drip_rate = 0;
+                faucet_failure = false;
+            }
+
+            if faucet_failure {
+                drip_rate = drip_rate.max(160);
+            } else if drip_rate >= 160 {
+                faucet_failure = true;
+            }
+
+            if !user.active_timer {
+                if user.flux > drip_rate {
+                    user.flux -= drip_rate;
+                }
+                if drip_rate < 140 {
+                    drip_rate += 24;
+                }
+            } else if drip_rate >= 24 {
+                drip_rate -= 24
+            }
+
+            let _ = save_user(&user, &conn, &state);
+        }
+    });
+}*/
+
+async fn pause_dripper(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+) -> Result<StatusCode, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+
+    if user.pause_drip {
+        user.pause_drip = false;
+        save_user(&user, &conn, &state)?;
+        Ok(StatusCode::ACCEPTED)
+    } else {
+        user.pause_drip = true;
+        save_user(&user, &conn, &state)?;
+        Ok(StatusCode::OK)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+struct BarReq {
+    id: u8,
+    info: bool,
+}
+async fn bars(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<BarReq>,
+) -> Result<Json<Vec<Bar>>, ApiError> {
+    let (mut user, conn) = load_user(userid.clone(), &state)?;
+    let session = state.sessions.get(&user);
+    if req.info {
+        return Ok(Json(session.bars.lock().unwrap().clone()));
+    }
+    // Bar 5 is the idle bar, it only ever runs on its own.
+    if req.id >= 5 {
+        return Err(ApiError::Invalid(format!(
+            "bar {} can't be started by hand",
+            req.id
+        )));
+    }
+    if session.bars.lock().unwrap()[req.id as usize].locked {
+        return Err(BarError::Locked(req.id).into());
+    }
+
+    let mut timer_guard = session.timer.lock().map_err(|_| BarError::Busy)?;
+    let mut current_id = None;
+    if let Some(timer) = timer_guard.take() {
+        timer.abort();
+
+        let mut bars = session.bars.lock().unwrap();
+        current_id = bars.iter().find(|f| f.is_timing).map(|f| f.id);
+        bars.iter_mut().filter(|f| f.is_timing).for_each(|bar| {
+            bar.is_timing = false;
+        });
+    }
+
+    // Picking the running bar again stops it and drops back to idle.
+    if current_id == Some(req.id) {
+        *timer_guard = Some(Bar::by_id(5).start_idle(session.clone()));
+    } else {
+        *timer_guard = Some(Bar::by_id(req.id).start_timer(session.clone()));
+        user.active_bar = req.id;
+    }
+    drop(timer_guard);
+
+    session.checkpoint(&mut user);
+    save_user(&user, &conn, &state)?;
+
+    Ok(Json(user.bars))
+}
+
+fn load_config(args: &[String]) -> &'static config::Config {
+    match config::load(args) {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// The gacha-sim binary.
+pub fn simulate() {
+    let args: Vec<String> = std::env::args().collect();
+    load_config(&args);
+    sim::run(&args);
+}
+
+// The gacha binary: serves the API, or runs one of the maintenance flags.
+pub async fn serve() {
+    let args: Vec<String> = std::env::args().collect();
+    let config = load_config(&args);
+
+    if args.iter().any(|arg| arg == "--migrate-dry-run") {
+        let conn = Connection::open(&config.storage.db_path).expect("Error opening DB!");
+        match migrations::migrate(&conn, true) {
+            Ok(pending) if pending.is_empty() => println!("Schema is up to date"),
+            Ok(pending) => pending
+                .iter()
+                .for_each(|(version, name)| println!("Would apply {:03} {}", version, name)),
+            Err(e) => println!("Migration would fail: {}", e),
+        }
+        return;
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--set-password") {
+        let userid = args.get(pos + 1).expect("Usage: --set-password <userid>");
+        let mut password = String::new();
+        std::io::stdin()
+            .read_line(&mut password)
+            .expect("Error reading password!");
+        let password = password.trim_end_matches(['\r', '\n']);
+        if password.len() < auth::PASSWORD_MIN {
+            println!("Password needs at least {} characters", auth::PASSWORD_MIN);
+            return;
+        }
+
+        let repo = SqliteRepo::new(&config.storage.db_path);
+        let (user, conn) = repo.load(UserId(userid.clone())).expect("User not found!");
+        auth::set_password(&conn, &user.id, password).expect("Error setting password!");
+        println!("Password set for {}", userid);
+        return;
+    }
+
+    let repo = SqliteRepo::new(&config.storage.db_path);
+    let events = Events::new();
+    let state = AppState {
+        repo: repo.into(),
+        sessions: Arc::new(Sessions::new(events.clone())),
+        events,
+        rng: Arc::new(RngSource::new(config.fairness.commit_reveal)),
+        logins: Arc::default(),
+    };
+
+    for userid in state.repo.user_ids().expect("Error listing users!") {
+        let (mut user, conn) = load_user(userid, &state).unwrap();
+        // Bars that were running before the restart catch up and carry on.
+        if user.bar_clock.is_some() {
+            state.sessions.get(&user).checkpoint(&mut user);
+        }
+        user.pause_drip = true;
+        let _ = save_user(&user, &conn, &state);
+    }
+
+    ledger::reconcile_all(&state);
+    session::spawn_sweeper(state.clone());
+    expiry::spawn_sweeper(state.clone());
+    stock::spawn_restocker(state.clone());
+    //drip(state.clone());
+
+    let app = Router::new()
+        .route("/pull", post(handle_pull))
+        .route("/pull_multi", post(handle_pull_multi))
+        .route("/banners", post(banner::banners))
+        .route("/odds", post(pity::odds))
+        .route("/pity", post(pity::pity))
+        .route("/pull_history", post(history::pull_history))
+        .route("/seeds", get(rng::seeds))
+        .route("/verify_pulls", post(rng::verify_pulls))
+        .route("/ledger", post(ledger::ledger))
+        .route("/ledger_reconcile", post(ledger::ledger_reconcile))
+        .route("/get_user_vouchers", post(get_user_vouchers))
+        .route("/purchase", post(purchase))
+        .route("/quote", post(pricing::quote))
+        .route("/reprice", post(pricing::reprice))
+        .route("/get_bundles", post(bundle::get_bundles))
+        .route("/create_bundle", post(bundle::create_bundle))
+        .route("/update_bundle", post(bundle::update_bundle))
+        .route("/delete_bundle", post(bundle::delete_bundle))
+        .route("/buy_bundle", post(bundle::buy_bundle))
+        .route("/register", post(register))
+        .route("/login", post(auth::login))
+        .route("/logout", post(auth::logout))
+        .route("/revoke_tokens", post(auth::revoke_tokens))
+        .route("/create_api_key", post(auth::create_api_key))
+        .route("/get_api_keys", post(auth::get_api_keys))
+        .route("/revoke_api_key", post(auth::revoke_api_key))
+        .route("/user_funds_info", post(get_user_info))
+        .route("/consume", post(consume))
+        .route("/create", post(create))
+        .route("/dailies", post(dailies))
+        .route("/remove_new_logo", post(remove_new_logo))
+        .route("/delete_item", post(delete_item))
+        .route("/create_advanced", post(create_advanced))
+        .route("/isrdo", post(isrdo))
+        .route("/get_isrdos", post(get_isrdos))
+        .route("/isrdo_complete", post(isrdo_complete))
+        .route("/7am_unlock", get(seven_am_unlock))
+        .route("/pause_dripper", get(pause_dripper))
+        .route("/bars", post(bars))
+        .route("/events", get(events::events))
+        .route("/ws", get(ws::ws))
+        .layer(CorsLayer::permissive())
+        .with_state(state);
+    let listener = tokio::net::TcpListener::bind(&config.network.bind)
+        .await
+        .unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn puller() -> User {
+        let mut user = User::new(UserId("tester".into()), "tester".into(), None);
+        user.astrai = 1000;
+        user
+    }
+
+    #[test]
+    fn a_floored_pull_is_at_least_an_a() {
+        let banner = banner::by_id(banner::STANDARD).unwrap();
+        let rolled = |floor: bool| {
+            (0..200).map(move |seed| {
+                let mut rng = ChaCha20Rng::seed_from_u64(seed);
+                pull(&mut puller(), banner, floor, &mut rng).unwrap().rarity
+            })
+        };
+        assert!(rolled(false).any(|rarity| rarity == SerializedRarity::B));
+        assert!(rolled(true).all(|rarity| rarity != SerializedRarity::B));
+    }
+
+    #[test]
+    fn every_block_of_ten_holds_an_a() {
+        let banner = banner::by_id(banner::STANDARD).unwrap();
+        for seed in 0..20 {
+            let mut user = puller();
+            let mut stream = PullStream {
+                day: "2026-10-18".into(),
+                rng: ChaCha20Rng::seed_from_u64(seed),
+            };
+            let records = pull_many(&mut user, banner, 100, &mut stream).unwrap();
+            assert_eq!(records.len(), 100);
+            for block in records.chunks(10) {
+                assert!(block.iter().any(|r| r.rarity != SerializedRarity::B));
+            }
+            assert_eq!(user.pulls.len(), 100);
+        }
+    }
+
+    #[test]
+    fn remainders_drop_only_their_duration_tag() {
+        assert_eq!(untagged("Nap [1.5H]"), "Nap");
+        assert_eq!(untagged("Nap [1.5H] [20M]"), "Nap [1.5H]");
+        assert_eq!(untagged("Coffee (Premium)"), "Coffee (Premium)");
+        assert_eq!(untagged("[Draft] Nap"), "[Draft] Nap");
+    }
+}
@@ -0,0 +1,223 @@
+use gacha_protocol::Rarities;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::{self, Loot, LootDraw, RateUp};
+use crate::expiry::Source;
+use crate::ledger::{Currency, LedgerSource, Overdraft};
+use crate::{User, Voucher};
+
+// One thing a pull handed out, as listed in the pull response and history.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Granted {
+    Flux { amount: i64 },
+    Astrum { amount: i64 },
+    Astrai { amount: i64 },
+    Voucher { uuid: Uuid, id: u64, name: String },
+}
+impl Granted {
+    // Voucher uuids are fresh on every grant, so replays compare without them.
+    pub(crate) fn same_as(&self, other: &Granted) -> bool {
+        match (self, other) {
+            (Granted::Voucher { id, name, .. }, Granted::Voucher { id: i, name: n, .. }) => {
+                id == i && name == n
+            }
+            _ => self == other,
+        }
+    }
+}
+
+// A drop that has been rolled but not handed out yet.
+pub(crate) enum Drop {
+    Flux(i64),
+    Astrum(i64),
+    Astrai(i64),
+    Voucher(Voucher),
+}
+impl Drop {
+    pub(crate) fn granted(&self) -> Granted {
+        match self {
+            Drop::Flux(amount) => Granted::Flux { amount: *amount },
+            Drop::Astrum(amount) => Granted::Astrum { amount: *amount },
+            Drop::Astrai(amount) => Granted::Astrai { amount: *amount },
+            Drop::Voucher(voucher) => Granted::Voucher {
+                uuid: voucher.uuid,
+                id: voucher.id,
+                name: voucher.name.clone(),
+            },
+        }
+    }
+}
+
+pub(crate) fn table(outcome: &Rarities) -> &'static [LootDraw] {
+    let loot = &config::economy().loot;
+    match outcome {
+        Rarities::MythicSSS => &loot.mythic,
+        Rarities::S => &loot.s,
+        Rarities::A => &loot.a,
+        Rarities::B => &loot.b,
+    }
+}
+
+fn pick<'a>(draw: &'a LootDraw, rng: &mut impl Rng) -> Option<&'a Loot> {
+    let total = draw.nothing + draw.entries.iter().map(|e| e.weight).sum::<u32>();
+    let mut pick = rng.random_range(0..total);
+    draw.entries.iter().find_map(|entry| {
+        if pick < entry.weight {
+            return Some(&entry.loot);
+        }
+        pick -= entry.weight;
+        None
+    })
+}
+
+// Everything a pull of `outcome` hands out: the featured prize first, then
+// each draw of the rarity's loot table. Only reads the user, the bool is true
+// when a 50/50 guarantee was spent. Templates this account doesn't have are
+// skipped; pulls check for them up front.
+pub(crate) fn drops(
+    user: &User,
+    featured: Option<(&RateUp, &mut bool)>,
+    outcome: &Rarities,
+    rng: &mut impl Rng,
+) -> (Vec<Drop>, bool) {
+    let mut drops = Vec::new();
+    let mut used = false;
+
+    if let Some((voucher, spent)) =
+        featured.and_then(|(rate_up, guaranteed)| rate_up.draw(guaranteed, user, rng))
+    {
+        used = spent;
+        drops.push(Drop::Voucher(voucher));
+    }
+    for draw in table(outcome) {
+        let drop = match pick(draw, rng) {
+            Some(Loot::Flux(amount)) => Some(Drop::Flux(*amount)),
+            Some(Loot::Astrum(amount)) => Some(Drop::Astrum(*amount)),
+            Some(Loot::Astrai(amount)) => Some(Drop::Astrai(*amount)),
+            Some(Loot::Voucher(prize)) => prize.voucher(user).map(Drop::Voucher),
+            None => None,
+        };
+        drops.extend(drop);
+    }
+    (drops, used)
+}
+
+impl User {
+    pub(crate) fn grant(&mut self, drop: Drop) -> Result<Granted, Overdraft> {
+        let granted = drop.granted();
+        match drop {
+            Drop::Flux(amount) => {
+                self.adjust(Currency::Flux, amount, LedgerSource::PullReward, None)?;
+                self.total_flux_aq += amount as u128;
+            }
+            Drop::Astrum(amount) => {
+                self.adjust(Currency::Astrum, amount, LedgerSource::PullReward, None)?;
+                self.total_astrum_aq += amount as u128;
+            }
+            Drop::Astrai(amount) => {
+                self.adjust(Currency::Astrai, amount, LedgerSource::PullReward, None)?;
+            }
+            Drop::Voucher(mut voucher) => {
+                voucher.acquire(Source::Pull);
+                self.vouchers.push(voucher);
+            }
+        }
+        Ok(granted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{LootEntry, Prize};
+    use crate::{UserId, banner};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "tester".into(), None)
+    }
+
+    #[test]
+    fn draws_follow_their_weights() {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let draw = LootDraw {
+            nothing: 2,
+            entries: vec![
+                LootEntry {
+                    weight: 1,
+                    loot: Loot::Flux(10),
+                },
+                LootEntry {
+                    weight: 1,
+                    loot: Loot::Astrai(1),
+                },
+            ],
+        };
+        let (mut flux, mut astrai, mut nothing) = (0, 0, 0);
+        for _ in 0..4000 {
+            match pick(&draw, &mut rng) {
+                Some(Loot::Flux(10)) => flux += 1,
+                Some(Loot::Astrai(1)) => astrai += 1,
+                None => nothing += 1,
+                other => panic!("drew {:?}", other),
+            }
+        }
+        assert!((900..1100).contains(&flux), "{}", flux);
+        assert!((900..1100).contains(&astrai), "{}", astrai);
+        assert!((1900..2100).contains(&nothing), "{}", nothing);
+
+        let never = LootDraw {
+            nothing: 1,
+            entries: vec![LootEntry {
+                weight: 0,
+                loot: Loot::Flux(10),
+            }],
+        };
+        assert!((0..100).all(|_| pick(&never, &mut rng).is_none()));
+    }
+
+    #[test]
+    fn the_featured_prize_comes_before_the_table() {
+        let user = user();
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        let standard = banner::by_id(banner::STANDARD).unwrap();
+        let mut guaranteed = true;
+        let featured = standard
+            .rate_up(&Rarities::MythicSSS)
+            .map(|r| (r, &mut guaranteed));
+        let (mythic, spent) = drops(&user, featured, &Rarities::MythicSSS, &mut rng);
+        assert!(spent && !guaranteed);
+        let granted: Vec<_> = mythic.iter().map(Drop::granted).collect();
+        assert!(matches!(granted[0], Granted::Voucher { id: 999, .. }));
+        assert_eq!(granted[1..], [Granted::Flux { amount: 2400 }]);
+
+        // A B has no featured prize and always pays its flux.
+        let (b, spent) = drops(&user, None, &Rarities::B, &mut rng);
+        assert!(!spent);
+        assert_eq!(b[0].granted(), Granted::Flux { amount: 4 });
+    }
+
+    #[test]
+    fn grants_land_on_the_user() {
+        let mut user = user();
+        let flux = user.flux;
+        let voucher = Prize::Template(4).voucher(&user).unwrap();
+        let uuid = voucher.uuid;
+        user.grant(Drop::Flux(70)).unwrap();
+        let granted = user.grant(Drop::Voucher(voucher)).unwrap();
+
+        assert_eq!(user.flux, flux + 70);
+        assert_eq!(user.total_flux_aq, 70);
+        assert_eq!(user.ledger.len(), 1);
+        assert!(granted.same_as(&Granted::Voucher {
+            uuid: uuid::Uuid::nil(),
+            id: 4,
+            name: user.vouchers.last().unwrap().name.clone(),
+        }));
+        assert_eq!(user.vouchers.last().unwrap().uuid, uuid);
+    }
+}
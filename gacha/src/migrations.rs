@@ -0,0 +1,309 @@
+use chrono::Utc;
+use rusqlite::{Connection, params};
+use serde_json::{Value, json};
+
+use crate::{
+    Bar, Coeff, Daily, PersistenceError, auth, banner, bundle, expiry, ledger, pricing, repo, rng,
+    session,
+};
+
+enum Step {
+    Sql(&'static str),
+    Rust(fn(&Connection) -> Result<(), PersistenceError>),
+}
+struct Migration {
+    version: u32,
+    name: &'static str,
+    step: Step,
+}
+
+// Append only. A migration that has shipped is never edited or reordered,
+// schema changes always get a new version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "pull_history",
+        step: Step::Sql(
+            "CREATE TABLE IF NOT EXISTS pull_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_id TEXT NOT NULL,
+                pulled_at INTEGER NOT NULL,
+                rarity TEXT NOT NULL,
+                sss_pity INTEGER NOT NULL,
+                s_pity INTEGER NOT NULL,
+                a_pity INTEGER NOT NULL,
+                astrai_spent INTEGER NOT NULL,
+                astrum_spent INTEGER NOT NULL,
+                vouchers INTEGER NOT NULL,
+                slip_consumed INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS pull_history_user ON pull_history (user_id, pulled_at);",
+        ),
+    },
+    Migration {
+        version: 2,
+        name: "rename_users_json",
+        step: Step::Rust(repo::rename_legacy),
+    },
+    Migration {
+        version: 3,
+        name: "relational_users",
+        step: Step::Sql(repo::SCHEMA),
+    },
+    Migration {
+        version: 4,
+        name: "split_users_json",
+        step: Step::Rust(repo::split_legacy),
+    },
+    Migration {
+        version: 5,
+        name: "ledger",
+        step: Step::Rust(ledger::open_ledger),
+    },
+    Migration {
+        version: 6,
+        name: "auth",
+        step: Step::Sql(auth::SCHEMA),
+    },
+    Migration {
+        version: 7,
+        name: "bar_clocks",
+        step: Step::Sql(session::SCHEMA),
+    },
+    Migration {
+        version: 8,
+        name: "banners",
+        step: Step::Sql(banner::SCHEMA),
+    },
+    Migration {
+        version: 9,
+        name: "pull_items",
+        step: Step::Sql("ALTER TABLE pull_history ADD COLUMN items TEXT NOT NULL DEFAULT '[]';"),
+    },
+    Migration {
+        version: 10,
+        name: "rng",
+        step: Step::Sql(rng::SCHEMA),
+    },
+    Migration {
+        version: 11,
+        name: "voucher_expiry",
+        step: Step::Rust(expiry::add_columns),
+    },
+    Migration {
+        version: 12,
+        name: "purchases",
+        step: Step::Sql(pricing::SCHEMA),
+    },
+    Migration {
+        version: 13,
+        name: "bundles",
+        step: Step::Sql(bundle::SCHEMA),
+    },
+    Migration {
+        version: 14,
+        name: "voucher_paid",
+        step: Step::Rust(pricing::add_columns),
+    },
+    Migration {
+        version: 15,
+        name: "pull_floor",
+        step: Step::Sql("ALTER TABLE pull_history ADD COLUMN floored INTEGER NOT NULL DEFAULT 0;"),
+    },
+];
+
+// Runs every pending migration in one transaction. A dry run applies them the
+// same way and rolls back, so it also catches migrations that would fail.
+pub(crate) fn migrate(
+    conn: &Connection,
+    dry_run: bool,
+) -> Result<Vec<(u32, &'static str)>, PersistenceError> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    let current: u32 = tx.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )?;
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        match migration.step {
+            Step::Sql(sql) => tx.execute_batch(sql)?,
+            Step::Rust(step) => step(&tx)?,
+        }
+        tx.execute(
+            "INSERT INTO schema_version (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![migration.version, migration.name, Utc::now().timestamp()],
+        )?;
+        applied.push((migration.version, migration.name));
+    }
+
+    if dry_run {
+        tx.rollback()?;
+    } else {
+        tx.commit()?;
+        applied
+            .iter()
+            .for_each(|(version, name)| println!("Applied migration {:03} {}", version, name));
+    }
+    Ok(applied)
+}
+
+// Upgrades for the old `users.data` blobs. Each step fills in what a field
+// added to `User` needs to deserialize; `_version` in the blob records how
+// many of them already ran.
+const BLOB_MIGRATIONS: &[fn(&mut Value)] = &[voucher_coeff, user_defaults];
+
+pub(crate) fn upgrade_blob(blob: &mut Value) -> bool {
+    let version = blob.get("_version").and_then(Value::as_u64).unwrap_or(0) as usize;
+    if version >= BLOB_MIGRATIONS.len() || !blob.is_object() {
+        return false;
+    }
+
+    BLOB_MIGRATIONS[version..].iter().for_each(|step| step(blob));
+    blob["_version"] = json!(BLOB_MIGRATIONS.len());
+    true
+}
+
+fn voucher_coeff(blob: &mut Value) {
+    for key in ["vouchers", "templates"] {
+        if let Some(vouchers) = blob.get_mut(key).and_then(Value::as_array_mut) {
+            for voucher in vouchers.iter_mut().filter_map(Value::as_object_mut) {
+                voucher
+                    .entry("coeff")
+                    .or_insert_with(|| json!(Coeff::system()));
+            }
+        }
+    }
+}
+
+fn user_defaults(blob: &mut Value) {
+    let Some(user) = blob.as_object_mut() else {
+        return;
+    };
+
+    user.entry("email").or_insert(Value::Null);
+    user.entry("timer").or_insert(Value::Null);
+    user.entry("has_slip").or_insert(json!(false));
+    user.entry("active_timer").or_insert(json!(false));
+    user.entry("active_bar").or_insert(json!(0));
+    user.entry("pause_drip").or_insert(json!(false));
+    user.entry("timeout_map").or_insert(json!({}));
+    user.entry("isrdos").or_insert(json!([]));
+    user.entry("vouchers").or_insert(json!([]));
+    user.entry("templates").or_insert(json!([]));
+    user.entry("todays_flux").or_insert(json!([0, 0]));
+    user.entry("bars").or_insert_with(|| json!(Bar::_vec()));
+    for key in [
+        "sss_pity",
+        "s_pity",
+        "a_pity",
+        "total_pulls",
+        "total_flux_aq",
+        "total_astrum_aq",
+    ] {
+        user.entry(key).or_insert(json!(0));
+    }
+
+    // Older accounts were created before the 7am daily (id 4) existed.
+    let dailies = user.entry("dailies").or_insert(json!([]));
+    if let Some(dailies) = dailies.as_array_mut() {
+        for daily in Daily::_init() {
+            if !dailies.iter().any(|d| d["id"] == json!(daily.id)) {
+                dailies.push(json!(daily));
+            }
+        }
+        dailies.sort_by_key(|d| d["id"].as_u64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tables(conn: &Connection) -> Vec<String> {
+        conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table' ORDER BY name")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn a_dry_run_leaves_nothing_behind() {
+        let conn = Connection::open_in_memory().unwrap();
+        let applied = migrate(&conn, true).unwrap();
+        assert_eq!(applied.len(), MIGRATIONS.len());
+        assert!(tables(&conn).is_empty());
+
+        // The real run applies the same list and records it.
+        assert_eq!(migrate(&conn, false).unwrap(), applied);
+        let recorded: u32 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(recorded as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn a_second_run_does_nothing() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn, false).unwrap();
+        let before = tables(&conn);
+        assert!(migrate(&conn, false).unwrap().is_empty());
+        assert!(migrate(&conn, true).unwrap().is_empty());
+        assert_eq!(tables(&conn), before);
+    }
+
+    #[test]
+    fn versions_only_go_up() {
+        assert!(
+            MIGRATIONS
+                .windows(2)
+                .all(|w| w[1].version == w[0].version + 1)
+        );
+        assert_eq!(MIGRATIONS[0].version, 1);
+    }
+
+    #[test]
+    fn blobs_only_run_the_steps_they_missed() {
+        let mut old = json!({
+            "id": "tester",
+            "vouchers": [{ "id": 4 }],
+            "dailies": [{ "id": 0 }, { "id": 2 }, { "id": 1 }],
+        });
+        assert!(upgrade_blob(&mut old));
+        assert_eq!(old["_version"], BLOB_MIGRATIONS.len());
+        assert_eq!(old["vouchers"][0]["coeff"], json!(Coeff::system()));
+        assert_eq!(old["flux"], Value::Null);
+        assert_eq!(old["sss_pity"], 0);
+        // Missing dailies, the 7am one (id 4) among them, are filled in and
+        // kept in order.
+        let ids: Vec<_> = old["dailies"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| d["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        assert_eq!(old["dailies"][4]["claimable"], false);
+
+        // Past the coefficient step: vouchers are left as they are.
+        let mut halfway = json!({ "_version": 1, "vouchers": [{ "id": 4 }] });
+        assert!(upgrade_blob(&mut halfway));
+        assert_eq!(halfway["vouchers"][0].get("coeff"), None);
+        assert_eq!(halfway["dailies"].as_array().unwrap().len(), 5);
+
+        let mut current = json!({ "_version": BLOB_MIGRATIONS.len(), "vouchers": [] });
+        assert!(!upgrade_blob(&mut current));
+        assert_eq!(current.get("dailies"), None);
+    }
+}
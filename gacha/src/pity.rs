@@ -0,0 +1,337 @@
+use axum::Json;
+use axum::extract::State;
+use gacha_protocol::{PityCtx, Rarities, roll as protocol_roll};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthUser;
+use crate::banner::{self, BannerPity};
+use crate::config::{self, Curve, Curves};
+use crate::error::ApiError;
+use crate::history::RarityCounts;
+use crate::{AppState, SqliteRepo, UserId, load_user};
+
+// gacha_protocol doesn't expose its rates, so without curves the odds are
+// measured by rolling it this many times.
+const SAMPLES: u32 = 20_000;
+
+impl From<&BannerPity> for PityCtx {
+    fn from(pity: &BannerPity) -> Self {
+        PityCtx {
+            sss_pity: pity.sss_pity,
+            s_pity: pity.s_pity,
+            a_pity: pity.a_pity,
+        }
+    }
+}
+
+impl Curve {
+    // The chance on the `pulls`th pull since this rarity last dropped.
+    pub(crate) fn rate(&self, pulls: u16) -> f64 {
+        if self.hard.is_some_and(|hard| pulls >= hard) {
+            return 1.0;
+        }
+        let soft = self
+            .soft_start
+            .map_or(0, |start| pulls.saturating_sub(start));
+        (self.base + soft as f64 * self.soft_step).min(1.0)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Odds {
+    pub(crate) mythic: f64,
+    pub(crate) s: f64,
+    pub(crate) a: f64,
+    pub(crate) b: f64,
+}
+impl Odds {
+    // Higher rarities come first, each lower one gets at most what is left.
+    fn from_rates(mythic: f64, s: f64, a: f64) -> Self {
+        let s = s.min(1.0 - mythic);
+        let a = a.min(1.0 - mythic - s);
+        Self {
+            mythic,
+            s,
+            a,
+            b: 1.0 - mythic - s - a,
+        }
+    }
+    fn pick(&self, r: f64) -> Rarities {
+        if r < self.mythic {
+            Rarities::MythicSSS
+        } else if r < self.mythic + self.s {
+            Rarities::S
+        } else if r < self.mythic + self.s + self.a {
+            Rarities::A
+        } else {
+            Rarities::B
+        }
+    }
+}
+
+fn curve_odds(curves: &Curves, pity: &BannerPity) -> Odds {
+    Odds::from_rates(
+        curves.mythic.rate(pity.sss_pity + 1),
+        curves.s.rate(pity.s_pity + 1),
+        curves.a.rate(pity.a_pity + 1),
+    )
+}
+
+// One draw from `rng` per pull, so curve rolls replay like the rest of it.
+pub(crate) fn curve_roll(curves: &Curves, pity: &BannerPity, rng: &mut impl Rng) -> Rarities {
+    curve_odds(curves, pity).pick(rng.random())
+}
+
+// gacha_protocol draws from its own thread rng.
+pub(crate) fn roll(pity: &BannerPity, rng: &mut impl Rng) -> Rarities {
+    match &config::pity().curves {
+        Some(curves) => curve_roll(curves, pity, rng),
+        None => protocol_roll(&PityCtx::from(pity)),
+    }
+}
+
+fn rank(rarity: &Rarities) -> usize {
+    match rarity {
+        Rarities::MythicSSS => 0,
+        Rarities::S => 1,
+        Rarities::A => 2,
+        Rarities::B => 3,
+    }
+}
+
+// One number per rarity with a pity counter.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct PerRarity {
+    pub(crate) mythic: Option<u16>,
+    pub(crate) s: Option<u16>,
+    pub(crate) a: Option<u16>,
+}
+
+// The pull since the last drop that is certain to be that rarity (or better).
+// gacha_protocol doesn't tell, so without curves none is known.
+pub(crate) fn hard_pity() -> PerRarity {
+    match &config::pity().curves {
+        Some(curves) => PerRarity {
+            mythic: curves.mythic.hard,
+            s: curves.s.hard,
+            a: curves.a.hard,
+        },
+        None => PerRarity::default(),
+    }
+}
+
+// The chances of the next pull, from whatever rolls it. Exact with curves,
+// measured from gacha_protocol otherwise, so never call it holding the db.
+pub(crate) fn next_odds(pity: &BannerPity) -> (Odds, bool) {
+    if let Some(curves) = &config::pity().curves {
+        return (curve_odds(curves, pity), true);
+    }
+    let ctx = PityCtx::from(pity);
+    let mut counts = [0u32; 4];
+    for _ in 0..SAMPLES {
+        counts[rank(&protocol_roll(&ctx))] += 1;
+    }
+    let share = |n: u32| n as f64 / SAMPLES as f64;
+    let odds = Odds {
+        mythic: share(counts[0]),
+        s: share(counts[1]),
+        a: share(counts[2]),
+        b: share(counts[3]),
+    };
+    (odds, false)
+}
+
+// Counters move after a pull: the rarity that dropped starts over, the others
+// count one more, or start over too with `resets_lower` when they are below it.
+pub(crate) fn advance(pity: &mut BannerPity, outcome: &Rarities) {
+    let resets_lower = config::pity().resets_lower;
+    let bump = |counter: &mut u16, reset: bool| {
+        *counter = if reset { 0 } else { *counter + 1 };
+    };
+    match outcome {
+        Rarities::MythicSSS => {
+            pity.sss_pity = 0;
+            bump(&mut pity.s_pity, resets_lower);
+            bump(&mut pity.a_pity, resets_lower);
+        }
+        Rarities::S => {
+            pity.s_pity = 0;
+            bump(&mut pity.sss_pity, false);
+            bump(&mut pity.a_pity, resets_lower);
+        }
+        Rarities::A => {
+            pity.a_pity = 0;
+            bump(&mut pity.sss_pity, false);
+            bump(&mut pity.s_pity, false);
+        }
+        Rarities::B => {
+            bump(&mut pity.sss_pity, false);
+            bump(&mut pity.s_pity, false);
+            bump(&mut pity.a_pity, false);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct OddsRequest {
+    banner_id: Option<String>,
+}
+#[derive(Serialize)]
+pub(crate) struct OddsResponse {
+    banner: String,
+    pity: BannerPity,
+    odds: Odds,
+    // False when the odds were sampled from gacha_protocol.
+    exact: bool,
+}
+// What the next single pull on a banner can give. Like /pull, no body means
+// the standard banner.
+pub(crate) async fn odds(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    req: Option<Json<OddsRequest>>,
+) -> Result<Json<OddsResponse>, ApiError> {
+    let banner_id = req.and_then(|Json(req)| req.banner_id);
+    let banner = banner::find(banner_id.as_deref())?;
+    let pity = load_user(userid, &state)?.0.pity(&banner.id);
+    let (odds, exact) = next_odds(&pity);
+
+    Ok(Json(OddsResponse {
+        banner: banner.id.clone(),
+        pity,
+        odds,
+        exact,
+    }))
+}
+
+#[derive(Serialize)]
+pub(crate) struct Lifetime {
+    total_pulls: u128,
+    #[serde(flatten)]
+    counts: RarityCounts,
+    pulls_per_mythic: Option<f64>,
+    pulls_per_s: Option<f64>,
+}
+#[derive(Serialize)]
+pub(crate) struct PityResponse {
+    banner: String,
+    pity: BannerPity,
+    hard: PerRarity,
+    // Pulls left until the certain one, counting it.
+    to_hard: PerRarity,
+    odds: Odds,
+    // False when the odds were sampled from gacha_protocol.
+    exact: bool,
+    lifetime: Lifetime,
+}
+// Everything the UI shows about pity on a banner, the standard one when no
+// body is sent. Odds are the ones /odds gives, hard pity is only known with
+// curves. Lifetime numbers count pulls on every banner.
+pub(crate) async fn pity(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    req: Option<Json<OddsRequest>>,
+) -> Result<Json<PityResponse>, ApiError> {
+    let banner_id = req.and_then(|Json(req)| req.banner_id);
+    let banner = banner::find(banner_id.as_deref())?;
+    let (user, counts) = {
+        let (user, conn) = load_user(userid, &state)?;
+        let counts = SqliteRepo::rarity_counts(&conn, &user.id)?;
+        (user, counts)
+    };
+    let pity = user.pity(&banner.id);
+    let (odds, exact) = next_odds(&pity);
+    let hard = hard_pity();
+
+    let logged = counts.mythic + counts.s + counts.a + counts.b;
+    let per = |n: u64| (n > 0).then(|| logged as f64 / n as f64);
+    Ok(Json(PityResponse {
+        banner: banner.id.clone(),
+        pity,
+        hard,
+        to_hard: PerRarity {
+            mythic: hard.mythic.map(|h| h.saturating_sub(pity.sss_pity)),
+            s: hard.s.map(|h| h.saturating_sub(pity.s_pity)),
+            a: hard.a.map(|h| h.saturating_sub(pity.a_pity)),
+        },
+        odds,
+        exact,
+        lifetime: Lifetime {
+            total_pulls: user.total_pulls,
+            pulls_per_mythic: per(counts.mythic),
+            pulls_per_s: per(counts.s),
+            counts,
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(base: f64, soft_start: Option<u16>, soft_step: f64, hard: Option<u16>) -> Curve {
+        Curve {
+            base,
+            soft_start,
+            soft_step,
+            hard,
+        }
+    }
+
+    #[test]
+    fn soft_pity_climbs_linearly_until_hard_pity() {
+        let mythic = curve(0.006, Some(73), 0.06, Some(90));
+        assert_eq!(mythic.rate(1), 0.006);
+        assert_eq!(mythic.rate(73), 0.006);
+        assert!((mythic.rate(75) - 0.126).abs() < 1e-9);
+        assert!((mythic.rate(89) - 0.966).abs() < 1e-9);
+        assert_eq!(mythic.rate(90), 1.0);
+        assert_eq!(curve(0.5, Some(1), 0.5, None).rate(10), 1.0);
+    }
+
+    #[test]
+    fn higher_rarities_take_precedence() {
+        let curves = Curves {
+            mythic: curve(0.01, None, 0.0, Some(90)),
+            s: curve(0.05, None, 0.0, Some(10)),
+            a: curve(0.2, None, 0.0, None),
+        };
+        let odds = curve_odds(&curves, &BannerPity::default());
+        assert!((odds.b - 0.74).abs() < 1e-9);
+
+        let pity = BannerPity {
+            sss_pity: 89,
+            s_pity: 9,
+            ..BannerPity::default()
+        };
+        let odds = curve_odds(&curves, &pity);
+        assert_eq!((odds.mythic, odds.s, odds.a, odds.b), (1.0, 0.0, 0.0, 0.0));
+        assert!(matches!(odds.pick(0.999), Rarities::MythicSSS));
+    }
+
+    #[test]
+    fn without_curves_the_protocol_gives_the_odds() {
+        let (odds, exact) = next_odds(&BannerPity::default());
+        assert!(!exact);
+        assert!((odds.mythic + odds.s + odds.a + odds.b - 1.0).abs() < 1e-9);
+        assert!(odds.b > 0.0);
+        assert_eq!(hard_pity(), PerRarity::default());
+    }
+
+    #[test]
+    fn counters_move_after_a_pull() {
+        let mut pity = BannerPity {
+            sss_pity: 10,
+            s_pity: 4,
+            a_pity: 2,
+            ..BannerPity::default()
+        };
+        advance(&mut pity, &Rarities::S);
+        assert_eq!((pity.sss_pity, pity.s_pity, pity.a_pity), (11, 0, 3));
+        advance(&mut pity, &Rarities::B);
+        assert_eq!((pity.sss_pity, pity.s_pity, pity.a_pity), (12, 1, 4));
+        advance(&mut pity, &Rarities::MythicSSS);
+        assert_eq!((pity.sss_pity, pity.s_pity, pity.a_pity), (0, 2, 5));
+    }
+}
@@ -0,0 +1,359 @@
+use axum::Json;
+use axum::extract::State;
+use chrono::{DateTime, Timelike, Utc};
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::AuthUser;
+use crate::config::{self, Surcharge};
+use crate::error::ApiError;
+use crate::expiry::{self, VoucherInfo};
+use crate::stock::{self, Stock};
+use crate::{
+    AppState, Coeff, PersistenceError, PurchaseRequest, User, UserId, Voucher, load_user, repo,
+    save_user,
+};
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS purchases (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL REFERENCES users (id),
+    template_id INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    bought_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS purchases_template ON purchases (user_id, template_id, bought_at);
+";
+
+// What a voucher was bought for, None for the ones from before it was kept.
+// Templates get the column too.
+pub(crate) fn add_columns(conn: &Connection) -> Result<(), PersistenceError> {
+    repo::add_voucher_columns(conn, &[("paid", "INTEGER")])
+}
+
+// The built-in specials (full day off, coffee, mythic week) keep the price
+// they were given.
+pub(crate) fn fixed_price(id: u64) -> bool {
+    matches!(id, 1 | 4 | 999)
+}
+
+// What `minutes` of a coefficient cost before any modifier.
+pub(crate) fn base_cost(coeff: &Coeff, minutes: f64) -> u64 {
+    (coeff.get_val() as f64 * minutes / 60.0).round() as u64
+}
+
+fn in_window(surcharge: &Surcharge, hour: u32) -> bool {
+    if surcharge.from_hour <= surcharge.to_hour {
+        (surcharge.from_hour..surcharge.to_hour).contains(&hour)
+    } else {
+        hour >= surcharge.from_hour || hour < surcharge.to_hour
+    }
+}
+
+// Vouchers of a template bought by /purchase, /create_advanced or in a
+// bundle, what demand and stock are worked out from. Queued on the user like
+// ledger entries and written by the same save as the vouchers.
+#[derive(Debug, Clone)]
+pub(crate) struct Purchase {
+    pub(crate) template: u64,
+    pub(crate) amount: u32,
+    pub(crate) at: DateTime<Utc>,
+}
+
+pub(crate) fn write_purchases(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let mut stmt = conn.prepare(
+        "INSERT INTO purchases (user_id, template_id, amount, bought_at)
+        VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for purchase in user.purchases.iter() {
+        stmt.execute(params![
+            user.id.0,
+            purchase.template as i64,
+            purchase.amount,
+            purchase.at.timestamp()
+        ])?;
+    }
+    Ok(())
+}
+
+// How much pricier recent buying has made a template, 1.0 when it hasn't.
+pub(crate) fn demand(
+    conn: &Connection,
+    user: &UserId,
+    template_id: u64,
+    now: DateTime<Utc>,
+) -> Result<f64, PersistenceError> {
+    let demand = &config::economy().pricing.demand;
+    if demand.step <= 0.0 {
+        return Ok(1.0);
+    }
+    let window = demand.window_hours * 3600.0;
+    let bought: Vec<(u32, i64)> = conn
+        .prepare(
+            "SELECT amount, bought_at FROM purchases
+            WHERE user_id = ?1 AND template_id = ?2 AND bought_at > ?3",
+        )?
+        .query_map(
+            params![user.0, template_id as i64, now.timestamp() - window as i64],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?
+        .collect::<Result<_, _>>()?;
+    Ok(heat(&bought, demand.step, window, now).min(demand.max))
+}
+
+fn heat(bought: &[(u32, i64)], step: f64, window: f64, now: DateTime<Utc>) -> f64 {
+    let fading: f64 = bought
+        .iter()
+        .map(|(amount, at)| {
+            let age = (now.timestamp() - at) as f64;
+            *amount as f64 * (1.0 - age / window).max(0.0)
+        })
+        .sum();
+    1.0 + step * fading
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ModifierKind {
+    Bulk,
+    Surcharge,
+    Scarcity,
+    Demand,
+}
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Modifier {
+    kind: ModifierKind,
+    multiplier: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct Quote {
+    id: u64,
+    name: String,
+    hours: f64,
+    amount: u32,
+    // One voucher before modifiers.
+    base: u64,
+    modifiers: Vec<Modifier>,
+    // One voucher after them.
+    pub(crate) unit: u64,
+    pub(crate) total: u64,
+}
+
+// What `amount` of `voucher` cost `user` at `now`, `demand` coming from
+// `demand()`. Scarcity and demand count what came before the order, every
+// voucher in it costs the same.
+pub(crate) fn price(
+    user: &User,
+    voucher: &Voucher,
+    amount: u32,
+    demand: f64,
+    now: DateTime<Utc>,
+) -> Quote {
+    let pricing = &config::economy().pricing;
+    let mut modifiers = Vec::new();
+
+    let tier = pricing
+        .bulk
+        .iter()
+        .filter(|tier| amount >= tier.min_amount)
+        .max_by_key(|tier| tier.min_amount);
+    if let Some(tier) = tier {
+        modifiers.push(Modifier {
+            kind: ModifierKind::Bulk,
+            multiplier: 1.0 - tier.discount,
+        });
+    }
+    for surcharge in pricing.surcharges.iter() {
+        if in_window(surcharge, now.hour()) {
+            modifiers.push(Modifier {
+                kind: ModifierKind::Surcharge,
+                multiplier: surcharge.multiplier,
+            });
+        }
+    }
+    let held = user
+        .vouchers
+        .iter()
+        .filter(|v| v.id == voucher.id && !v.expired(now))
+        .count();
+    if held > 0 && pricing.scarcity.step > 0.0 {
+        modifiers.push(Modifier {
+            kind: ModifierKind::Scarcity,
+            multiplier: (1.0 + pricing.scarcity.step * held as f64).min(pricing.scarcity.max),
+        });
+    }
+    if demand > 1.0 {
+        modifiers.push(Modifier {
+            kind: ModifierKind::Demand,
+            multiplier: demand,
+        });
+    }
+
+    let multiplier: f64 = modifiers.iter().map(|m| m.multiplier).product();
+    let unit = (voucher.cost as f64 * multiplier).round() as u64;
+    Quote {
+        id: voucher.id,
+        name: voucher.name.clone(),
+        hours: voucher.hours(),
+        amount,
+        base: voucher.cost,
+        modifiers,
+        unit,
+        total: unit * amount as u64,
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct QuoteRequest {
+    id: u64,
+    #[serde(default = "one")]
+    amount: u8,
+    // Hours, priced like /purchase. Without it the template is quoted as it
+    // is, like /create_advanced.
+    dur: Option<f64>,
+}
+fn one() -> u8 {
+    1
+}
+// What buying would cost right now, without buying.
+pub(crate) async fn quote(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<QuoteRequest>,
+) -> Result<Json<Quote>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    if req.amount < 1 {
+        return Err(ApiError::Invalid("amount must be at least 1".into()));
+    }
+    let template = Voucher::by_id(req.id, &user).map_err(|_| ApiError::NotFound("voucher"))?;
+    let voucher = match req.dur {
+        Some(dur) => Voucher::from_purchase_req(
+            PurchaseRequest {
+                amount: req.amount,
+                id: req.id,
+                dur,
+            },
+            &user,
+        )?,
+        None => template,
+    };
+    let now = Utc::now();
+    let demand = demand(&conn, &user.id, voucher.id, now)?;
+    Ok(Json(price(&user, &voucher, req.amount as u32, demand, now)))
+}
+
+#[derive(Serialize)]
+pub(crate) struct StoreInfo {
+    // The demand multiplier alone, `price` has every modifier in it.
+    demand: f64,
+    price: u64,
+    #[serde(flatten)]
+    stock: Stock,
+}
+// The store as /get_user_vouchers lists it, with what one of each costs now
+// and how many are left.
+pub(crate) fn store(user: &User, conn: &Connection) -> Result<Vec<VoucherInfo>, PersistenceError> {
+    let now = Utc::now();
+    let mut listed = expiry::listing(user.templates.clone());
+    for info in listed.iter_mut() {
+        let demand = demand(conn, &user.id, info.voucher.id, now)?;
+        info.store = Some(StoreInfo {
+            demand,
+            price: price(user, &info.voucher, 1, demand, now).unit,
+            stock: stock::stock(conn, &user.id, info.voucher.id, now)?,
+        });
+    }
+    Ok(listed)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RepriceRequest {
+    id: Option<u64>,
+}
+#[derive(Serialize)]
+pub(crate) struct Repriced {
+    id: u64,
+    name: String,
+    old: u64,
+    new: u64,
+}
+// Recomputes template costs from their coefficient and duration, all of them
+// or just `id`. Lists the ones that changed.
+pub(crate) async fn reprice(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<RepriceRequest>,
+) -> Result<Json<Vec<Repriced>>, ApiError> {
+    let (mut user, conn) = load_user(userid, &state)?;
+    if req
+        .id
+        .is_some_and(|id| !user.templates.iter().any(|t| t.id == id))
+    {
+        return Err(ApiError::NotFound("voucher"));
+    }
+
+    let mut repriced = Vec::new();
+    for template in user.templates.iter_mut() {
+        // Nothing to price without a duration, like the older templates that
+        // were saved without one.
+        if fixed_price(template.id)
+            || template.dur <= 0.0
+            || req.id.is_some_and(|id| id != template.id)
+        {
+            continue;
+        }
+        let cost = base_cost(&template.coeff, template.minutes());
+        if cost != template.cost {
+            repriced.push(Repriced {
+                id: template.id,
+                name: template.name.clone(),
+                old: template.cost,
+                new: cost,
+            });
+            template.cost = cost;
+        }
+    }
+    if !repriced.is_empty() {
+        save_user(&user, &conn, &state)?;
+    }
+    Ok(Json(repriced))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn coefficients_set_the_hourly_rate() {
+        assert_eq!(base_cost(&Coeff::pure_c(), 60.0), 206);
+        assert_eq!(base_cost(&Coeff::pure_c(), 90.0), 309);
+        assert_eq!(base_cost(&Coeff::g(), 120.0), 296);
+        assert_eq!(base_cost(&Coeff::base(), 30.0), 60);
+    }
+
+    #[test]
+    fn demand_fades_over_the_window() {
+        let now = Utc::now();
+        let ago = |hours: i64| now.timestamp() - hours * 3600;
+        let window = 100.0 * 3600.0;
+        assert_eq!(heat(&[], 0.1, window, now), 1.0);
+        assert!((heat(&[(2, ago(0))], 0.1, window, now) - 1.2).abs() < 1e-9);
+        assert!((heat(&[(1, ago(50)), (1, ago(75))], 0.1, window, now) - 1.075).abs() < 1e-9);
+        assert_eq!(heat(&[(4, ago(100))], 0.1, window, now), 1.0);
+    }
+
+    #[test]
+    fn surcharge_windows_wrap_past_midnight() {
+        let window = |from_hour, to_hour| Surcharge {
+            from_hour,
+            to_hour,
+            multiplier: 1.5,
+        };
+        assert!(in_window(&window(18, 22), 18));
+        assert!(!in_window(&window(18, 22), 22));
+        assert!(in_window(&window(22, 6), 23));
+        assert!(in_window(&window(22, 6), 3));
+        assert!(!in_window(&window(22, 6), 12));
+    }
+}
@@ -0,0 +1,870 @@
+use chrono::{DateTime, Utc};
+use rusqlite::types::Type;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::sync::MutexGuard;
+use uuid::Uuid;
+
+use crate::{
+    Bar, Coeff, Daily, ISRDO, PersistenceError, SqliteRepo, Timer, User, UserId, UserRepo, Voucher,
+    banner, bundle, history, ledger, migrations, pricing, rng, session,
+};
+
+pub(crate) const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS users (
+        id TEXT PRIMARY KEY,
+        username TEXT NOT NULL,
+        email TEXT,
+        has_slip INTEGER NOT NULL DEFAULT 0,
+        active_timer INTEGER NOT NULL DEFAULT 0,
+        active_bar INTEGER NOT NULL DEFAULT 0,
+        pause_drip INTEGER NOT NULL DEFAULT 0,
+        timer TEXT,
+        timeout_map TEXT NOT NULL DEFAULT '{}',
+        sss_pity INTEGER NOT NULL DEFAULT 0,
+        s_pity INTEGER NOT NULL DEFAULT 0,
+        a_pity INTEGER NOT NULL DEFAULT 0,
+        total_pulls INTEGER NOT NULL DEFAULT 0,
+        todays_flux_at INTEGER NOT NULL DEFAULT 0,
+        todays_flux INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS wallets (
+        user_id TEXT PRIMARY KEY REFERENCES users (id),
+        astrai INTEGER NOT NULL DEFAULT 0,
+        astrum INTEGER NOT NULL DEFAULT 0,
+        flux INTEGER NOT NULL DEFAULT 0,
+        total_flux_aq INTEGER NOT NULL DEFAULT 0,
+        total_astrum_aq INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE IF NOT EXISTS vouchers (
+        user_id TEXT NOT NULL REFERENCES users (id),
+        uuid TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        cost INTEGER NOT NULL,
+        dur REAL NOT NULL DEFAULT 0,
+        new INTEGER NOT NULL,
+        description TEXT NOT NULL,
+        coeff TEXT NOT NULL,
+        PRIMARY KEY (user_id, uuid)
+    );
+    CREATE TABLE IF NOT EXISTS templates (
+        user_id TEXT NOT NULL REFERENCES users (id),
+        uuid TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        cost INTEGER NOT NULL,
+        dur REAL NOT NULL DEFAULT 0,
+        new INTEGER NOT NULL,
+        description TEXT NOT NULL,
+        coeff TEXT NOT NULL,
+        PRIMARY KEY (user_id, uuid)
+    );
+    CREATE TABLE IF NOT EXISTS dailies (
+        user_id TEXT NOT NULL REFERENCES users (id),
+        id INTEGER NOT NULL,
+        claimable INTEGER NOT NULL,
+        claimed INTEGER NOT NULL,
+        last_claimed INTEGER NOT NULL,
+        PRIMARY KEY (user_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS isrdos (
+        user_id TEXT NOT NULL REFERENCES users (id),
+        uuid TEXT NOT NULL,
+        description TEXT NOT NULL,
+        payout INTEGER NOT NULL,
+        PRIMARY KEY (user_id, uuid)
+    );
+    CREATE TABLE IF NOT EXISTS bars (
+        user_id TEXT NOT NULL REFERENCES users (id),
+        id INTEGER NOT NULL,
+        locked INTEGER NOT NULL,
+        is_timing INTEGER NOT NULL,
+        overdrive INTEGER NOT NULL,
+        c REAL NOT NULL,
+        s REAL NOT NULL,
+        smax REAL NOT NULL,
+        tmax REAL NOT NULL,
+        tbase REAL NOT NULL,
+        s_reduction REAL NOT NULL,
+        overdrive_val REAL NOT NULL,
+        PRIMARY KEY (user_id, id)
+    );";
+
+// Databases from before the split keep every user as one JSON blob in
+// `users.data`. The blob table is renamed to `users_json` and left in place as
+// a backup.
+pub(crate) fn rename_legacy(conn: &Connection) -> Result<(), PersistenceError> {
+    let has_blob: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'data'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_blob {
+        conn.execute("ALTER TABLE users RENAME TO users_json", [])?;
+    }
+    Ok(())
+}
+
+// Blobs are brought up to date by the JSON migrations first. One that still
+// fails to deserialize fails the migration, which rolls all of it back rather
+// than leave an account without rows to load from. Users are written
+// with the columns the tables had in migration 3, later migrations add and
+// fill in their own.
+pub(crate) fn split_legacy(conn: &Connection) -> Result<(), PersistenceError> {
+    let has_legacy: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type = 'table' AND name = 'users_json'",
+        [],
+        |row| row.get(0),
+    )?;
+    if !has_legacy {
+        return Ok(());
+    }
+
+    let rows: Vec<(String, String)> = conn
+        .prepare(
+            "SELECT id, data FROM users_json WHERE id NOT IN (SELECT id FROM users) ORDER BY id",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>()?;
+
+    for (id, data) in rows {
+        let mut blob: serde_json::Value = serde_json::from_str(&data)?;
+        if migrations::upgrade_blob(&mut blob) {
+            conn.execute(
+                "UPDATE users_json SET data = ?1 WHERE id = ?2",
+                params![serde_json::to_string_pretty(&blob)?, id],
+            )?;
+        }
+
+        let user: User = serde_json::from_value(blob)
+            .inspect_err(|e| println!("Legacy user {} can't be read: {}", id, e))?;
+        insert_legacy(conn, &user)?;
+    }
+    Ok(())
+}
+
+fn insert_legacy(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let id = &user.id;
+    conn.execute(
+        "INSERT INTO users (id, username, email, has_slip, active_timer, active_bar,
+        pause_drip, timer, timeout_map, sss_pity, s_pity, a_pity, total_pulls,
+        todays_flux_at, todays_flux)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)",
+        params![
+            id.0,
+            user.username,
+            user.email,
+            user.has_slip,
+            user.active_timer,
+            user.active_bar,
+            user.pause_drip,
+            user.timer
+                .as_ref()
+                .map(|t| serde_json::to_string(t).unwrap_or_default()),
+            serde_json::to_string(&user.timeout_map)?,
+            user.sss_pity,
+            user.s_pity,
+            user.a_pity,
+            user.total_pulls as i64,
+            user.todays_flux.0,
+            user.todays_flux.1 as i64,
+        ],
+    )?;
+    conn.execute(
+        "INSERT INTO wallets (user_id, astrai, astrum, flux, total_flux_aq, total_astrum_aq)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            id.0,
+            user.astrai as i64,
+            user.astrum as i64,
+            user.flux as i64,
+            user.total_flux_aq as i64,
+            user.total_astrum_aq as i64,
+        ],
+    )?;
+    for (table, vouchers) in [("vouchers", &user.vouchers), ("templates", &user.templates)] {
+        let mut insert = conn.prepare(&format!(
+            "INSERT INTO {} (user_id, uuid, id, name, cost, dur, new, description, coeff)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            table
+        ))?;
+        for v in vouchers.iter() {
+            insert.execute(params![
+                id.0,
+                v.uuid.to_string(),
+                v.id as i64,
+                v.name,
+                v.cost as i64,
+                v.dur,
+                v.new,
+                v.description,
+                serde_json::to_string(&v.coeff)?,
+            ])?;
+        }
+    }
+    let mut insert = conn.prepare(
+        "INSERT INTO dailies (user_id, id, claimable, claimed, last_claimed)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?;
+    for d in user.dailies.iter() {
+        insert.execute(params![id.0, d.id, d.claimable, d.claimed, d.last_claimed])?;
+    }
+    let mut insert = conn.prepare(
+        "INSERT INTO isrdos (user_id, uuid, description, payout) VALUES (?1, ?2, ?3, ?4)",
+    )?;
+    for i in user.isrdos.iter() {
+        insert.execute(params![id.0, i.uuid.to_string(), i.description, i.payout])?;
+    }
+    let mut insert = conn.prepare(
+        "INSERT INTO bars (user_id, id, locked, is_timing, overdrive, c, s, smax, tmax, tbase,
+        s_reduction, overdrive_val) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+    )?;
+    for b in user.bars.iter() {
+        insert.execute(params![
+            id.0,
+            b.id,
+            b.locked,
+            b.is_timing,
+            b.overdrive,
+            b.c,
+            b.s,
+            b.smax,
+            b.tmax,
+            b.tbase,
+            b.s_reduction,
+            b.overdrive_val,
+        ])?;
+    }
+    Ok(())
+}
+
+pub(crate) fn json_col<T: DeserializeOwned>(row: &Row, idx: usize) -> rusqlite::Result<T> {
+    let raw: String = row.get(idx)?;
+    serde_json::from_str(&raw)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+fn uuid_col(row: &Row, idx: usize) -> rusqlite::Result<Uuid> {
+    let raw: String = row.get(idx)?;
+    Uuid::parse_str(&raw)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(idx, Type::Text, Box::new(e)))
+}
+
+fn timestamp_col(row: &Row, idx: usize) -> rusqlite::Result<Option<DateTime<Utc>>> {
+    let raw: Option<i64> = row.get(idx)?;
+    Ok(raw.and_then(|t| DateTime::from_timestamp(t, 0)))
+}
+
+// Adds whichever of `columns` the voucher and template tables are missing.
+// Databases split before migration 4 stopped adding them have some already.
+pub(crate) fn add_voucher_columns(
+    conn: &Connection,
+    columns: &[(&str, &str)],
+) -> Result<(), PersistenceError> {
+    for table in ["vouchers", "templates"] {
+        for (column, decl) in columns {
+            let exists: bool = conn.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                params![table, column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                conn.execute(
+                    &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+                    [],
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_vouchers(
+    conn: &Connection,
+    table: &str,
+    id: &UserId,
+) -> Result<Vec<Voucher>, PersistenceError> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT id, uuid, name, cost, dur, new, description, coeff, acquired, expires, refund,
+        paid FROM {} WHERE user_id = ?1 ORDER BY rowid",
+        table
+    ))?;
+    let vouchers = stmt
+        .query_map(params![id.0], |row| {
+            Ok(Voucher {
+                id: row.get::<_, i64>(0)? as u64,
+                uuid: uuid_col(row, 1)?,
+                name: row.get(2)?,
+                cost: row.get::<_, i64>(3)? as u64,
+                dur: row.get(4)?,
+                new: row.get(5)?,
+                description: row.get(6)?,
+                coeff: json_col::<Coeff>(row, 7)?,
+                acquired: timestamp_col(row, 8)?,
+                expires: timestamp_col(row, 9)?,
+                refund: row.get::<_, i64>(10)? as u64,
+                paid: row.get::<_, Option<i64>>(11)?.map(|p| p as u64),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(vouchers)
+}
+
+fn read_user(conn: &Connection, id: &UserId) -> Result<Option<User>, PersistenceError> {
+    let user = conn
+        .query_row(
+            "SELECT u.username, u.email, u.has_slip, u.active_timer, u.active_bar, u.pause_drip,
+            u.timer, u.timeout_map, u.sss_pity, u.s_pity, u.a_pity, u.total_pulls,
+            u.todays_flux_at, u.todays_flux, w.astrai, w.astrum, w.flux, w.total_flux_aq,
+            w.total_astrum_aq
+            FROM users u JOIN wallets w ON w.user_id = u.id WHERE u.id = ?1",
+            params![id.0],
+            |row| {
+                let timer: Option<String> = row.get(6)?;
+                Ok(User {
+                    id: id.clone(),
+                    username: row.get(0)?,
+                    email: row.get(1)?,
+                    has_slip: row.get(2)?,
+                    active_timer: row.get(3)?,
+                    active_bar: row.get(4)?,
+                    pause_drip: row.get(5)?,
+                    timer: timer.and_then(|t| serde_json::from_str::<Timer>(&t).ok()),
+                    timeout_map: json_col(row, 7)?,
+                    sss_pity: row.get(8)?,
+                    s_pity: row.get(9)?,
+                    a_pity: row.get(10)?,
+                    total_pulls: row.get::<_, i64>(11)? as u128,
+                    todays_flux: (row.get(12)?, row.get::<_, i64>(13)? as u64),
+                    astrai: row.get::<_, i64>(14)? as u64,
+                    astrum: row.get::<_, i64>(15)? as u64,
+                    flux: row.get::<_, i64>(16)? as i128,
+                    total_flux_aq: row.get::<_, i64>(17)? as u128,
+                    total_astrum_aq: row.get::<_, i64>(18)? as u128,
+                    dailies: Vec::new(),
+                    vouchers: Vec::new(),
+                    templates: Vec::new(),
+                    bars: Vec::new(),
+                    isrdos: Vec::new(),
+                    ledger: Vec::new(),
+                    bar_clock: None,
+                    banner_pity: HashMap::new(),
+                    orders: Vec::new(),
+                    purchases: Vec::new(),
+                    pulls: Vec::new(),
+                    stream: None,
+                })
+            },
+        )
+        .optional()?;
+    let Some(mut user) = user else {
+        return Ok(None);
+    };
+
+    user.vouchers = read_vouchers(conn, "vouchers", id)?;
+    user.templates = read_vouchers(conn, "templates", id)?;
+    user.dailies = conn
+        .prepare(
+            "SELECT id, claimable, claimed, last_claimed FROM dailies
+            WHERE user_id = ?1 ORDER BY id",
+        )?
+        .query_map(params![id.0], |row| {
+            Ok(Daily {
+                id: row.get(0)?,
+                claimable: row.get(1)?,
+                claimed: row.get(2)?,
+                last_claimed: row.get(3)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    user.isrdos = conn
+        .prepare("SELECT uuid, description, payout FROM isrdos WHERE user_id = ?1 ORDER BY rowid")?
+        .query_map(params![id.0], |row| {
+            Ok(ISRDO {
+                uuid: uuid_col(row, 0)?,
+                description: row.get(1)?,
+                payout: row.get(2)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    user.bars = conn
+        .prepare(
+            "SELECT id, locked, is_timing, overdrive, c, s, smax, tmax, tbase, s_reduction,
+            overdrive_val FROM bars WHERE user_id = ?1 ORDER BY id",
+        )?
+        .query_map(params![id.0], |row| {
+            Ok(Bar {
+                id: row.get(0)?,
+                locked: row.get(1)?,
+                is_timing: row.get(2)?,
+                overdrive: row.get(3)?,
+                c: row.get(4)?,
+                s: row.get(5)?,
+                smax: row.get(6)?,
+                tmax: row.get(7)?,
+                tbase: row.get(8)?,
+                s_reduction: row.get(9)?,
+                overdrive_val: row.get(10)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(Some(user))
+}
+
+#[derive(PartialEq)]
+struct Profile<'a> {
+    username: &'a str,
+    email: &'a Option<String>,
+    has_slip: bool,
+    active_timer: bool,
+    active_bar: u8,
+    pause_drip: bool,
+    timer: &'a Option<Timer>,
+    timeout_map: &'a HashMap<u64, i64>,
+    pity: (u16, u16, u16),
+    total_pulls: u128,
+    todays_flux: (i64, u64),
+}
+impl<'a> From<&'a User> for Profile<'a> {
+    fn from(user: &'a User) -> Self {
+        Self {
+            username: &user.username,
+            email: &user.email,
+            has_slip: user.has_slip,
+            active_timer: user.active_timer,
+            active_bar: user.active_bar,
+            pause_drip: user.pause_drip,
+            timer: &user.timer,
+            timeout_map: &user.timeout_map,
+            pity: (user.sss_pity, user.s_pity, user.a_pity),
+            total_pulls: user.total_pulls,
+            todays_flux: user.todays_flux,
+        }
+    }
+}
+fn wallet(user: &User) -> (u64, u64, i128, u128, u128) {
+    (
+        user.astrai,
+        user.astrum,
+        user.flux,
+        user.total_flux_aq,
+        user.total_astrum_aq,
+    )
+}
+
+// Writes the rows of `new` whose key is missing from or different in `old`,
+// and removes the keys that are gone.
+fn sync_rows<T: PartialEq, K: Hash + Eq>(
+    old: &[T],
+    new: &[T],
+    key: impl Fn(&T) -> K,
+    mut upsert: impl FnMut(&T) -> rusqlite::Result<usize>,
+    mut delete: impl FnMut(&K) -> rusqlite::Result<usize>,
+) -> rusqlite::Result<()> {
+    let stored: HashMap<K, &T> = old.iter().map(|row| (key(row), row)).collect();
+    let mut keep = HashSet::<K>::new();
+
+    for row in new {
+        if stored.get(&key(row)).is_none_or(|prev| *prev != row) {
+            upsert(row)?;
+        }
+        keep.insert(key(row));
+    }
+    for k in stored.keys().filter(|k| !keep.contains(*k)) {
+        delete(k)?;
+    }
+    Ok(())
+}
+
+fn sync_vouchers(
+    tx: &Connection,
+    table: &str,
+    id: &UserId,
+    old: &[Voucher],
+    new: &[Voucher],
+) -> Result<(), PersistenceError> {
+    let mut upsert = tx.prepare(&format!(
+        "INSERT INTO {} (user_id, uuid, id, name, cost, dur, new, description, coeff, acquired,
+        expires, refund, paid)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+        ON CONFLICT (user_id, uuid) DO UPDATE SET id = ?3, name = ?4, cost = ?5, dur = ?6,
+        new = ?7, description = ?8, coeff = ?9, acquired = ?10, expires = ?11, refund = ?12,
+        paid = ?13",
+        table
+    ))?;
+    let mut delete = tx.prepare(&format!(
+        "DELETE FROM {} WHERE user_id = ?1 AND uuid = ?2",
+        table
+    ))?;
+    sync_rows(
+        old,
+        new,
+        |v| v.uuid,
+        |v| {
+            upsert.execute(params![
+                id.0,
+                v.uuid.to_string(),
+                v.id as i64,
+                v.name,
+                v.cost as i64,
+                v.dur,
+                v.new,
+                v.description,
+                serde_json::to_string(&v.coeff).unwrap_or_default(),
+                v.acquired.map(|t| t.timestamp()),
+                v.expires.map(|t| t.timestamp()),
+                v.refund as i64,
+                v.paid.map(|p| p as i64),
+            ])
+        },
+        |uuid| delete.execute(params![id.0, uuid.to_string()]),
+    )?;
+    Ok(())
+}
+
+fn write_user(tx: &Connection, user: &User, stored: Option<&User>) -> Result<(), PersistenceError> {
+    let id = &user.id;
+
+    if stored.is_none_or(|s| Profile::from(s) != Profile::from(user)) {
+        tx.execute(
+            "INSERT INTO users (id, username, email, has_slip, active_timer, active_bar,
+            pause_drip, timer, timeout_map, sss_pity, s_pity, a_pity, total_pulls,
+            todays_flux_at, todays_flux)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15)
+            ON CONFLICT (id) DO UPDATE SET username = ?2, email = ?3, has_slip = ?4,
+            active_timer = ?5, active_bar = ?6, pause_drip = ?7, timer = ?8, timeout_map = ?9,
+            sss_pity = ?10, s_pity = ?11, a_pity = ?12, total_pulls = ?13,
+            todays_flux_at = ?14, todays_flux = ?15",
+            params![
+                id.0,
+                user.username,
+                user.email,
+                user.has_slip,
+                user.active_timer,
+                user.active_bar,
+                user.pause_drip,
+                user.timer
+                    .as_ref()
+                    .map(|t| serde_json::to_string(t).unwrap_or_default()),
+                serde_json::to_string(&user.timeout_map)?,
+                user.sss_pity,
+                user.s_pity,
+                user.a_pity,
+                user.total_pulls as i64,
+                user.todays_flux.0,
+                user.todays_flux.1 as i64,
+            ],
+        )?;
+    }
+    if stored.is_none_or(|s| wallet(s) != wallet(user)) {
+        tx.execute(
+            "INSERT INTO wallets (user_id, astrai, astrum, flux, total_flux_aq, total_astrum_aq)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (user_id) DO UPDATE SET astrai = ?2, astrum = ?3, flux = ?4,
+            total_flux_aq = ?5, total_astrum_aq = ?6",
+            params![
+                id.0,
+                user.astrai as i64,
+                user.astrum as i64,
+                user.flux as i64,
+                user.total_flux_aq as i64,
+                user.total_astrum_aq as i64,
+            ],
+        )?;
+    }
+
+    sync_vouchers(
+        tx,
+        "vouchers",
+        id,
+        stored.map_or(&[], |s| &s.vouchers),
+        &user.vouchers,
+    )?;
+    sync_vouchers(
+        tx,
+        "templates",
+        id,
+        stored.map_or(&[], |s| &s.templates),
+        &user.templates,
+    )?;
+
+    let mut upsert = tx.prepare(
+        "INSERT INTO dailies (user_id, id, claimable, claimed, last_claimed)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (user_id, id) DO UPDATE SET claimable = ?3, claimed = ?4, last_claimed = ?5",
+    )?;
+    let mut delete = tx.prepare("DELETE FROM dailies WHERE user_id = ?1 AND id = ?2")?;
+    sync_rows(
+        stored.map_or(&[], |s| &s.dailies),
+        &user.dailies,
+        |d| d.id,
+        |d| upsert.execute(params![id.0, d.id, d.claimable, d.claimed, d.last_claimed]),
+        |d| delete.execute(params![id.0, d]),
+    )?;
+
+    let mut upsert = tx.prepare(
+        "INSERT INTO isrdos (user_id, uuid, description, payout) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (user_id, uuid) DO UPDATE SET description = ?3, payout = ?4",
+    )?;
+    let mut delete = tx.prepare("DELETE FROM isrdos WHERE user_id = ?1 AND uuid = ?2")?;
+    sync_rows(
+        stored.map_or(&[], |s| &s.isrdos),
+        &user.isrdos,
+        |i| i.uuid,
+        |i| upsert.execute(params![id.0, i.uuid.to_string(), i.description, i.payout]),
+        |uuid| delete.execute(params![id.0, uuid.to_string()]),
+    )?;
+
+    let mut upsert = tx.prepare(
+        "INSERT INTO bars (user_id, id, locked, is_timing, overdrive, c, s, smax, tmax, tbase,
+        s_reduction, overdrive_val) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)
+        ON CONFLICT (user_id, id) DO UPDATE SET locked = ?3, is_timing = ?4, overdrive = ?5,
+        c = ?6, s = ?7, smax = ?8, tmax = ?9, tbase = ?10, s_reduction = ?11,
+        overdrive_val = ?12",
+    )?;
+    let mut delete = tx.prepare("DELETE FROM bars WHERE user_id = ?1 AND id = ?2")?;
+    sync_rows(
+        stored.map_or(&[], |s| &s.bars),
+        &user.bars,
+        |b| b.id,
+        |b| {
+            upsert.execute(params![
+                id.0,
+                b.id,
+                b.locked,
+                b.is_timing,
+                b.overdrive,
+                b.c,
+                b.s,
+                b.smax,
+                b.tmax,
+                b.tbase,
+                b.s_reduction,
+                b.overdrive_val,
+            ])
+        },
+        |b| delete.execute(params![id.0, b]),
+    )?;
+
+    Ok(())
+}
+
+// Everything a save writes, inside a transaction the caller holds.
+pub(crate) fn write(tx: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let stored = read_user(tx, &user.id)?;
+    write_user(tx, user, stored.as_ref())?;
+    ledger::write_entries(tx, user)?;
+    session::write_clock(tx, user)?;
+    banner::write_pity(tx, user)?;
+    bundle::write_orders(tx, user)?;
+    pricing::write_purchases(tx, user)?;
+    history::write_pulls(tx, user)?;
+    rng::write_stream(tx, user)?;
+    Ok(())
+}
+
+impl SqliteRepo {
+    pub(crate) fn user_ids(&self) -> Result<Vec<String>, PersistenceError> {
+        let conn = self.db.lock().unwrap();
+        let ids = conn
+            .prepare("SELECT id FROM users ORDER BY id")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+}
+
+impl UserRepo for SqliteRepo {
+    fn load<'a>(
+        &'a self,
+        id: UserId,
+    ) -> Result<(User, MutexGuard<'a, Connection>), PersistenceError> {
+        let conn = self.db.lock().unwrap();
+        let mut user = read_user(&conn, &id)?.ok_or(PersistenceError::NotFound)?;
+        user.bar_clock = session::read_clock(&conn, &id)?;
+        user.banner_pity = banner::read_pity(&conn, &id)?;
+        Ok((user, conn))
+    }
+    fn save(&self, user: &User, conn: &Connection) -> Result<(), PersistenceError> {
+        let tx = conn.unchecked_transaction()?;
+        write(&tx, user)?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "tester".into(), None)
+    }
+    fn migrated(conn: Connection) -> SqliteRepo {
+        migrations::migrate(&conn, false).unwrap();
+        SqliteRepo {
+            db: Mutex::new(conn),
+        }
+    }
+    fn save(repo: &SqliteRepo, user: &User) {
+        let conn = repo.db.lock().unwrap();
+        repo.save(user, &conn).unwrap();
+    }
+    fn legacy(blobs: &[(&str, String)]) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE users (id TEXT PRIMARY KEY, data TEXT NOT NULL);")
+            .unwrap();
+        for (id, data) in blobs {
+            conn.execute("INSERT INTO users VALUES (?1, ?2)", params![id, data])
+                .unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn legacy_blobs_split_into_tables() {
+        let mut old = user();
+        old.astrum = 160;
+        old.flux = 50;
+        old.vouchers.push(Voucher::coffee());
+        // Saved before coefficients, the 7am daily and todays_flux existed.
+        let mut blob = serde_json::to_value(&old).unwrap();
+        blob["vouchers"][0].as_object_mut().unwrap().remove("coeff");
+        blob.as_object_mut().unwrap().remove("todays_flux");
+        blob["dailies"]
+            .as_array_mut()
+            .unwrap()
+            .retain(|d| d["id"] != 4);
+
+        let repo = migrated(legacy(&[("tester", blob.to_string())]));
+        let (user, conn) = repo.load(old.id.clone()).unwrap();
+        assert_eq!((user.astrum, user.flux), (160, 50));
+        assert_eq!(user.todays_flux, (0, 0));
+        assert_eq!(user.dailies.len(), old.dailies.len());
+        assert_eq!(user.vouchers.len(), 1);
+        assert_eq!(user.vouchers[0].coeff, Coeff::system());
+        // Filled in by the expiry migration.
+        assert!(user.vouchers[0].acquired.is_some());
+        assert_eq!(user.templates.len(), old.templates.len());
+
+        let backup: String = conn
+            .query_row(
+                "SELECT data FROM users_json WHERE id = 'tester'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(backup.contains("\"_version\""));
+    }
+
+    #[test]
+    fn a_blob_that_cant_be_read_fails_the_split() {
+        let conn = legacy(&[
+            ("tester", serde_json::to_string(&user()).unwrap()),
+            ("broken", r#"{"id": "broken", "username": 5}"#.into()),
+        ]);
+        assert!(migrations::migrate(&conn, false).is_err());
+        // Rolled back to the blob table, the working account included.
+        let blobs: bool = conn
+            .query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info('users') WHERE name = 'data'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(blobs);
+    }
+
+    #[test]
+    fn saves_load_back_as_they_were() {
+        let repo = migrated(Connection::open_in_memory().unwrap());
+        let mut user = user();
+        user.astrai = 3;
+        user.flux = -20;
+        user.has_slip = true;
+        user.sss_pity = 42;
+        user.todays_flux = (1_700_000_000, 30);
+        user.timeout_map.insert(4, 1_700_000_000);
+        user.bars[1].s = 12.5;
+        user.vouchers.push(Voucher::coffee());
+        user.vouchers.push(Voucher {
+            paid: Some(5),
+            ..Voucher::off_day()
+        });
+        user.isrdos.push(ISRDO {
+            uuid: Uuid::now_v7(),
+            description: "Dishes".into(),
+            payout: 40,
+        });
+        save(&repo, &user);
+
+        let (loaded, _conn) = repo.load(user.id.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&user).unwrap()
+        );
+    }
+
+    #[test]
+    fn removed_vouchers_and_isrdos_are_deleted() {
+        let repo = migrated(Connection::open_in_memory().unwrap());
+        let mut user = user();
+        for _ in 0..3 {
+            user.vouchers.push(Voucher {
+                uuid: Uuid::now_v7(),
+                ..Voucher::coffee()
+            });
+        }
+        for payout in [10, 20] {
+            user.isrdos.push(ISRDO {
+                uuid: Uuid::now_v7(),
+                description: "Chore".into(),
+                payout,
+            });
+        }
+        save(&repo, &user);
+
+        let (mut loaded, conn) = repo.load(user.id.clone()).unwrap();
+        drop(conn);
+        let gone = loaded.vouchers.remove(1).uuid;
+        loaded.isrdos.remove(0);
+        save(&repo, &loaded);
+
+        let (again, conn) = repo.load(user.id.clone()).unwrap();
+        let uuids: Vec<Uuid> = again.vouchers.iter().map(|v| v.uuid).collect();
+        assert_eq!(uuids, [user.vouchers[0].uuid, user.vouchers[2].uuid]);
+        assert!(!uuids.contains(&gone));
+        assert_eq!(again.isrdos.len(), 1);
+        assert_eq!(again.isrdos[0].payout, 20);
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM vouchers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 2);
+    }
+
+    #[test]
+    fn only_changed_rows_are_written() {
+        let old = [(1, "a"), (2, "b"), (3, "c")];
+        let new = [(1, "a"), (2, "B"), (4, "d")];
+        let (mut upserted, mut deleted) = (Vec::new(), Vec::new());
+        sync_rows(
+            &old,
+            &new,
+            |row| row.0,
+            |row| {
+                upserted.push(row.0);
+                Ok(1)
+            },
+            |key| {
+                deleted.push(*key);
+                Ok(1)
+            },
+        )
+        .unwrap();
+        assert_eq!(upserted, [2, 4]);
+        assert_eq!(deleted, [3]);
+    }
+}
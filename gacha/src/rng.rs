@@ -0,0 +1,423 @@
+use axum::Json;
+use axum::extract::State;
+use chrono::{NaiveDate, Utc};
+use gacha_protocol::Rarities;
+use rand::{Rng, SeedableRng, rng};
+use rand_chacha::ChaCha20Rng;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::auth::AuthUser;
+use crate::banner::{self, BannerPity};
+use crate::config::Curves;
+use crate::error::ApiError;
+use crate::loot::{self, Granted};
+use crate::{
+    AppState, PersistenceError, PullRecord, SerializedRarity, SqliteRepo, User, UserId, config,
+    load_user, pity,
+};
+
+// Seeds listed by /seeds, newest first.
+const SEEDS_LISTED: u32 = 30;
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS rng_seeds (
+    day TEXT PRIMARY KEY,
+    seed TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS rng_streams (
+    user_id TEXT NOT NULL REFERENCES users (id),
+    day TEXT NOT NULL,
+    word_pos INTEGER NOT NULL,
+    PRIMARY KEY (user_id, day)
+);
+ALTER TABLE pull_history ADD COLUMN rng_day TEXT;
+ALTER TABLE pull_history ADD COLUMN rng_word_pos INTEGER;
+";
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+fn unhex(text: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0u8; 32];
+    if text.len() != 64 {
+        return None;
+    }
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(idx * 2..idx * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// An account's stream for a day is ChaCha20 keyed with
+// sha256(server seed || user id), so anyone holding the revealed seed can
+// rebuild it.
+fn keyed(seed: &[u8; 32], user: &UserId) -> ChaCha20Rng {
+    let key: [u8; 32] = Sha256::new()
+        .chain_update(seed)
+        .chain_update(user.0.as_bytes())
+        .finalize()
+        .into();
+    ChaCha20Rng::from_seed(key)
+}
+
+fn today() -> String {
+    Utc::now().date_naive().to_string()
+}
+
+// Where pulls get their randomness. Every UTC day has one server seed, and how
+// far each account has drawn into its stream for the day is stored. Pulls log
+// the position they started at, which is all a replay needs.
+//
+// With pity curves configured the rarity is drawn from the stream too.
+// Otherwise it comes from `gacha_protocol::roll`, which draws from its own
+// thread rng; replays then take it as recorded and check everything drawn
+// after it (50/50s and loot).
+pub(crate) struct RngSource {
+    commit_reveal: bool,
+}
+impl RngSource {
+    pub(crate) fn new(commit_reveal: bool) -> Self {
+        Self { commit_reveal }
+    }
+
+    // The day's seed and its hash, created on first use.
+    fn seed(&self, conn: &Connection, day: &str) -> Result<([u8; 32], String), PersistenceError> {
+        let fresh: [u8; 32] = rng().random();
+        conn.execute(
+            "INSERT OR IGNORE INTO rng_seeds (day, seed, hash) VALUES (?1, ?2, ?3)",
+            params![day, hex(&fresh), hex(&Sha256::digest(fresh))],
+        )?;
+        let (seed, hash): (String, String) = conn.query_row(
+            "SELECT seed, hash FROM rng_seeds WHERE day = ?1",
+            params![day],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok((unhex(&seed).ok_or(PersistenceError::NotFound)?, hash))
+    }
+
+    pub(crate) fn stream(
+        &self,
+        conn: &Connection,
+        user: &UserId,
+    ) -> Result<PullStream, PersistenceError> {
+        let day = today();
+        let (seed, _) = self.seed(conn, &day)?;
+        let word_pos: Option<i64> = conn
+            .query_row(
+                "SELECT word_pos FROM rng_streams WHERE user_id = ?1 AND day = ?2",
+                params![user.0, day],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let mut rng = keyed(&seed, user);
+        rng.set_word_pos(word_pos.unwrap_or(0) as u128);
+        Ok(PullStream { day, rng })
+    }
+}
+
+pub(crate) struct PullStream {
+    pub(crate) day: String,
+    pub(crate) rng: ChaCha20Rng,
+}
+impl PullStream {
+    pub(crate) fn word_pos(&self) -> i64 {
+        self.rng.get_word_pos() as i64
+    }
+    pub(crate) fn pos(&self) -> StreamPos {
+        StreamPos {
+            day: self.day.clone(),
+            word_pos: self.word_pos(),
+        }
+    }
+}
+
+// How far an account has drawn into a day's stream, queued on the account by
+// a pull and stored by the save that stores the pull.
+#[derive(Clone, Debug)]
+pub(crate) struct StreamPos {
+    day: String,
+    word_pos: i64,
+}
+
+pub(crate) fn write_stream(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    let Some(pos) = &user.stream else {
+        return Ok(());
+    };
+    conn.execute(
+        "INSERT INTO rng_streams (user_id, day, word_pos) VALUES (?1, ?2, ?3)
+        ON CONFLICT (user_id, day) DO UPDATE SET word_pos = ?3",
+        params![user.id.0, pos.day, pos.word_pos],
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub(crate) struct SeedInfo {
+    day: String,
+    hash: String,
+    seed: Option<String>,
+}
+// Today's hash is committed to before any pull uses it, past days come with
+// their seed. Public, there is nothing account specific in here.
+pub(crate) async fn seeds(State(state): State<AppState>) -> Result<Json<Vec<SeedInfo>>, ApiError> {
+    if !state.rng.commit_reveal {
+        return Err(ApiError::Forbidden(
+            "seeds are only published in commit-reveal mode".into(),
+        ));
+    }
+    let conn = state.repo.db.lock().unwrap();
+    let day = today();
+    state.rng.seed(&conn, &day)?;
+
+    let seeds = conn
+        .prepare("SELECT day, seed, hash FROM rng_seeds ORDER BY day DESC LIMIT ?1")?
+        .query_map(params![SEEDS_LISTED], |row| {
+            let seed_day: String = row.get(0)?;
+            Ok(SeedInfo {
+                seed: (seed_day < day).then(|| row.get(1)).transpose()?,
+                hash: row.get(2)?,
+                day: seed_day,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(Json(seeds))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct VerifyRequest {
+    day: NaiveDate,
+}
+#[derive(Serialize)]
+pub(crate) struct Mismatch {
+    record: PullRecord,
+    rarity: SerializedRarity,
+    replayed: Vec<Granted>,
+}
+#[derive(Serialize)]
+pub(crate) struct VerifyResponse {
+    day: String,
+    seed_hash: String,
+    // Only once the day is over, and only in commit-reveal mode.
+    seed: Option<String>,
+    pulls: usize,
+    mismatches: Vec<Mismatch>,
+}
+
+// The rarity a logged pull should have rolled. Pulls gacha_protocol rolled are
+// taken as recorded. Only a pull logged as floored turns a rolled B into an A.
+fn rarity(
+    curves: Option<&Curves>,
+    record: &PullRecord,
+    pity: &BannerPity,
+    rng: &mut ChaCha20Rng,
+) -> Option<Rarities> {
+    let Some(curves) = curves else {
+        return record.rarity.rarity();
+    };
+    Some(match pity::curve_roll(curves, pity, rng) {
+        Rarities::B if record.floored => Rarities::A,
+        rolled => rolled,
+    })
+}
+
+// What a logged pull should have rolled and handed out, drawn again from its
+// stream position. None for pulls logged before streams existed.
+fn replay(
+    record: &PullRecord,
+    user: &User,
+    seed: &[u8; 32],
+) -> Option<(SerializedRarity, Vec<Granted>)> {
+    let word_pos = record.rng_word_pos?;
+    let mut rng = keyed(seed, &user.id);
+    rng.set_word_pos(word_pos as u128);
+
+    let mut pity = BannerPity {
+        sss_pity: record.sss_pity,
+        s_pity: record.s_pity,
+        a_pity: record.a_pity,
+        ..BannerPity::default()
+    };
+    let outcome = rarity(config::pity().curves.as_ref(), record, &pity, &mut rng)?;
+
+    let mut granted = Vec::new();
+    if let Some(banner) = banner::by_id(&record.banner) {
+        // A spent guarantee means it was set going into the pull.
+        if let Some(guaranteed) = pity.guaranteed(&outcome) {
+            *guaranteed = record.slip_consumed;
+        }
+        let featured = banner.rate_up(&outcome).zip(pity.guaranteed(&outcome));
+        let (drops, _) = loot::drops(user, featured, &outcome, &mut rng);
+        granted = drops.iter().map(loot::Drop::granted).collect();
+    }
+    Some((SerializedRarity::try_from(outcome).ok()?, granted))
+}
+
+// Replays the account's pulls of a day from the day's seed and lists the ones
+// that don't match what was logged. Replays use the banners and loot tables
+// configured now, a config change since then shows up as mismatches.
+pub(crate) async fn verify_pulls(
+    State(state): State<AppState>,
+    AuthUser(UserId(userid)): AuthUser,
+    Json(req): Json<VerifyRequest>,
+) -> Result<Json<VerifyResponse>, ApiError> {
+    let (user, conn) = load_user(userid, &state)?;
+    let day = req.day.to_string();
+    let (seed, seed_hash): (String, String) = conn
+        .query_row(
+            "SELECT seed, hash FROM rng_seeds WHERE day = ?1",
+            params![day],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .optional()?
+        .ok_or(ApiError::NotFound("seed"))?;
+    let key = unhex(&seed).ok_or(ApiError::NotFound("seed"))?;
+
+    let records = SqliteRepo::pulls_on_day(&conn, &user.id, &day)?;
+    let pulls = records.len();
+    let mismatches = records
+        .into_iter()
+        .filter_map(|record| {
+            let (rarity, replayed) = replay(&record, &user, &key)?;
+            let same = rarity == record.rarity
+                && replayed.len() == record.items.len()
+                && replayed
+                    .iter()
+                    .zip(&record.items)
+                    .all(|(r, l)| r.same_as(l));
+            (!same).then_some(Mismatch {
+                record,
+                rarity,
+                replayed,
+            })
+        })
+        .collect();
+
+    Ok(Json(VerifyResponse {
+        seed: (state.rng.commit_reveal && day < today()).then_some(seed),
+        day,
+        seed_hash,
+        pulls,
+        mismatches,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::banner::find;
+    use crate::config::{Banner, Curve};
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "Tester".into(), None)
+    }
+    fn draws(rng: &mut ChaCha20Rng) -> Vec<u32> {
+        (0..8).map(|_| rng.random()).collect()
+    }
+
+    #[test]
+    fn stream_picks_up_at_a_saved_position() {
+        let seed = [7u8; 32];
+        let user = user();
+        let mut first = keyed(&seed, &user.id);
+        draws(&mut first);
+        let saved = first.get_word_pos();
+        let expected = draws(&mut first);
+
+        let mut resumed = keyed(&seed, &user.id);
+        resumed.set_word_pos(saved);
+        assert_eq!(draws(&mut resumed), expected);
+    }
+
+    #[test]
+    fn streams_differ_per_account() {
+        let seed = [7u8; 32];
+        let mut a = keyed(&seed, &UserId("a".into()));
+        let mut b = keyed(&seed, &UserId("b".into()));
+        assert_ne!(draws(&mut a), draws(&mut b));
+    }
+
+    #[test]
+    fn drops_replay_from_the_same_position() {
+        let user = user();
+        let standard: &Banner = find(None).unwrap();
+        let roll = |rng: &mut ChaCha20Rng| {
+            let mut pity = BannerPity::default();
+            let featured = standard
+                .rate_up(&Rarities::MythicSSS)
+                .map(|r| (r, &mut pity.sss_guaranteed));
+            let (drops, _) = loot::drops(&user, featured, &Rarities::MythicSSS, rng);
+            drops.iter().map(loot::Drop::granted).collect::<Vec<_>>()
+        };
+
+        let mut rng = keyed(&[1u8; 32], &user.id);
+        let pulls: Vec<_> = (0..20)
+            .map(|_| (rng.get_word_pos(), roll(&mut rng)))
+            .collect();
+        for (word_pos, granted) in pulls {
+            let mut again = keyed(&[1u8; 32], &user.id);
+            again.set_word_pos(word_pos);
+            let replayed = roll(&mut again);
+            assert!(replayed.iter().zip(&granted).all(|(r, g)| r.same_as(g)));
+            assert_eq!(replayed.len(), granted.len());
+        }
+    }
+
+    #[test]
+    fn only_floored_pulls_turn_a_b_into_an_a() {
+        let flat = |base| Curve {
+            base,
+            soft_start: None,
+            soft_step: 0.0,
+            hard: None,
+        };
+        let always_b = Curves {
+            mythic: flat(0.0),
+            s: flat(0.0),
+            a: flat(0.0),
+        };
+        let record = |rarity, floored| PullRecord {
+            pulled_at: Utc::now(),
+            banner: banner::STANDARD.into(),
+            rarity,
+            sss_pity: 0,
+            s_pity: 0,
+            a_pity: 0,
+            astrai_spent: 1,
+            astrum_spent: 0,
+            vouchers: 0,
+            slip_consumed: false,
+            floored,
+            items: Vec::new(),
+            rng_day: None,
+            rng_word_pos: Some(0),
+        };
+        let replayed = |record: &PullRecord, curves: Option<&Curves>| {
+            let mut rng = keyed(&[1u8; 32], &user().id);
+            rarity(curves, record, &BannerPity::default(), &mut rng)
+        };
+
+        let upgraded = record(SerializedRarity::A, false);
+        assert!(matches!(
+            replayed(&upgraded, Some(&always_b)),
+            Some(Rarities::B)
+        ));
+        let floored = record(SerializedRarity::A, true);
+        assert!(matches!(
+            replayed(&floored, Some(&always_b)),
+            Some(Rarities::A)
+        ));
+        // Without curves gacha_protocol rolled it, there is nothing to draw.
+        assert!(matches!(replayed(&upgraded, None), Some(Rarities::A)));
+    }
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes: [u8; 32] = std::array::from_fn(|i| (i * 9) as u8);
+        assert_eq!(unhex(&hex(&bytes)), Some(bytes));
+        assert_eq!(unhex("zz"), None);
+    }
+}
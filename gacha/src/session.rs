@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use rusqlite::{Connection, OptionalExtension, params};
+use tokio::task::AbortHandle;
+
+use crate::engine::{BarEngine, BarMode};
+use crate::events::{Event, Events};
+use crate::{AppState, Bar, PersistenceError, User, UserId, UserRepo};
+
+const SESSION_IDLE: Duration = Duration::from_secs(30 * 60);
+const SWEEP_EVERY: Duration = Duration::from_secs(60);
+
+pub(crate) const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS bar_clocks (
+    user_id TEXT PRIMARY KEY REFERENCES users (id),
+    mode TEXT NOT NULL,
+    bar INTEGER NOT NULL,
+    anchor INTEGER NOT NULL
+);
+";
+
+// Which task was driving the bars, saved next to them. `anchor` is the wall
+// clock time (unix millis) the saved bars were taken at, so whatever ran after
+// that can be replayed on the next start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct BarClock {
+    pub(crate) mode: BarMode,
+    pub(crate) bar: u8,
+    pub(crate) anchor: i64,
+}
+
+pub(crate) fn read_clock(
+    conn: &Connection,
+    id: &UserId,
+) -> Result<Option<BarClock>, PersistenceError> {
+    let row: Option<(String, u8, i64)> = conn
+        .query_row(
+            "SELECT mode, bar, anchor FROM bar_clocks WHERE user_id = ?1",
+            params![id.0],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()?;
+    Ok(row.and_then(|(mode, bar, anchor)| {
+        Some(BarClock {
+            mode: BarMode::from_str(&mode)?,
+            bar,
+            anchor,
+        })
+    }))
+}
+pub(crate) fn write_clock(conn: &Connection, user: &User) -> Result<(), PersistenceError> {
+    if read_clock(conn, &user.id)? == user.bar_clock {
+        return Ok(());
+    }
+    match user.bar_clock {
+        Some(clock) => conn.execute(
+            "INSERT INTO bar_clocks (user_id, mode, bar, anchor) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (user_id) DO UPDATE SET mode = ?2, bar = ?3, anchor = ?4",
+            params![user.id.0, clock.mode.as_str(), clock.bar, clock.anchor],
+        )?,
+        None => conn.execute(
+            "DELETE FROM bar_clocks WHERE user_id = ?1",
+            params![user.id.0],
+        )?,
+    };
+    Ok(())
+}
+
+// One account's live bar state: the bars themselves, the running
+// start_timer/start_idle task and any voucher consumptions in flight.
+#[derive(Clone)]
+pub(crate) struct Session {
+    pub(crate) bars: Arc<Mutex<Vec<Bar>>>,
+    pub(crate) timer: Arc<Mutex<Option<AbortHandle>>>,
+    clock: Arc<Mutex<Option<(BarMode, u8)>>>,
+    consumers: Arc<Mutex<Vec<AbortHandle>>>,
+    last_seen: Arc<Mutex<Instant>>,
+    owner: UserId,
+    events: Events,
+}
+impl Session {
+    // Picks the stored bars up where they were saved: the time since then is
+    // replayed and the task that was running is started again. Bars saved
+    // without a clock had nothing running.
+    fn resume(user: &User, events: Events) -> Self {
+        let mut bars = user.bars.clone();
+        let running = match user.bar_clock {
+            Some(clock) => {
+                let elapsed = (Utc::now().timestamp_millis() - clock.anchor).max(0);
+                let (engine, _) = BarEngine::new(bars, Some((clock.mode, clock.bar)))
+                    .advance(Duration::from_millis(elapsed as u64));
+                bars = engine.bars;
+                engine.running
+            }
+            None => {
+                bars.iter_mut().for_each(|f| {
+                    f.is_timing = false;
+                });
+                None
+            }
+        };
+
+        let session = Self {
+            bars: Arc::new(Mutex::new(bars)),
+            timer: Arc::new(Mutex::new(None)),
+            clock: Arc::new(Mutex::new(None)),
+            consumers: Arc::new(Mutex::new(Vec::new())),
+            last_seen: Arc::new(Mutex::new(Instant::now())),
+            owner: user.id.clone(),
+            events,
+        };
+        if let Some((mode, id)) = running {
+            *session.timer.lock().unwrap() = Some(session.drive(mode, id));
+        }
+        session
+    }
+    // The single task behind the bars: it waits out one tick of whatever is
+    // running and leaves the rest to the engine, until nothing runs anymore.
+    pub(crate) fn drive(&self, mode: BarMode, bar: u8) -> AbortHandle {
+        *self.clock.lock().unwrap() = Some((mode, bar));
+        let session = self.clone();
+        tokio::spawn(async move {
+            let mut wait = Duration::ZERO;
+            loop {
+                tokio::time::sleep(wait).await;
+
+                let (engine, events) = {
+                    let mut bars = session.bars.lock().unwrap();
+                    let mut clock = session.clock.lock().unwrap();
+                    let (engine, events) = BarEngine::new(bars.clone(), *clock).advance(wait);
+                    *bars = engine.bars.clone();
+                    *clock = engine.running;
+                    (engine, events)
+                };
+
+                for event in events {
+                    session.events.publish(&session.owner, event.into());
+                }
+                session
+                    .events
+                    .publish(&session.owner, Event::BarTick { bars: engine.bars });
+
+                match engine.running {
+                    Some((mode, _)) => wait = mode.tick(),
+                    None => break,
+                }
+            }
+        })
+        .abort_handle()
+    }
+    pub(crate) fn running(&self) -> Option<(BarMode, u8)> {
+        *self.clock.lock().unwrap()
+    }
+    // Copies the live bars and the running task onto the user, anchored now.
+    pub(crate) fn checkpoint(&self, user: &mut User) {
+        let bars = self.bars.lock().unwrap();
+        user.bars = bars.clone();
+        user.bar_clock = self.clock.lock().unwrap().map(|(mode, bar)| BarClock {
+            mode,
+            bar,
+            anchor: Utc::now().timestamp_millis(),
+        });
+    }
+    pub(crate) fn track(&self, handle: AbortHandle) {
+        let mut consumers = self.consumers.lock().unwrap();
+        consumers.retain(|f| !f.is_finished());
+        consumers.push(handle);
+    }
+    fn touch(&self) {
+        *self.last_seen.lock().unwrap() = Instant::now();
+    }
+    // A consumption in flight keeps the session alive, the bar timer alone
+    // does not since it never stops on its own.
+    fn is_idle(&self) -> bool {
+        self.last_seen.lock().unwrap().elapsed() >= SESSION_IDLE
+            && self
+                .consumers
+                .lock()
+                .unwrap()
+                .iter()
+                .all(|f| f.is_finished())
+    }
+    fn shutdown(&self) {
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            timer.abort();
+        }
+        self.consumers
+            .lock()
+            .unwrap()
+            .drain(..)
+            .for_each(|f| f.abort());
+    }
+}
+
+pub(crate) struct Sessions {
+    map: Mutex<HashMap<UserId, Session>>,
+    events: Events,
+}
+impl Sessions {
+    pub(crate) fn new(events: Events) -> Self {
+        Self {
+            map: Mutex::new(HashMap::new()),
+            events,
+        }
+    }
+    pub(crate) fn get(&self, user: &User) -> Session {
+        let session = self
+            .map
+            .lock()
+            .unwrap()
+            .entry(user.id.clone())
+            .or_insert_with(|| Session::resume(user, self.events.clone()))
+            .clone();
+        session.touch();
+        session
+    }
+    pub(crate) fn touch(&self, id: &UserId) {
+        if let Some(session) = self.map.lock().unwrap().get(id) {
+            session.touch();
+        }
+    }
+    fn live(&self) -> Vec<(UserId, Session)> {
+        self.map
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, session)| (id.clone(), session.clone()))
+            .collect()
+    }
+    fn take_idle(&self) -> Vec<(UserId, Session)> {
+        let mut map = self.map.lock().unwrap();
+        let idle: Vec<UserId> = map
+            .iter()
+            .filter(|(_, session)| session.is_idle())
+            .map(|(id, _)| id.clone())
+            .collect();
+        idle.into_iter()
+            .filter_map(|id| map.remove_entry(&id))
+            .collect()
+    }
+}
+
+fn save_checkpoint(state: &AppState, id: &UserId, session: &Session) {
+    let Ok((mut user, conn)) = state.repo.load(id.clone()) else {
+        return;
+    };
+    session.checkpoint(&mut user);
+    if let Err(e) = state.repo.save(&user, &conn) {
+        println!("Error saving bars for {}: {}", id.0, e);
+    }
+}
+
+// Every sweep saves the live bars with their clock, so a restart or crash
+// only has to replay from the last minute. Evicted sessions are saved the same
+// way and resume from there on the next request. Event feeds nobody listens
+// to go at the same time.
+pub(crate) fn spawn_sweeper(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_EVERY);
+        loop {
+            interval.tick().await;
+            for (id, session) in state.sessions.take_idle() {
+                session.shutdown();
+                save_checkpoint(&state, &id, &session);
+            }
+            for (id, session) in state.sessions.live() {
+                save_checkpoint(&state, &id, &session);
+            }
+            state.events.drop_idle();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> User {
+        User::new(UserId("tester".into()), "tester".into(), None)
+    }
+
+    #[test]
+    fn clocks_are_saved_and_cleared() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id TEXT PRIMARY KEY); INSERT INTO users VALUES ('tester');",
+        )
+        .unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        let mut user = user();
+        let clock = BarClock {
+            mode: BarMode::Idle,
+            bar: 5,
+            anchor: 1_700_000_000_000,
+        };
+
+        user.bar_clock = Some(clock);
+        write_clock(&conn, &user).unwrap();
+        write_clock(&conn, &user).unwrap();
+        assert_eq!(read_clock(&conn, &user.id).unwrap(), Some(clock));
+
+        user.bar_clock = None;
+        write_clock(&conn, &user).unwrap();
+        assert_eq!(read_clock(&conn, &user.id).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn resuming_replays_the_time_since_the_save() {
+        let mut user = user();
+        let tick = BarMode::Timer.tick().as_millis() as i64;
+        user.bar_clock = Some(BarClock {
+            mode: BarMode::Timer,
+            bar: 2,
+            anchor: Utc::now().timestamp_millis() - tick * 5 / 2,
+        });
+
+        let session = Session::resume(&user, Events::new());
+        assert_eq!(session.running(), Some((BarMode::Timer, 2)));
+        assert_eq!(session.bars.lock().unwrap()[2].s, 30.0);
+
+        session.checkpoint(&mut user);
+        session.shutdown();
+        let clock = user.bar_clock.unwrap();
+        assert_eq!((clock.mode, clock.bar), (BarMode::Timer, 2));
+        assert_eq!(user.bars[2].s, 30.0);
+    }
+
+    #[tokio::test]
+    async fn bars_saved_without_a_clock_stay_still() {
+        let mut user = user();
+        user.bars[3].is_timing = true;
+        user.bars[3].s = 40.0;
+
+        let session = Session::resume(&user, Events::new());
+        assert_eq!(session.running(), None);
+        let bars = session.bars.lock().unwrap();
+        assert!(!bars[3].is_timing);
+        assert_eq!(bars[3].s, 40.0);
+    }
+
+    #[tokio::test]
+    async fn an_account_keeps_one_session_until_it_idles() {
+        let sessions = Sessions::new(Events::new());
+        let user = user();
+        let first = sessions.get(&user);
+        let again = sessions.get(&user);
+        assert!(Arc::ptr_eq(&first.bars, &again.bars));
+
+        assert!(!first.is_idle());
+        assert!(sessions.take_idle().is_empty());
+        assert_eq!(sessions.live().len(), 1);
+    }
+}
@@ -0,0 +1,495 @@
+use chrono::Utc;
+use rand::{SeedableRng, random};
+use rand_chacha::ChaCha20Rng;
+use serde::Serialize;
+
+use crate::config::{self, Banner, Reward, arg_value};
+use crate::ledger::{Currency, LedgerSource, Overdraft};
+use crate::{SerializedRarity, User, UserId, Voucher, banner, pricing, pull};
+
+const USAGE: &str = "Usage: gacha-sim [--pulls N] [--runs N] [--days N] [--seed N] \
+[--banner ID] [--format json|csv]";
+
+enum Format {
+    Json,
+    Csv,
+}
+
+struct Options {
+    pulls: u64,
+    runs: u32,
+    days: u32,
+    seed: u64,
+    banner: &'static Banner,
+    format: Format,
+}
+
+fn number<T: std::str::FromStr>(args: &[String], flag: &str, default: T) -> Result<T, String> {
+    match arg_value(args, flag) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{} expects a number, got {}", flag, value)),
+        None => Ok(default),
+    }
+}
+
+fn options(args: &[String]) -> Result<Options, String> {
+    let banner_id = arg_value(args, "--banner").unwrap_or(banner::STANDARD.into());
+    Ok(Options {
+        pulls: number(args, "--pulls", 1_000_000)?,
+        runs: number(args, "--runs", 100)?,
+        days: number(args, "--days", 365)?,
+        seed: number(args, "--seed", random())?,
+        banner: banner::by_id(&banner_id).ok_or(format!("no banner {}", banner_id))?,
+        format: match arg_value(args, "--format").as_deref() {
+            None | Some("json") => Format::Json,
+            Some("csv") => Format::Csv,
+            Some(other) => return Err(format!("unknown format {}", other)),
+        },
+    })
+}
+
+// A histogram indexed by value, summarised.
+#[derive(Serialize, Default)]
+struct Spread {
+    samples: u64,
+    mean: f64,
+    median: usize,
+    p90: usize,
+    p99: usize,
+    max: usize,
+}
+impl Spread {
+    fn of(counts: &[u64]) -> Self {
+        let samples: u64 = counts.iter().sum();
+        if samples == 0 {
+            return Self::default();
+        }
+        let total: u64 = counts.iter().enumerate().map(|(v, n)| v as u64 * n).sum();
+        let quantile = |q: f64| {
+            let target = (samples as f64 * q).ceil() as u64;
+            let mut seen = 0;
+            counts
+                .iter()
+                .position(|n| {
+                    seen += n;
+                    seen >= target
+                })
+                .unwrap_or(0)
+        };
+        Self {
+            samples,
+            mean: total as f64 / samples as f64,
+            median: quantile(0.5),
+            p90: quantile(0.9),
+            p99: quantile(0.99),
+            max: counts.iter().rposition(|n| *n > 0).unwrap_or(0),
+        }
+    }
+}
+
+fn count(counts: &mut Vec<u64>, value: usize) {
+    if counts.len() <= value {
+        counts.resize(value + 1, 0);
+    }
+    counts[value] += 1;
+}
+
+#[derive(Serialize, Default)]
+struct PullStats {
+    pulls: u64,
+    mythic: u64,
+    s: u64,
+    a: u64,
+    b: u64,
+    flux_per_pull: f64,
+    vouchers_per_pull: f64,
+    pulls_to_mythic: Spread,
+    pulls_to_s: Spread,
+    // Index is the number of pulls it took, value how often it took that many.
+    mythic_pity: Vec<u64>,
+    s_pity: Vec<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct IncomeStats {
+    runs: u32,
+    days: u32,
+    pulls_per_day: f64,
+    dailies_flux_per_day: f64,
+    pull_flux_per_day: f64,
+    // Spent on the days the flux daily could be unlocked.
+    flux_spent_per_day: f64,
+    mythic_week_cost: u64,
+    days_to_mythic_week: Spread,
+    never_afforded: u32,
+    // Mean flux earned on each day, across runs.
+    flux_by_day: Vec<f64>,
+    // Index is the day the mythic week became affordable.
+    afforded_on: Vec<u64>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    seed: u64,
+    banner: String,
+    pulls: PullStats,
+    income: IncomeStats,
+}
+
+fn sim_user() -> User {
+    User::new(UserId("simulated".into()), "Simulated".into(), None)
+}
+
+// Pulls with a ticket always at hand, nothing else coming in. Rarities are
+// rolled by `pity::roll` like real pulls, so by gacha_protocol unless pity
+// curves are set.
+fn simulate_pulls(opts: &Options, rng: &mut ChaCha20Rng) -> Result<PullStats, Overdraft> {
+    let mut stats = PullStats::default();
+    let mut user = sim_user();
+    let mut vouchers = 0u64;
+
+    for _ in 0..opts.pulls {
+        user.astrai = 1;
+        let record = pull(&mut user, opts.banner, false, rng)?;
+        match record.rarity {
+            SerializedRarity::MythicSSS => {
+                stats.mythic += 1;
+                count(&mut stats.mythic_pity, record.sss_pity as usize + 1);
+            }
+            SerializedRarity::S => {
+                stats.s += 1;
+                count(&mut stats.s_pity, record.s_pity as usize + 1);
+            }
+            SerializedRarity::A => stats.a += 1,
+            SerializedRarity::B => stats.b += 1,
+            SerializedRarity::NoTickets => (),
+        }
+        vouchers += record.vouchers as u64;
+        // Nothing reads these here, and a million pulls' worth adds up.
+        user.ledger.clear();
+        user.vouchers.clear();
+    }
+
+    stats.pulls = opts.pulls;
+    stats.flux_per_pull = user.flux as f64 / opts.pulls.max(1) as f64;
+    stats.vouchers_per_pull = vouchers as f64 / opts.pulls.max(1) as f64;
+    stats.pulls_to_mythic = Spread::of(&stats.mythic_pity);
+    stats.pulls_to_s = Spread::of(&stats.s_pity);
+    Ok(stats)
+}
+
+// Every daily but the flux one (id 3), with the bonus for three claimed.
+fn dailies_income() -> Reward {
+    let dailies = &config::economy().dailies;
+    let mut reward = Reward::default();
+    for (id, daily) in dailies.rewards.iter().enumerate() {
+        if id != 3 {
+            reward.add(daily);
+        }
+    }
+    reward.add(&dailies.three_claimed);
+    reward
+}
+
+// The flux daily and the bonus for all of them, once `flux_threshold` is spent.
+fn unlocked_income() -> Reward {
+    let dailies = &config::economy().dailies;
+    let mut reward = Reward::default();
+    if let Some(daily) = dailies.rewards.get(3) {
+        reward.add(daily);
+    }
+    reward.add(&dailies.all_claimed);
+    reward
+}
+
+fn claim(user: &mut User, reward: &Reward) -> Result<(), Overdraft> {
+    for (currency, amount) in [
+        (Currency::Astrum, reward.astrum),
+        (Currency::Astrai, reward.astrai),
+        (Currency::Flux, reward.flux),
+    ] {
+        user.adjust(currency, amount as i64, LedgerSource::Daily, None)?;
+    }
+    Ok(())
+}
+
+// The Mythic Week Off at the cheapest hour of the day, priced like the store
+// prices a first one.
+fn mythic_week_cost(user: &User) -> u64 {
+    let today = Utc::now().date_naive();
+    (0..24)
+        .filter_map(|hour| today.and_hms_opt(hour, 0, 0))
+        .map(|at| pricing::price(user, &Voucher::mythic_week(), 1, 1.0, at.and_utc()).unit)
+        .min()
+        .unwrap_or_default()
+}
+
+// Players who claim every daily they can, pull with everything they get and
+// spend no flux beyond what unlocks the flux daily, each for `days` days.
+fn simulate_income(opts: &Options, rng: &mut ChaCha20Rng) -> Result<IncomeStats, Overdraft> {
+    let daily = dailies_income();
+    let unlocked = unlocked_income();
+    let economy = config::economy();
+    let price = economy.astrum_per_pull;
+    let cost = mythic_week_cost(&sim_user());
+    let mut stats = IncomeStats {
+        runs: opts.runs,
+        days: opts.days,
+        mythic_week_cost: cost,
+        flux_by_day: vec![0.0; opts.days as usize],
+        ..IncomeStats::default()
+    };
+    let (mut pulls, mut pull_flux) = (0u64, 0i128);
+    let (mut dailies_flux, mut spent) = (0u64, 0u64);
+
+    for _ in 0..opts.runs {
+        let mut user = sim_user();
+        let mut afforded = false;
+
+        for day in 0..opts.days as usize {
+            let before = user.flux;
+            claim(&mut user, &daily)?;
+            dailies_flux += daily.flux as u64;
+            // Spent before pulling, it's the flux the day has brought in so far.
+            if user.flux >= economy.flux_threshold as i128 {
+                user.adjust(
+                    Currency::Flux,
+                    -(economy.flux_threshold as i64),
+                    LedgerSource::Purchase,
+                    None,
+                )?;
+                claim(&mut user, &unlocked)?;
+                dailies_flux += unlocked.flux as u64;
+                spent += economy.flux_threshold;
+            }
+
+            let pulled = user.flux;
+            while user.astrai > 0 || user.astrum >= price {
+                pull(&mut user, opts.banner, false, rng)?;
+                pulls += 1;
+            }
+            user.ledger.clear();
+            user.vouchers.clear();
+
+            pull_flux += user.flux - pulled;
+            stats.flux_by_day[day] += (user.flux - before) as f64 / opts.runs as f64;
+            if !afforded && user.flux >= cost as i128 {
+                afforded = true;
+                count(&mut stats.afforded_on, day + 1);
+            }
+        }
+        if !afforded {
+            stats.never_afforded += 1;
+        }
+    }
+
+    let player_days = (opts.runs as u64 * opts.days as u64).max(1) as f64;
+    stats.pulls_per_day = pulls as f64 / player_days;
+    stats.dailies_flux_per_day = dailies_flux as f64 / player_days;
+    stats.pull_flux_per_day = pull_flux as f64 / player_days;
+    stats.flux_spent_per_day = spent as f64 / player_days;
+    stats.days_to_mythic_week = Spread::of(&stats.afforded_on);
+    Ok(stats)
+}
+
+fn spread_rows(rows: &mut Vec<(String, String, String)>, table: &str, spread: &Spread) {
+    let fields = [
+        ("samples", spread.samples.to_string()),
+        ("mean", spread.mean.to_string()),
+        ("median", spread.median.to_string()),
+        ("p90", spread.p90.to_string()),
+        ("p99", spread.p99.to_string()),
+        ("max", spread.max.to_string()),
+    ];
+    for (key, value) in fields {
+        rows.push((table.into(), key.into(), value));
+    }
+}
+fn histogram_rows(rows: &mut Vec<(String, String, String)>, table: &str, counts: &[u64]) {
+    for (value, n) in counts.iter().enumerate().filter(|(_, n)| **n > 0) {
+        rows.push((table.into(), value.to_string(), n.to_string()));
+    }
+}
+
+// Long format, one `table,key,value` row per number, so it pivots in any
+// spreadsheet.
+fn csv(report: &Report) -> String {
+    let (p, i) = (&report.pulls, &report.income);
+    let mut rows: Vec<(String, String, String)> = Vec::new();
+    let summary = [
+        ("seed", report.seed.to_string()),
+        ("banner", report.banner.clone()),
+        ("pulls", p.pulls.to_string()),
+        ("mythic", p.mythic.to_string()),
+        ("s", p.s.to_string()),
+        ("a", p.a.to_string()),
+        ("b", p.b.to_string()),
+        ("flux_per_pull", p.flux_per_pull.to_string()),
+        ("vouchers_per_pull", p.vouchers_per_pull.to_string()),
+        ("runs", i.runs.to_string()),
+        ("days", i.days.to_string()),
+        ("pulls_per_day", i.pulls_per_day.to_string()),
+        ("dailies_flux_per_day", i.dailies_flux_per_day.to_string()),
+        ("pull_flux_per_day", i.pull_flux_per_day.to_string()),
+        ("flux_spent_per_day", i.flux_spent_per_day.to_string()),
+        ("mythic_week_cost", i.mythic_week_cost.to_string()),
+        ("never_afforded", i.never_afforded.to_string()),
+    ];
+    for (key, value) in summary {
+        rows.push(("summary".into(), key.into(), value));
+    }
+    spread_rows(&mut rows, "pulls_to_mythic", &p.pulls_to_mythic);
+    spread_rows(&mut rows, "pulls_to_s", &p.pulls_to_s);
+    spread_rows(&mut rows, "days_to_mythic_week", &i.days_to_mythic_week);
+    histogram_rows(&mut rows, "mythic_pity", &p.mythic_pity);
+    histogram_rows(&mut rows, "s_pity", &p.s_pity);
+    histogram_rows(&mut rows, "afforded_on", &i.afforded_on);
+    for (day, flux) in i.flux_by_day.iter().enumerate() {
+        rows.push((
+            "flux_by_day".into(),
+            (day + 1).to_string(),
+            flux.to_string(),
+        ));
+    }
+
+    let mut out = String::from("table,key,value\n");
+    for (table, key, value) in rows {
+        out.push_str(&format!("{},{},{}\n", table, key, value));
+    }
+    out
+}
+
+// `gacha-sim`: runs the configured economy through the real pull code
+// and prints the numbers. The seed drives everything drawn by the pull code;
+// without pity curves rarities come from `gacha_protocol::roll`, which can't
+// be seeded, and reruns only agree statistically.
+pub(crate) fn run(args: &[String]) {
+    let opts = match options(args) {
+        Ok(opts) => opts,
+        Err(e) => {
+            println!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = opts.banner.check(&sim_user()) {
+        println!("{}", e);
+        std::process::exit(1);
+    }
+
+    let mut rng = ChaCha20Rng::seed_from_u64(opts.seed);
+    let simulated = simulate_pulls(&opts, &mut rng)
+        .and_then(|pulls| Ok((pulls, simulate_income(&opts, &mut rng)?)));
+    let (pulls, income) = match simulated {
+        Ok(stats) => stats,
+        Err(e) => {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let report = Report {
+        seed: opts.seed,
+        banner: opts.banner.id.clone(),
+        pulls,
+        income,
+    };
+    match opts.format {
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+        Format::Csv => print!("{}", csv(&report)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> Report {
+        let mythic_pity = vec![0, 0, 1, 0, 1];
+        Report {
+            seed: 7,
+            banner: banner::STANDARD.into(),
+            pulls: PullStats {
+                pulls: 10,
+                mythic: 2,
+                b: 8,
+                pulls_to_mythic: Spread::of(&mythic_pity),
+                mythic_pity,
+                ..PullStats::default()
+            },
+            income: IncomeStats {
+                runs: 1,
+                days: 2,
+                flux_by_day: vec![4.0, 4.5],
+                ..IncomeStats::default()
+            },
+        }
+    }
+
+    #[test]
+    fn spreads_summarise_a_histogram() {
+        assert_eq!(Spread::of(&[]).samples, 0);
+        assert_eq!(Spread::of(&[0, 0, 0]).mean, 0.0);
+
+        // Two samples of 1, one of 2, one of 5.
+        let spread = Spread::of(&[0, 2, 1, 0, 0, 1]);
+        assert_eq!(spread.samples, 4);
+        assert_eq!(spread.mean, 9.0 / 4.0);
+        assert_eq!((spread.median, spread.p90, spread.p99), (1, 5, 5));
+        assert_eq!(spread.max, 5);
+
+        let mut counts = Vec::new();
+        count(&mut counts, 3);
+        count(&mut counts, 3);
+        count(&mut counts, 0);
+        assert_eq!(counts, [1, 0, 0, 2]);
+    }
+
+    #[test]
+    fn csv_has_one_row_per_number() {
+        let csv = csv(&report());
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], "table,key,value");
+        assert!(rows.iter().skip(1).all(|row| row.split(',').count() == 3));
+        for row in [
+            "summary,seed,7",
+            "summary,banner,standard",
+            "summary,mythic,2",
+            "pulls_to_mythic,samples,2",
+            "pulls_to_mythic,mean,3",
+            "mythic_pity,2,1",
+            "mythic_pity,4,1",
+            "flux_by_day,1,4",
+            "flux_by_day,2,4.5",
+        ] {
+            assert!(rows.contains(&row), "missing {}", row);
+        }
+        // Empty histogram buckets are left out.
+        assert!(!rows.contains(&"mythic_pity,3,0"));
+    }
+
+    #[test]
+    fn json_nests_pulls_and_income() {
+        let json = serde_json::to_value(report()).unwrap();
+        assert_eq!(json["seed"], 7);
+        assert_eq!(json["pulls"]["pulls_to_mythic"]["median"], 2);
+        assert_eq!(json["pulls"]["mythic_pity"][4], 1);
+        assert_eq!(json["income"]["flux_by_day"][1], 4.5);
+    }
+
+    #[test]
+    fn every_simulated_pull_lands_on_a_rarity() {
+        let opts = Options {
+            pulls: 500,
+            runs: 1,
+            days: 1,
+            seed: 7,
+            banner: banner::by_id(banner::STANDARD).unwrap(),
+            format: Format::Json,
+        };
+        let mut rng = ChaCha20Rng::seed_from_u64(opts.seed);
+        let stats = simulate_pulls(&opts, &mut rng).unwrap();
+        assert_eq!(stats.mythic + stats.s + stats.a + stats.b, 500);
+        assert_eq!(stats.pulls_to_mythic.samples, stats.mythic);
+        assert_eq!(stats.pulls_to_s.samples, stats.s);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+
+use crate::bundle::Period;
+use crate::config::{self, Stock as StockRule};
+use crate::error::ApiError;
+use crate::events::Event;
+use crate::{AppState, Daily, PersistenceError, UserId, Voucher};
+
+fn caps(rule: &StockRule) -> [(Period, Option<u32>); 4] {
+    [
+        (Period::Day, rule.per_day),
+        (Period::Week, rule.per_week),
+        (Period::Month, rule.per_month),
+        (Period::Ever, rule.lifetime),
+    ]
+}
+
+fn rule(id: u64) -> Option<&'static StockRule> {
+    config::economy().stock.iter().find(|s| s.id == id)
+}
+
+// Vouchers of a template bought since `since`, or ever. Counted from the
+// logged purchases, bundle contents included.
+fn sold(
+    conn: &Connection,
+    user: &UserId,
+    id: u64,
+    since: Option<DateTime<Utc>>,
+) -> Result<u32, PersistenceError> {
+    Ok(conn.query_row(
+        "SELECT COALESCE(SUM(amount), 0) FROM purchases
+        WHERE user_id = ?1 AND template_id = ?2 AND bought_at >= ?3",
+        params![user.0, id as i64, since.map_or(i64::MIN, |s| s.timestamp())],
+        |row| row.get(0),
+    )?)
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub(crate) struct Stock {
+    // None when the template is unlimited.
+    remaining: Option<u32>,
+    // When some of what was bought comes back. None when nothing will, either
+    // because nothing was bought or the lifetime cap is used up.
+    restocks: Option<DateTime<Utc>>,
+}
+
+pub(crate) fn stock(
+    conn: &Connection,
+    user: &UserId,
+    id: u64,
+    now: DateTime<Utc>,
+) -> Result<Stock, PersistenceError> {
+    match rule(id) {
+        Some(rule) => left(conn, user, rule, now),
+        None => Ok(Stock::default()),
+    }
+}
+
+fn left(
+    conn: &Connection,
+    user: &UserId,
+    rule: &StockRule,
+    now: DateTime<Utc>,
+) -> Result<Stock, PersistenceError> {
+    let id = rule.id;
+    let mut stock = Stock::default();
+    let mut sold_out_for_good = false;
+    for (period, cap) in caps(rule) {
+        let Some(cap) = cap else {
+            continue;
+        };
+        let sold = sold(conn, user, id, period.start(now))?;
+        let left = cap.saturating_sub(sold);
+        stock.remaining = Some(stock.remaining.map_or(left, |r| r.min(left)));
+        match period.next(now) {
+            Some(next) if sold > 0 => {
+                stock.restocks = Some(stock.restocks.map_or(next, |r| r.min(next)));
+            }
+            Some(_) => (),
+            None => sold_out_for_good = left == 0,
+        }
+    }
+    if sold_out_for_good {
+        stock.restocks = None;
+    }
+    Ok(stock)
+}
+
+// Refuses orders the template's stock can't cover.
+pub(crate) fn check(
+    conn: &Connection,
+    user: &UserId,
+    voucher: &Voucher,
+    amount: u32,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let stock = stock(conn, user, voucher.id, now)?;
+    let Some(left) = stock.remaining else {
+        return Ok(());
+    };
+    if amount <= left {
+        return Ok(());
+    }
+    Err(ApiError::Forbidden(match (left, stock.restocks) {
+        (0, Some(at)) => format!("{} is out of stock until {}", voucher.name, at),
+        (0, None) => format!("{} is out of stock", voucher.name),
+        (left, _) => format!("only {} of {} left", left, voucher.name),
+    }))
+}
+
+// Lets every account that bought a capped template in a period ending at `at`
+// know it's back in stock.
+fn restock(state: &AppState, at: DateTime<Utc>) -> Result<(), PersistenceError> {
+    let ended = at - Duration::seconds(1);
+    let mut restocked: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    {
+        let conn = state.repo.db.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT DISTINCT user_id FROM purchases
+            WHERE template_id = ?1 AND bought_at >= ?2 AND bought_at < ?3",
+        )?;
+        for rule in config::economy().stock.iter() {
+            for (period, cap) in caps(rule) {
+                if cap.is_none() || period.next(ended) != Some(at) {
+                    continue;
+                }
+                let Some(start) = period.start(ended) else {
+                    continue;
+                };
+                let users = stmt
+                    .query_map(
+                        params![rule.id as i64, start.timestamp(), at.timestamp()],
+                        |row| row.get::<_, String>(0),
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                for user in users {
+                    let ids = restocked.entry(user).or_default();
+                    if !ids.contains(&rule.id) {
+                        ids.push(rule.id);
+                    }
+                }
+            }
+        }
+    }
+    for (user, templates) in restocked {
+        state
+            .events
+            .publish(&UserId(user), Event::Restocked { templates });
+    }
+    Ok(())
+}
+
+// Wakes at every daily reset, which is when weeks and months roll over too.
+pub(crate) fn spawn_restocker(state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            let now = Utc::now();
+            let next = Daily::cycle_at(now, 0) + Duration::days(1);
+            tokio::time::sleep((next - now).to_std().unwrap_or_default()).await;
+            if let Err(e) = restock(&state, next) {
+                println!("Restock failed: {}", e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn caps_count_what_was_bought_in_their_period() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id TEXT PRIMARY KEY); INSERT INTO users VALUES ('tester');",
+        )
+        .unwrap();
+        conn.execute_batch(crate::pricing::SCHEMA).unwrap();
+        let user = UserId("tester".into());
+        let rule = StockRule {
+            id: 4,
+            per_day: Some(2),
+            per_week: None,
+            per_month: None,
+            lifetime: Some(3),
+        };
+        let buy = |amount: u32, at: DateTime<Utc>| {
+            conn.execute(
+                "INSERT INTO purchases (user_id, template_id, amount, bought_at)
+                VALUES ('tester', 4, ?1, ?2)",
+                params![amount, at.timestamp()],
+            )
+            .unwrap();
+        };
+        // A Wednesday, after the 04:00 reset.
+        let now = Utc.with_ymd_and_hms(2026, 10, 14, 12, 0, 0).unwrap();
+        let tomorrow = Utc.with_ymd_and_hms(2026, 10, 15, 4, 0, 0).unwrap();
+
+        let stock = left(&conn, &user, &rule, now).unwrap();
+        assert_eq!((stock.remaining, stock.restocks), (Some(2), None));
+
+        buy(1, now - Duration::days(3));
+        let stock = left(&conn, &user, &rule, now).unwrap();
+        assert_eq!((stock.remaining, stock.restocks), (Some(2), None));
+
+        buy(1, now - Duration::hours(1));
+        let stock = left(&conn, &user, &rule, now).unwrap();
+        assert_eq!((stock.remaining, stock.restocks), (Some(1), Some(tomorrow)));
+
+        // The lifetime cap is used up, nothing comes back tomorrow.
+        buy(1, now);
+        let stock = left(&conn, &user, &rule, now).unwrap();
+        assert_eq!((stock.remaining, stock.restocks), (Some(0), None));
+    }
+}
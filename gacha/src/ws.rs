@@ -0,0 +1,380 @@
+use axum::Json;
+use axum::body::to_bytes;
+use std::time::Duration;
+
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade, close_code};
+use axum::extract::{Query, State};
+use axum::response::{IntoResponse, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::auth::{AuthUser, Token};
+use crate::error::ApiError;
+use crate::events::{Event, Published, Received, Subscription};
+use crate::{AppState, UserId, bars, consume, dailies, handle_pull, handle_pull_multi};
+
+// Replies are read back from the handler's response, this only guards
+// against a runaway body.
+const REPLY_LIMIT: usize = 1024 * 1024;
+// How often a quiet socket checks that its token is still good. Commands
+// check it every time.
+const TOKEN_RECHECK: Duration = Duration::from_secs(30);
+
+// Client to server: `{"id": 7, "cmd": "bars", "args": {"id": 2, "info": false}}`.
+// `id` is echoed back on the reply and can be anything JSON.
+#[derive(Deserialize)]
+struct Command {
+    id: Value,
+    cmd: String,
+    #[serde(default)]
+    args: Value,
+}
+
+// Server to client. `hello` comes first on every connection; `resumed: false`
+// means events were missed and the client should refetch what it shows.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Outgoing<'a> {
+    Hello {
+        resume_token: String,
+        resumed: bool,
+    },
+    Event {
+        resume_token: String,
+        event: &'a Event,
+    },
+    Reply {
+        id: Value,
+        status: u16,
+        body: Value,
+    },
+}
+
+#[derive(Deserialize)]
+pub(crate) struct WsParams {
+    resume: Option<String>,
+}
+
+// The token can also go in `access_token`, browsers can't set headers on a
+// WebSocket either. The socket is closed once the token is revoked or
+// expires.
+pub(crate) async fn ws(
+    State(state): State<AppState>,
+    AuthUser(id): AuthUser,
+    token: Token,
+    Query(params): Query<WsParams>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    upgrade.on_upgrade(move |socket| serve(socket, state, id, token, params.resume))
+}
+
+async fn send(socket: &mut WebSocket, message: &Outgoing<'_>) -> bool {
+    let Ok(text) = serde_json::to_string(message) else {
+        return true;
+    };
+    socket.send(Message::Text(text.into())).await.is_ok()
+}
+
+async fn signed_out(mut socket: WebSocket) {
+    let frame = CloseFrame {
+        code: close_code::POLICY,
+        reason: "token revoked or expired".into(),
+    };
+    let _ = socket.send(Message::Close(Some(frame))).await;
+}
+
+async fn serve(
+    mut socket: WebSocket,
+    state: AppState,
+    id: UserId,
+    token: Token,
+    resume: Option<String>,
+) {
+    let mut sub = state.events.subscribe(&id, resume.as_deref());
+
+    let hello = Outgoing::Hello {
+        resume_token: sub.token(sub.last),
+        resumed: sub.resumed,
+    };
+    if !send(&mut socket, &hello).await {
+        return;
+    }
+
+    let mut recheck = tokio::time::interval(TOKEN_RECHECK);
+    loop {
+        tokio::select! {
+            _ = recheck.tick() => {
+                if !token.valid(&state) {
+                    return signed_out(socket).await;
+                }
+            }
+            msg = socket.recv() => {
+                let text = match msg {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                if !token.valid(&state) {
+                    return signed_out(socket).await;
+                }
+                let reply = match serde_json::from_str::<Command>(&text) {
+                    Ok(command) => {
+                        let resp = dispatch(&state, &id, &command.cmd, command.args).await;
+                        reply(command.id, resp).await
+                    }
+                    Err(e) => {
+                        let resp = ApiError::Invalid(e.to_string()).into_response();
+                        reply(Value::Null, resp).await
+                    }
+                };
+                if !send(&mut socket, &reply).await {
+                    break;
+                }
+            }
+            received = sub.recv() => match received {
+                Received::Event(published) => {
+                    if !push(&mut socket, &sub, &published).await {
+                        break;
+                    }
+                }
+                Received::Lost(_) => {
+                    let hello = Outgoing::Hello {
+                        resume_token: sub.token(sub.last),
+                        resumed: false,
+                    };
+                    if !send(&mut socket, &hello).await {
+                        break;
+                    }
+                }
+                Received::Closed => break,
+            },
+        }
+    }
+}
+
+async fn push(socket: &mut WebSocket, sub: &Subscription, published: &Published) -> bool {
+    let message = Outgoing::Event {
+        resume_token: sub.token(published.seq),
+        event: &published.event,
+    };
+    send(socket, &message).await
+}
+
+fn args<T: DeserializeOwned>(args: Value) -> Result<Json<T>, ApiError> {
+    serde_json::from_value(args)
+        .map(Json)
+        .map_err(|e| ApiError::Invalid(e.to_string()))
+}
+
+// Commands run through the same handlers as the REST routes, the reply is
+// whatever the route would have answered.
+async fn dispatch(state: &AppState, id: &UserId, cmd: &str, body: Value) -> Response {
+    call(State(state.clone()), AuthUser(id.clone()), cmd, body)
+        .await
+        .unwrap_or_else(IntoResponse::into_response)
+}
+async fn call(
+    state: State<AppState>,
+    user: AuthUser,
+    cmd: &str,
+    body: Value,
+) -> Result<Response, ApiError> {
+    Ok(match cmd {
+        "pull" => {
+            let req = match body {
+                Value::Null => None,
+                body => Some(args(body)?),
+            };
+            handle_pull(state, user, req).await.into_response()
+        }
+        "pull_multi" => handle_pull_multi(state, user, args(body)?)
+            .await
+            .into_response(),
+        "bars" => bars(state, user, args(body)?).await.into_response(),
+        "consume" => consume(state, user, args(body)?).await.into_response(),
+        "dailies" => dailies(state, user, args(body)?).await.into_response(),
+        _ => return Err(ApiError::NotFound("command")),
+    })
+}
+
+async fn reply(id: Value, resp: Response) -> Outgoing<'static> {
+    let status = resp.status().as_u16();
+    let body = match to_bytes(resp.into_body(), REPLY_LIMIT).await {
+        Ok(bytes) if bytes.is_empty() => Value::Null,
+        Ok(bytes) => serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into())),
+        Err(_) => Value::Null,
+    };
+    Outgoing::Reply { id, status, body }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{User, auth, repo};
+    use axum::Router;
+    use axum::http::StatusCode;
+    use axum::routing::get;
+    use futures_util::{SinkExt, StreamExt};
+    use rusqlite::params;
+    use serde_json::json;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    fn tester() -> UserId {
+        UserId("tester".into())
+    }
+    fn registered() -> AppState {
+        let state = AppState::in_memory();
+        let user = User::new(tester(), "tester".into(), None);
+        repo::write(&state.repo.db.lock().unwrap(), &user).unwrap();
+        state
+    }
+    fn issue_token(state: &AppState) -> String {
+        let token = auth::issue_token(&state.repo.db.lock().unwrap(), &tester()).unwrap();
+        serde_json::to_value(token).unwrap()["token"]
+            .as_str()
+            .unwrap()
+            .into()
+    }
+    fn revoke_all(state: &AppState) {
+        state
+            .repo
+            .db
+            .lock()
+            .unwrap()
+            .execute("UPDATE tokens SET revoked = 1", params![])
+            .unwrap();
+    }
+    async fn connect(state: &AppState, token: &str) -> Client {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/ws", get(ws))
+            .with_state(state.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let url = format!("ws://{}/ws?access_token={}", addr, token);
+        connect_async(url).await.unwrap().0
+    }
+    async fn receive(client: &mut Client) -> Value {
+        match client.next().await.unwrap().unwrap() {
+            ClientMessage::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+    async fn command(client: &mut Client, command: Value) {
+        let text = command.to_string();
+        client.send(ClientMessage::Text(text.into())).await.unwrap();
+    }
+    async fn closed_by_server(client: &mut Client) {
+        match client.next().await.unwrap().unwrap() {
+            ClientMessage::Close(Some(frame)) => {
+                assert_eq!(u16::from(frame.code), close_code::POLICY)
+            }
+            other => panic!("expected a close, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_run_the_rest_handlers() {
+        let state = registered();
+        let bars = dispatch(&state, &tester(), "bars", json!({"id": 0, "info": true})).await;
+        let Outgoing::Reply { status, body, .. } = reply(json!(1), bars).await else {
+            panic!("expected a reply");
+        };
+        assert_eq!(status, 200);
+        let user = User::new(tester(), "tester".into(), None);
+        assert_eq!(body.as_array().unwrap().len(), user.bars.len());
+
+        let unknown = dispatch(&state, &tester(), "drop_tables", Value::Null).await;
+        assert_eq!(unknown.status(), StatusCode::NOT_FOUND);
+        let bad_args = dispatch(&state, &tester(), "bars", json!({"id": "two"})).await;
+        assert_eq!(bad_args.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn replies_carry_the_id_of_their_command() {
+        let state = registered();
+        let token = issue_token(&state);
+        let mut client = connect(&state, &token).await;
+        let hello = receive(&mut client).await;
+        assert_eq!(hello["type"], "hello");
+        assert_eq!(hello["resumed"], false);
+
+        command(
+            &mut client,
+            json!({"id": "first", "cmd": "bars", "args": {"id": 0, "info": true}}),
+        )
+        .await;
+        command(&mut client, json!({"id": {"n": 2}, "cmd": "nope"})).await;
+        client
+            .send(ClientMessage::Text("not json".into()))
+            .await
+            .unwrap();
+
+        let first = receive(&mut client).await;
+        assert_eq!(
+            (&first["type"], &first["id"], &first["status"]),
+            (&json!("reply"), &json!("first"), &json!(200))
+        );
+        let second = receive(&mut client).await;
+        assert_eq!(
+            (&second["id"], &second["status"]),
+            (&json!({"n": 2}), &json!(404))
+        );
+        assert_eq!(second["body"]["code"], "not_found");
+        let garbled = receive(&mut client).await;
+        assert_eq!(
+            (&garbled["id"], &garbled["status"]),
+            (&Value::Null, &json!(400))
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_pushed_with_their_resume_token() {
+        let state = registered();
+        let token = issue_token(&state);
+        let mut client = connect(&state, &token).await;
+        receive(&mut client).await;
+
+        state.events.publish(&tester(), Event::Overdrive { bar: 3 });
+        let pushed = receive(&mut client).await;
+        assert_eq!(pushed["type"], "event");
+        assert_eq!(pushed["event"], json!({"type": "overdrive", "bar": 3}));
+        let resume = state.events.subscribe(&tester(), None).token(1);
+        assert_eq!(pushed["resume_token"], resume);
+    }
+
+    #[tokio::test]
+    async fn a_revoked_token_closes_the_socket_on_the_next_command() {
+        let state = registered();
+        let token = issue_token(&state);
+        let mut client = connect(&state, &token).await;
+        receive(&mut client).await;
+
+        revoke_all(&state);
+        command(
+            &mut client,
+            json!({"id": 1, "cmd": "bars", "args": {"id": 0, "info": true}}),
+        )
+        .await;
+        closed_by_server(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn a_quiet_socket_notices_the_revocation() {
+        let state = registered();
+        let token = issue_token(&state);
+        let mut client = connect(&state, &token).await;
+        receive(&mut client).await;
+
+        revoke_all(&state);
+        let waited = tokio::time::timeout(TOKEN_RECHECK * 2, closed_by_server(&mut client));
+        tokio::time::pause();
+        waited.await.unwrap();
+    }
+}
@@ -6,7 +6,7 @@ const sleep = (ms: number) => new Promise((r) => setTimeout(r, ms));
 
 async function det_id() {
   let device_id = localStorage.getItem("device_id");
-  if (device_id) {
+  if (device_id && localStorage.getItem("token")) {
     window.addEventListener("DOMContentLoaded", async () => {
       const barpage = new BarPage();
       const update_func = async (barpage: BarPage) => {
@@ -74,10 +74,33 @@ async function det_id() {
         document.querySelector("#userid-form") as HTMLFormElement,
       );
 
+      const resp = await fetch(`${API_BASE}/login`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+          userid: form.get("userid"),
+          password: form.get("password"),
+        }),
+      });
+      const btn = document.querySelector("#useridForm-btn") as HTMLElement;
+      if (!resp.ok) {
+        btn.innerText = "Try Again";
+        return;
+      }
+
+      const login: { token: string } = await resp.json();
       localStorage.setItem("device_id", form.get("userid") as string);
+      localStorage.setItem("token", login.token);
       document.querySelector("#user-modal")?.remove();
+      location.reload();
     });
 }
+function authHeaders() {
+  return {
+    "Content-Type": "application/json",
+    Authorization: `Bearer ${localStorage.getItem("token") ?? ""}`,
+  };
+}
 function get_userid() {
   // Check if we already have an ID
   let deviceId = localStorage.getItem("device_id");
@@ -113,7 +136,7 @@ async function updateBars(barpage: BarPage, id: number) {
   try {
     let resp = await fetch(`${API_BASE}/bars`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -597,7 +620,7 @@ async function isrdo(desc: string, coeff: number) {
   try {
     let response = await fetch(`${API_BASE}/isrdo`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -713,7 +736,7 @@ async function construct_isrdo_node(
   try {
     const response = await fetch(`${API_BASE}/isrdo_complete`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -737,7 +760,7 @@ async function get_isrdos() {
   try {
     const response = await fetch(`${API_BASE}/get_isrdos`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(get_userid()),
     });
 
@@ -794,7 +817,7 @@ interface ConsumeRequest {
   try {
     let resp = await fetch(`${API_BASE}${path}`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -863,7 +886,7 @@ async function queryUserFunds(): Promise<boolean> {
   try {
     let response = await fetch(`${API_BASE}${path}`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -946,7 +969,7 @@ async function apiActionCreateVoucher(path: string) {
         };
         const response = await fetch(`${API_BASE}${path}`, {
           method: "POST",
-          headers: { "Content-Type": "application/json" },
+          headers: authHeaders(),
           body: JSON.stringify(payload),
         });
 
@@ -1051,7 +1074,7 @@ async function apiActionGetVouchers(
   try {
     let response = await fetch(`${API_BASE}${path}`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
     let data_v: Voucher[] = await response.json();
@@ -1183,11 +1206,11 @@ async function apiActionGetVouchers(
 }
 
 async function flip_new(uuid: string) {
-  let payload = uuid;
+  let payload = { uuid };
   try {
     await fetch(`${API_BASE}/remove_new_logo`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
   } catch (err) {
@@ -1219,7 +1242,7 @@ async function flip_new(uuid: string) {
     try {
       let resp = await fetch(`${API_BASE}/purchase`, {
         method: "POST",
-        headers: { "Content-Type": "application/json" },
+        headers: authHeaders(),
         body: JSON.stringify(payload),
       });
       if (resp.ok) {
@@ -1264,7 +1287,7 @@ async function flip_new(uuid: string) {
         console.log(payload.dur);
         let resp = await fetch(`${API_BASE}/purchase`, {
           method: "POST",
-          headers: { "Content-Type": "application/json" },
+          headers: authHeaders(),
           body: JSON.stringify(payload),
         });
         if (resp.ok) {
@@ -1535,7 +1558,7 @@ async function delete_voucher(uuid: string, store: boolean): Promise<boolean> {
   try {
     let response = await fetch(`${API_BASE}/delete_item`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -1555,7 +1578,7 @@ async function custom_voucher(id: number, amount: number) {
   try {
     let response = await fetch(`${API_BASE}/create_advanced`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
 
@@ -1709,7 +1732,7 @@ async function apiActionPull(
   try {
     const response = await fetch(`${API_BASE}${path}`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
     const data: RewardTup = await response.json();
@@ -1794,7 +1817,7 @@ async function apiActionTimer(path: string, payload: TimerRequest) {
       if (path === "/stop_timer") {
         const response = await fetch(`${API_BASE}${path}`, {
           method: "POST",
-          headers: { "Content-Type": "application/json" },
+          headers: authHeaders(),
           body: JSON.stringify(payload),
         });
         const data = await response.json();
@@ -1851,7 +1874,7 @@ async function apiActionTimer(path: string, payload: TimerRequest) {
         try {
           resp = await fetch(`${API_BASE}${path}`, {
             method: "POST",
-            headers: { "Content-Type": "application/json" },
+            headers: authHeaders(),
             body: JSON.stringify(payload),
           });
           const data = await resp.json();
@@ -1900,6 +1923,11 @@ const usermodal = `
                         <input type="text" name="userid" placeholder="person"
                             class="w-full bg-slate-800 border border-slate-600 rounded-xl px-4 py-3 text-emerald-400 font-mono focus:outline-none focus:border-emerald-500 transition-colors">
                 </div>
+                <div>
+                        <label class="block text-[10px] font-bold text-emerald-500 uppercase tracking-[0.2em] mb-1.5">Password</label>
+                        <input type="password" name="password"
+                            class="w-full bg-slate-800 border border-slate-600 rounded-xl px-4 py-3 text-emerald-400 font-mono focus:outline-none focus:border-emerald-500 transition-colors">
+                </div>
 
                 <button id="userid-btn" type="submit" class="text-sm mt-4 w-full bg-emerald-600 text-slate-900 font-black py-4 rounded-xl hover:bg-emerald-400 active:scale-[0.98] transition-all duration-200 flex justify-center items-center shadow-lg shadow-emerald-900/40 uppercase tracking-wider">
                     <div class="flex justify-center mx-2">
@@ -2437,7 +2465,9 @@ document.querySelector("#flow")?.addEventListener("click", () => {
 });
 
 async function toggleFlow() {
-  let resp = await fetch(`${API_BASE}/pause_dripper?userid=${get_userid()}`);
+  let resp = await fetch(`${API_BASE}/pause_dripper`, {
+    headers: authHeaders(),
+  });
   const button = document.querySelector("#flow") as HTMLButtonElement;
 
   if (button && resp.status === 200) {
@@ -2471,7 +2501,7 @@ document
           let payload = { userid: get_userid(), id: idx, info: false };
           let response = await fetch(`${API_BASE}/dailies`, {
             method: "POST",
-            headers: { "Content-Type": "application/json" },
+            headers: authHeaders(),
             body: JSON.stringify(payload),
           });
 
@@ -2624,7 +2654,7 @@ async function prepareDailies() {
 
         let response = await fetch(`${API_BASE}/dailies`, {
           method: "POST",
-          headers: { "Content-Type": "application/json" },
+          headers: authHeaders(),
           body: JSON.stringify(payload),
         });
 
@@ -2667,7 +2697,7 @@ async function updateTimerBtn() {
   let path = "/start_timer";
   const response = await fetch(`${API_BASE}${path}`, {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: authHeaders(),
     body: JSON.stringify(payload),
   });
   const data = await response.json();
@@ -2742,7 +2772,7 @@ refreshBtn.addEventListener("click", async () => {
     let payload = { id: 255, info: true, userid: get_userid() };
     let response = await fetch(`${API_BASE}/dailies`, {
       method: "POST",
-      headers: { "Content-Type": "application/json" },
+      headers: authHeaders(),
       body: JSON.stringify(payload),
     });
     const response_j = await response.json();
@@ -2800,7 +2830,7 @@ async function updateHUD() {
   };
   const dailies_f = await fetch(`${API_BASE}/dailies`, {
     method: "POST",
-    headers: { "Content-Type": "application/json" },
+    headers: authHeaders(),
     body: JSON.stringify(payload),
   });
 